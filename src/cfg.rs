@@ -0,0 +1,210 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::chip8::Variant;
+use crate::disassembler::{decode, Instruction, Operand};
+
+/// Where ROMs are loaded and start running
+const ROM_START: u16 = 0x200;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum EdgeKind {
+  /// Execution runs on into the next block
+  Fallthrough,
+  Jump,
+  /// A subroutine call, which also falls through to the instruction after it once it returns
+  Call,
+  /// A skip instruction whose condition held
+  Skip,
+}
+
+#[derive(Clone, Debug)]
+pub struct BasicBlock {
+  pub start: u16,
+  pub instructions: Vec<Instruction>,
+  /// Whether the block ends in a jump whose target depends on a register, e.g. BNNN
+  pub indirect: bool,
+}
+
+#[derive(Clone, Debug, Default)]
+/// The basic blocks reachable from the start of a ROM and the edges between them
+pub struct ControlFlowGraph {
+  pub blocks: BTreeMap<u16, BasicBlock>,
+  pub edges: Vec<(u16, u16, EdgeKind)>,
+}
+
+/// Where control can go after an instruction, and whether it can't be followed statically
+fn successors(memory: &[u8], instruction: &Instruction, variant: Variant) -> (Vec<(u16, EdgeKind)>, bool) {
+  let next = instruction.addr.wrapping_add(instruction.len);
+  let target = instruction.operands.iter().find_map(|operand| match operand {
+    Operand::Address(addr) => Some(*addr),
+    _ => None,
+  });
+
+  match (instruction.mnemonic, instruction.operands.first()) {
+    ("JP", Some(Operand::Register(_))) => (vec![], true),
+    ("JP", _) => (vec![(target.unwrap(), EdgeKind::Jump)], false),
+    ("CALL", _) => (vec![(target.unwrap(), EdgeKind::Call), (next, EdgeKind::Fallthrough)], false),
+    ("RET", _) | ("EXIT", _) | ("DW", _) => (vec![], false),
+    ("SE", _) | ("SNE", _) | ("SKP", _) | ("SKNP", _) => {
+      // Skips hop over the whole of a following long load, as `Chip8::skip` does
+      let skipped = decode(memory, next, variant);
+      (vec![(next, EdgeKind::Fallthrough), (next.wrapping_add(skipped.len), EdgeKind::Skip)], false)
+    },
+    _ => (vec![(next, EdgeKind::Fallthrough)], false),
+  }
+}
+
+/// Statically walk a ROM from 0x200, following jumps, calls and both outcomes of skips, and split
+/// the instructions found into basic blocks. Jumps through a register can't be followed, so the
+/// blocks ending in them are marked as indirect instead.
+pub fn control_flow_graph(rom: &[u8], variant: Variant) -> ControlFlowGraph {
+  let mut memory = vec![0; 65536];
+  let rom_end = (ROM_START as usize + rom.len()).min(memory.len());
+  memory[ROM_START as usize..rom_end].copy_from_slice(&rom[..rom_end - ROM_START as usize]);
+
+  // Find every reachable instruction, and where blocks have to start
+  let mut instructions = BTreeMap::new();
+  let mut exits = BTreeMap::new();
+  let mut leaders = BTreeSet::from([ROM_START]);
+  let mut pending = vec![ROM_START];
+  while let Some(addr) = pending.pop() {
+    if instructions.contains_key(&addr) {
+      continue;
+    }
+    let instruction = decode(&memory, addr, variant);
+    let (next, indirect) = successors(&memory, &instruction, variant);
+    let ends_block = indirect || next.len() != 1 || next[0].1 != EdgeKind::Fallthrough;
+    for (target, _) in &next {
+      if ends_block {
+        leaders.insert(*target);
+      }
+      pending.push(*target);
+    }
+    instructions.insert(addr, instruction);
+    exits.insert(addr, (next, indirect, ends_block));
+  }
+
+  // A fallthrough into an instruction that isn't the next one in address order, which happens
+  // when code overlaps, has to start a block too
+  let addrs = instructions.keys().copied().collect::<Vec<_>>();
+  for (index, addr) in addrs.iter().enumerate() {
+    let (next, _, ends_block) = &exits[addr];
+    if !ends_block && addrs.get(index + 1) != Some(&next[0].0) {
+      leaders.insert(next[0].0);
+    }
+  }
+
+  // Group runs of instructions into blocks, ending them at control flow or the next leader
+  let mut graph = ControlFlowGraph::default();
+  let mut current: Option<(BasicBlock, u16)> = None;
+  for (addr, instruction) in instructions {
+    let (next, indirect, ends_block) = exits.remove(&addr).unwrap();
+    let mut block = match current.take() {
+      Some((block, _)) if !leaders.contains(&addr) => block,
+      previous => {
+        // A block cut short by a leader just runs on into it
+        if let Some((previous, fallthrough)) = previous {
+          graph.edges.push((previous.start, fallthrough, EdgeKind::Fallthrough));
+          graph.blocks.insert(previous.start, previous);
+        }
+        BasicBlock { start: addr, instructions: vec![], indirect: false }
+      }
+    };
+    block.instructions.push(instruction);
+
+    if ends_block {
+      block.indirect = indirect;
+      for (target, kind) in next {
+        graph.edges.push((block.start, target, kind));
+      }
+      graph.blocks.insert(block.start, block);
+    } else {
+      current = Some((block, next[0].0));
+    }
+  }
+  if let Some((block, _)) = current {
+    graph.blocks.insert(block.start, block);
+  }
+
+  graph
+}
+
+impl ControlFlowGraph {
+  /// Render the graph in Graphviz DOT, with each block listing its instructions
+  pub fn to_dot(&self) -> String {
+    let mut dot = "digraph cfg {\n  node [shape=box, fontname=\"monospace\"];\n".to_string();
+    for block in self.blocks.values() {
+      let listing = block.instructions
+        .iter()
+        .map(|instruction| format!("{:04X}  {}\\l", instruction.addr, instruction))
+        .collect::<String>();
+      let style = if block.indirect { ", style=filled, fillcolor=lightyellow" } else { "" };
+      dot += &format!("  b{:04X} [label=\"{}\"{}];\n", block.start, listing.replace('"', "\\\""), style);
+      if block.indirect {
+        dot += &format!("  b{:04X} -> indirect [style=dotted];\n", block.start);
+      }
+    }
+    if self.blocks.values().any(|block| block.indirect) {
+      dot += "  indirect [shape=ellipse, label=\"indirect jump\"];\n";
+    }
+
+    for (from, to, kind) in &self.edges {
+      let attributes = match kind {
+        EdgeKind::Fallthrough => "",
+        EdgeKind::Jump => " [label=\"jump\"]",
+        EdgeKind::Call => " [label=\"call\", style=dashed]",
+        EdgeKind::Skip => " [label=\"skip\", color=blue]",
+      };
+      dot += &format!("  b{:04X} -> b{:04X}{};\n", from, to, attributes);
+    }
+    dot += "}\n";
+    dot
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn instruction_addrs(block: &BasicBlock) -> Vec<u16> {
+    block.instructions.iter().map(|instruction| instruction.addr).collect()
+  }
+
+  #[test]
+  fn skips_hop_over_the_whole_of_a_long_load() {
+    let rom = [
+      0x30, 0x01, // 0x200: if v0 == 1 skip
+      0xF0, 0x00, 0x03, 0x00, // 0x202: i := long 0x300
+      0x12, 0x06, // 0x206: jump 0x206
+    ];
+    let graph = control_flow_graph(&rom, Variant::XOCHIP);
+
+    assert_eq!(graph.blocks.keys().copied().collect::<Vec<_>>(), [0x200, 0x202, 0x206]);
+    // The second half of the long load is never taken for an instruction of its own
+    assert_eq!(instruction_addrs(&graph.blocks[&0x202]), [0x202]);
+    assert_eq!(graph.edges, [
+      (0x200, 0x202, EdgeKind::Fallthrough),
+      (0x200, 0x206, EdgeKind::Skip),
+      (0x202, 0x206, EdgeKind::Fallthrough),
+      (0x206, 0x206, EdgeKind::Jump),
+    ]);
+  }
+
+  #[test]
+  fn jumps_through_a_register_end_the_walk() {
+    let rom = [
+      0x60, 0x02, // 0x200: v0 := 2
+      0xB3, 0x00, // 0x202: jump0 0x300
+      0x00, 0xE0, // 0x204: clear, never reached
+    ];
+    for variant in [Variant::CHIP8, Variant::SCHIP_MODERN] {
+      let graph = control_flow_graph(&rom, variant);
+      assert_eq!(graph.blocks.len(), 1);
+      let block = &graph.blocks[&0x200];
+      assert_eq!(instruction_addrs(block), [0x200, 0x202]);
+      assert!(block.indirect);
+      assert!(graph.edges.is_empty());
+      assert!(graph.to_dot().contains("b0200 -> indirect"));
+    }
+  }
+}
@@ -1,13 +1,24 @@
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::path::Path;
 
-const STANDARD_LAYOUT: [u8; 16] = [
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// The keypad as laid out on the COSMAC VIP, row by row. Keypad state is indexed in this order
+/// rather than by key value.
+pub const STANDARD_LAYOUT: [u8; 16] = [
   0x1, 0x2, 0x3, 0xC,
   0x4, 0x5, 0x6, 0xD,
   0x7, 0x8, 0x9, 0xE,
   0xA, 0x0, 0xB, 0xF
 ];
 
+/// How many frames back `polled_keys` looks, since a game may only read the keypad now and then
+pub const POLLED_KEY_FRAMES: usize = 60;
+
+/// The pitch an XO-CHIP program starts with, playing its pattern at 4000 bits per second
+pub const DEFAULT_PITCH: u8 = 64;
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 #[allow(non_camel_case_types)]
 /// Which particular CHIP-8 interpreter to emulate
@@ -18,6 +29,152 @@ pub enum Variant {
   XOCHIP
 }
 
+impl Variant {
+  /// Every variant, in a stable order that is safe to store by index
+  pub const ALL: [Variant; 4] = [Variant::CHIP8, Variant::SCHIP_LEGACY, Variant::SCHIP_MODERN, Variant::XOCHIP];
+
+  /// The variant's name as written on the command line
+  pub fn name(&self) -> &'static str {
+    match self {
+      Variant::CHIP8 => "chip8",
+      Variant::SCHIP_LEGACY => "schip-legacy",
+      Variant::SCHIP_MODERN => "schip-modern",
+      Variant::XOCHIP => "xochip",
+    }
+  }
+
+  pub fn from_name(name: &str) -> Option<Variant> {
+    Variant::ALL.into_iter().find(|variant| variant.name().eq_ignore_ascii_case(name))
+  }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+/// The behaviours that differ between interpreters for the same instructions. Each variant
+/// has its own, which can then be changed one at a time.
+pub struct Quirks {
+  /// 8XY1, 8XY2 and 8XY3 reset VF to 0
+  pub vf_reset: bool,
+  /// FX55 and FX65 leave I pointing past the last register they used
+  pub memory: bool,
+  /// 8XY6 and 8XYE shift VX in place instead of shifting VY into VX
+  pub shifting: bool,
+  /// BNNN jumps to XNN + VX instead of NNN + V0
+  pub jumping: bool,
+  /// Sprites are cut off at the edges of the display instead of wrapping around
+  pub clipping: bool,
+  /// Drawing waits for the next frame, so at most one sprite is drawn per frame
+  pub display_wait: bool,
+}
+
+impl Quirks {
+  pub fn for_variant(variant: Variant) -> Quirks {
+    let schip = matches!(variant, Variant::SCHIP_LEGACY | Variant::SCHIP_MODERN);
+    Quirks {
+      vf_reset: variant == Variant::CHIP8,
+      memory: !schip,
+      shifting: schip,
+      jumping: schip,
+      clipping: variant != Variant::XOCHIP,
+      display_wait: matches!(variant, Variant::CHIP8 | Variant::SCHIP_LEGACY),
+    }
+  }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+/// Why the interpreter stopped executing instructions
+pub enum HaltReason {
+  /// SCHIP: the program executed 00FD
+  Exit,
+  /// The program jumped to its own address and can never leave
+  SelfJump(u16),
+  /// An opcode the interpreter doesn't recognize, if halting on those is enabled
+  UnknownOpcode(u16),
+  /// A return with an empty stack, if halting on those is enabled
+  StackUnderflow,
+}
+
+/// The opcode with its operand nibbles zeroed, e.g. 0x8XY4 becomes 0x8004, which groups
+/// instructions by what they do
+pub fn opcode_class(op: u16) -> u16 {
+  match op & 0xF000 {
+    0x0000 if matches!(op & 0xFFF0, 0x00C0 | 0x00D0) => op & 0xFFF0,
+    0x0000 => op,
+    0x5000 | 0x8000 | 0x9000 => op & 0xF00F,
+    0xE000 | 0xF000 => op & 0xF0FF,
+    _ => op & 0xF000,
+  }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+/// An XO-CHIP audio pattern, 128 one-bit samples played in a loop while the sound timer runs
+pub struct AudioPattern {
+  pub bits: [u8; 16],
+  pub pitch: u8,
+}
+
+impl AudioPattern {
+  /// Bits played per second, doubling every 48 steps of pitch
+  pub fn rate(&self) -> f32 {
+    4000.0 * 2_f32.powf((self.pitch as f32 - DEFAULT_PITCH as f32) / 48.0)
+  }
+
+  /// Whether bit `index` of the loop is set, counting from the top bit of the first byte
+  pub fn bit(&self, index: usize) -> bool {
+    self.bits[(index / 8) % 16] & (0x80 >> (index % 8)) != 0
+  }
+}
+
+#[derive(Clone, Debug)]
+/// Execution counts gathered while profiling is enabled
+pub struct Profile {
+  /// Instructions fetched from each address
+  pub address_counts: Vec<u64>,
+  /// Instructions run of each kind, keyed by `opcode_class`
+  pub class_counts: HashMap<u16, u64>,
+  pub total: u64,
+}
+
+impl Profile {
+  fn new() -> Profile {
+    Self {
+      address_counts: vec![0; 65536],
+      class_counts: HashMap::new(),
+      total: 0,
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+/// Which bytes of memory the program has used, and how
+pub struct Coverage {
+  /// Bytes of instructions that have run, including the address after an F000 long load
+  pub executed: Vec<bool>,
+  /// Addresses read as data by DXYN, FX65, F002 and 5XY3
+  pub data_read: Vec<bool>,
+  /// For skip instructions, bit 0 is set once the skip hasn't been taken and bit 1 once it has
+  pub branches: Vec<u8>,
+}
+
+impl Coverage {
+  fn new() -> Coverage {
+    Self {
+      executed: vec![false; 65536],
+      data_read: vec![false; 65536],
+      branches: vec![0; 65536],
+    }
+  }
+}
+
+/// Whether an opcode conditionally skips the next instruction
+fn is_skip(op: u16) -> bool {
+  match op & 0xF000 {
+    0x3000 | 0x4000 => true,
+    0x5000 | 0x9000 => op & 0x000F == 0,
+    0xE000 => matches!(op & 0x00FF, 0x009E | 0x00A1),
+    _ => false,
+  }
+}
+
 pub struct Chip8 {
   memory: [u8; 65536],
   display: [u8; 128 * 64],
@@ -30,18 +187,38 @@ pub struct Chip8 {
   keypad: [bool; 16],
   keypad_prev: [bool; 16],
   last_pressed_key: Option<usize>,
+  /// The key values tested or waited for in each of the last few frames, as bitmasks with bit N
+  /// for key N, going round from `polled_frame`
+  polled_keys: [u16; POLLED_KEY_FRAMES],
+  polled_frame: usize,
   displayed: bool,
   variant: Variant,
+  quirks: Quirks,
+  halted: Option<HaltReason>,
+  halt_on_unknown_opcode: bool,
+  halt_on_stack_underflow: bool,
+  /// Addresses of unknown opcodes that have already been logged
+  reported_unknown_opcodes: HashSet<u16>,
+  seed: u64,
+  rng: StdRng,
+  memory_writes: Vec<u16>,
+  coverage: Coverage,
+  profiling: bool,
+  profile: Option<Profile>,
   // SCHIP
   hires_mode: bool,
   flags: [u8; 8],
   // XOCHIP
   audio_pattern_buffer: [u8; 16],
+  /// Whether F002 has loaded a pattern, which replaces the buzzer from then on
+  audio_pattern_loaded: bool,
+  pitch: u8,
 }
 
 impl Chip8 {
   /// Create a new Chip8 instance
   pub fn new(variant: Variant) -> Chip8 {
+    let seed = rand::random::<u64>();
     Self {
       memory: [0; 65536],
       display: [0; 128 * 64],
@@ -54,13 +231,28 @@ impl Chip8 {
       keypad: [false; 16],
       keypad_prev: [false; 16],
       last_pressed_key: None,
+      polled_keys: [0; POLLED_KEY_FRAMES],
+      polled_frame: 0,
       displayed: false,
       variant,
+      quirks: Quirks::for_variant(variant),
+      halted: None,
+      halt_on_unknown_opcode: false,
+      halt_on_stack_underflow: false,
+      reported_unknown_opcodes: HashSet::new(),
+      seed,
+      rng: StdRng::seed_from_u64(seed),
+      memory_writes: Vec::new(),
+      coverage: Coverage::new(),
+      profiling: false,
+      profile: None,
       // SCHIP
       hires_mode: false,
       flags: [0; 8],
       // XOCHIP
       audio_pattern_buffer: [0; 16],
+      audio_pattern_loaded: false,
+      pitch: DEFAULT_PITCH,
     }
   }
 
@@ -124,6 +316,7 @@ impl Chip8 {
     memory_slice.copy_from_slice(bytes.as_slice());
   }
 
+  /// Reset the interpreter state, leaving memory as it is
   pub fn reset(&mut self) {
     self.pc = 0x0200;
     self.i = 0;
@@ -134,13 +327,104 @@ impl Chip8 {
     self.delay_timer = 0;
     self.sound_timer = 0;
     self.keypad = [false; 16];
+    self.keypad_prev = [false; 16];
+    self.last_pressed_key = None;
+    self.polled_keys = [0; POLLED_KEY_FRAMES];
     self.registers = [0; 16];
+    self.audio_pattern_buffer = [0; 16];
+    self.audio_pattern_loaded = false;
+    self.pitch = DEFAULT_PITCH;
+    self.halted = None;
+    self.reported_unknown_opcodes.clear();
+    self.rng = StdRng::seed_from_u64(self.seed);
+    self.memory_writes.clear();
+  }
+
+  /// Zero all of memory, including the font, and forget which of it was run as code
+  pub fn clear_memory(&mut self) {
+    self.memory.fill(0);
+    self.coverage = Coverage::new();
+  }
+
+  /// Change which interpreter's behaviour to emulate from the next instruction onwards,
+  /// including its quirks
+  pub fn set_variant(&mut self, variant: Variant) {
+    self.variant = variant;
+    self.quirks = Quirks::for_variant(variant);
+  }
+
+  pub fn quirks(&self) -> Quirks {
+    self.quirks
+  }
+
+  /// Change individual quirks, which last until the variant is next set
+  pub fn set_quirks(&mut self, quirks: Quirks) {
+    self.quirks = quirks;
+  }
+
+  pub fn get_seed(&self) -> u64 {
+    self.seed
+  }
+
+  /// Seed the random number generator used by CXNN, so runs with the same input are repeatable
+  pub fn set_seed(&mut self, seed: u64) {
+    self.seed = seed;
+    self.rng = StdRng::seed_from_u64(seed);
   }
 
   pub fn get_memory(&self) -> Vec<u8> {
     Vec::from(&self.memory)
   }
 
+  /// Overwrite memory starting at `addr`, wrapping around at the end of memory
+  pub fn set_memory(&mut self, addr: u16, bytes: &[u8]) {
+    for (offset, byte) in bytes.iter().enumerate() {
+      self.memory[(addr as usize + offset) % self.memory.len()] = *byte;
+    }
+  }
+
+  /// Addresses written by instructions since the last call to `clear_memory_writes`
+  pub fn memory_writes(&self) -> &[u16] {
+    &self.memory_writes
+  }
+
+  pub fn clear_memory_writes(&mut self) {
+    self.memory_writes.clear();
+  }
+
+  /// How the program has used memory since it was last cleared, which also tells code apart
+  /// from data for the disassembler
+  pub fn coverage(&self) -> &Coverage {
+    &self.coverage
+  }
+
+  /// Forget how memory has been used so far, e.g. before running another test input
+  pub fn reset_coverage(&mut self) {
+    self.coverage = Coverage::new();
+  }
+
+  pub fn is_profiling(&self) -> bool {
+    self.profiling
+  }
+
+  /// Start or stop counting how often each address and kind of instruction runs,
+  /// keeping the counts so far when stopping
+  pub fn set_profiling(&mut self, enabled: bool) {
+    self.profiling = enabled;
+    if enabled && self.profile.is_none() {
+      self.profile = Some(Profile::new());
+    }
+  }
+
+  /// The counts gathered since profiling was first enabled or last reset
+  pub fn profile(&self) -> Option<&Profile> {
+    self.profile.as_ref()
+  }
+
+  pub fn reset_profile(&mut self) {
+    self.profile = if self.profiling { Some(Profile::new()) } else { None };
+  }
+
   /// Get screen pixel data as a sequence of Uint8s
   pub fn get_display(&self) -> Vec<u8> {
     Vec::from(&self.display)
@@ -158,6 +442,11 @@ impl Chip8 {
     self.sound_timer
   }
 
+  /// The pattern played in place of the buzzer, once the program has loaded one with F002
+  pub fn audio_pattern(&self) -> Option<AudioPattern> {
+    self.audio_pattern_loaded.then_some(AudioPattern { bits: self.audio_pattern_buffer, pitch: self.pitch })
+  }
+
   pub fn get_stack(&self) -> Vec<u16> {
     Vec::from(self.stack.clone())
   }
@@ -166,6 +455,18 @@ impl Chip8 {
     self.keypad.iter().map(|x| if *x { 1 } else { 0 }).collect()
   }
 
+  /// The key values the program has tested with EX9E/EXA1 or waited for with FX0A over the last
+  /// `POLLED_KEY_FRAMES` frames, as a bitmask with bit N for key N
+  pub fn polled_keys(&self) -> u16 {
+    self.polled_keys.iter().fold(0, |keys, frame| keys | frame)
+  }
+
+  /// Start recording polled keys for a new frame, forgetting the oldest one
+  pub fn start_polling_frame(&mut self) {
+    self.polled_frame = (self.polled_frame + 1) % POLLED_KEY_FRAMES;
+    self.polled_keys[self.polled_frame] = 0;
+  }
+
   pub fn get_index(&self) -> u16 {
     self.i
   }
@@ -174,6 +475,10 @@ impl Chip8 {
     Vec::from(&self.registers)
   }
 
+  pub fn set_register(&mut self, register: u8, value: u8) {
+    self.registers[register as usize & 0xF] = value;
+  }
+
   pub fn displayed_this_frame(&self) -> bool {
     self.displayed
   }
@@ -182,6 +487,44 @@ impl Chip8 {
     self.hires_mode
   }
 
+  /// Whether the program has stopped and `run` will no longer execute instructions
+  pub fn is_halted(&self) -> bool {
+    self.halted.is_some()
+  }
+
+  pub fn halt_reason(&self) -> Option<HaltReason> {
+    self.halted
+  }
+
+  /// Halt on opcodes the interpreter doesn't recognize instead of skipping over them
+  pub fn set_halt_on_unknown_opcode(&mut self, value: bool) {
+    self.halt_on_unknown_opcode = value;
+  }
+
+  /// Halt when returning with an empty stack instead of restarting the program
+  pub fn set_halt_on_stack_underflow(&mut self, value: bool) {
+    self.halt_on_stack_underflow = value;
+  }
+
+  /// A 64-bit FNV-1a hash of everything that affects how the program runs from here, used to
+  /// check that two interpreters fed the same input are still in step
+  pub fn state_hash(&self) -> u64 {
+    let registers = [
+      &self.pc.to_le_bytes()[..],
+      &self.i.to_le_bytes(),
+      &[self.delay_timer, self.sound_timer, self.hires_mode as u8, self.pitch],
+      &self.audio_pattern_buffer,
+      &self.registers,
+      &self.flags,
+    ];
+    let stack = self.stack.iter().flat_map(|addr| addr.to_le_bytes());
+    registers.into_iter().flatten().copied()
+      .chain(stack)
+      .chain(self.memory.iter().copied())
+      .chain(self.display.iter().copied())
+      .fold(0xcbf29ce484222325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+  }
+
   pub fn get_current_opcode(&self) -> u16 {
     ((self.memory[self.pc as usize] as u16) << 8) | (self.memory[(self.pc + 1) as usize] as u16)
   }
@@ -190,6 +533,10 @@ impl Chip8 {
   pub fn run(&mut self) {
     self.displayed = false;
 
+    if self.halted.is_some() {
+      return;
+    }
+
     // Fetch the next 16-bit instruction
     if self.pc as usize >= self.memory.len() {
       // Probably not accurate to real life but just set back to start of program
@@ -198,6 +545,14 @@ impl Chip8 {
     let op1 = self.memory[self.pc as usize];
     let op2 = self.memory[(self.pc + 1) as usize];
     let op = ((op1 as u16) << 8) | (op2 as u16);
+    let addr = self.pc;
+    self.coverage.executed[addr as usize] = true;
+    self.coverage.executed[addr.wrapping_add(1) as usize] = true;
+    if let (true, Some(profile)) = (self.profiling, &mut self.profile) {
+      profile.address_counts[addr as usize] += 1;
+      *profile.class_counts.entry(opcode_class(op)).or_insert(0) += 1;
+      profile.total += 1;
+    }
     self.pc = self.pc.wrapping_add(2);
 
     // Decode and execute the instruction
@@ -237,6 +592,11 @@ impl Chip8 {
         // Return from subroutine
         self.pc = match self.stack.pop() {
           Some(addr) => addr,
+          None if self.halt_on_stack_underflow => {
+            self.pc = self.pc.wrapping_sub(2);
+            self.halted = Some(HaltReason::StackUnderflow);
+            return;
+          },
           None => {
             // Probably not accurate to real life but just set back to start of program
             0x0200
@@ -278,6 +638,11 @@ impl Chip8 {
           }
         }
       },
+      (0x0000, 0x0000, 0x00F0, 0x000D) => {
+        // SCHIP: Exit the interpreter
+        self.pc = self.pc.wrapping_sub(2);
+        self.halted = Some(HaltReason::Exit);
+      },
       (0x0000, 0x0000, 0x00F0, 0x000E) => {
         // SCHIP: Use lores mode
         self.hires_mode = false;
@@ -288,6 +653,10 @@ impl Chip8 {
       }
       (0x1000, _, _, _) => {
         // Jump to address NNN
+        // A jump to itself can never be left, so treat it as the end of the program
+        if nnn == self.pc.wrapping_sub(2) {
+          self.halted = Some(HaltReason::SelfJump(nnn));
+        }
         self.pc = nnn;
       },
       (0x2000, _, _, _) => {
@@ -316,13 +685,13 @@ impl Chip8 {
       (0x5000, _, _, 0x0002) => {
         // XOCHIP: save an inclusive range of registers to memory starting at i
         for register in x..y + 1 {
-          self.memory[self.i as usize + register] = self.registers[register];
+          self.write_memory(self.i as usize + register, self.registers[register]);
         }
       },
       (0x5000, _, _, 0x0003) => {
         // XOCHIP: load an inclusive range of registers from memory starting at i
         for register in x..y + 1 {
-          self.registers[register] = self.memory[self.i as usize + register];
+          self.registers[register] = self.read_data(self.i as usize + register);
         }
       },
       (0x6000, _, _, _) => {
@@ -340,21 +709,21 @@ impl Chip8 {
       (0x8000, _, _, 0x0001) => {
         // Set register VX to VX | VY
         self.registers[x] = self.registers[x] | self.registers[y];
-        if self.variant == Variant::CHIP8 {
+        if self.quirks.vf_reset {
           self.registers[0xF] = 0;
         }
       },
       (0x8000, _, _, 0x0002) => {
         // Set register VX to VX & VY
         self.registers[x] = self.registers[x] & self.registers[y];
-        if self.variant == Variant::CHIP8 {
+        if self.quirks.vf_reset {
           self.registers[0xF] = 0;
         }
       },
       (0x8000, _, _, 0x0003) => {
         // Set register VX to VX ^ VY
         self.registers[x] = self.registers[x] ^ self.registers[y];
-        if self.variant == Variant::CHIP8 {
+        if self.quirks.vf_reset {
           self.registers[0xF] = 0;
         }
       },
@@ -376,7 +745,7 @@ impl Chip8 {
       },
       (0x8000, _, _, 0x0006) => {
         let lsb = self.registers[x] & 0x01;
-        if !matches!(self.variant, Variant::SCHIP_LEGACY | Variant::SCHIP_MODERN) {
+        if !self.quirks.shifting {
           // Set register VX to VY >> 1
           // Set register VF to the least significant bit prior to the shift
           self.registers[x] = self.registers[y] >> 1;
@@ -395,7 +764,7 @@ impl Chip8 {
       },
       (0x8000, _, _, 0x000E) => {
         let msb = (self.registers[x] & 0x80) >> 7;
-        if !matches!(self.variant, Variant::SCHIP_LEGACY | Variant::SCHIP_MODERN) {
+        if !self.quirks.shifting {
           // Set register VX to VY << 1
           // Set register VF to the most significant bit prior to the shift
           self.registers[x] = self.registers[y] << 1;
@@ -416,7 +785,7 @@ impl Chip8 {
         self.i = nnn;
       },
       (0xB000, _, _, _) => {
-        if !matches!(self.variant, Variant::SCHIP_LEGACY | Variant::SCHIP_MODERN) {
+        if !self.quirks.jumping {
           // Jump to address NNN + V0
           self.pc = nnn + self.registers[0] as u16;
         } else {
@@ -426,7 +795,7 @@ impl Chip8 {
       },
       (0xC000, _, _, _) => {
         // Set VX to a random number with a mask of NN
-        self.registers[x] = rand::random::<u8>() & nn;
+        self.registers[x] = self.rng.gen::<u8>() & nn;
       },
       (0xD000, _, _, _) => {
         // Draw sprite
@@ -447,19 +816,19 @@ impl Chip8 {
 
         // Start iterating through the rows of the sprite
         for row in 0..height {
-          if self.variant != Variant::XOCHIP && y_val + row == max_height {
+          if self.quirks.clipping && y_val + row == max_height {
             break;
           }
           // Start iterating through the bytes in the row
           for column in 0..width {
-            if self.variant != Variant::XOCHIP && x_val + column == max_width {
+            if self.quirks.clipping && x_val + column == max_width {
               break;
             }
             let scale_factor = if self.hires_mode && n == 0 { 2 } else { 1 };
             // An offset to the next byte to apply in case we are drawing a 16x16 sprite
             let offset = if column > 7 { row * scale_factor + 1 } else { row * scale_factor };
             // The location of the sprite in memory.
-            let sprite = self.memory[(self.i + offset) as usize % self.memory.len()];
+            let sprite = self.read_data((self.i + offset) as usize);
             let pixel_x = (x_val + column) % max_width;
             let pixel_y = (y_val + row) % max_height;
             // 0x80 is 0b10000000, this iterates through each bit
@@ -477,6 +846,7 @@ impl Chip8 {
       },
       (0xE000, _, 0x0090, 0x000E) => {
         // Skip next instruction if key stored in VX is pressed
+        self.polled_keys[self.polled_frame] |= 1 << (self.registers[x] & 0xF);
         let index = self.get_keypad_index_from_value(self.registers[x] & 0xF);
         if self.keypad[index] {
           self.skip();
@@ -484,6 +854,7 @@ impl Chip8 {
       },
       (0xE000, _, 0x00A0, 0x0001) => {
         // Skip next instruction if key stored in VX is not pressed
+        self.polled_keys[self.polled_frame] |= 1 << (self.registers[x] & 0xF);
         let index = self.get_keypad_index_from_value(self.registers[x] & 0xF);
         if !self.keypad[index] {
           self.skip();
@@ -493,6 +864,8 @@ impl Chip8 {
         // XOCHIP: load i with a 16-bit address from the next opcode
         let op1 = self.memory[self.pc as usize];
         let op2 = self.memory[(self.pc + 1) as usize];
+        self.coverage.executed[self.pc as usize] = true;
+        self.coverage.executed[self.pc.wrapping_add(1) as usize] = true;
         let next_op = ((op1 as u16) << 8) | (op2 as u16);
         self.i = next_op;
         self.pc = self.pc.wrapping_add(2);
@@ -500,8 +873,9 @@ impl Chip8 {
       (0xF000, 0x0000, 0x0000, 0x0002) => {
         // XOCHIP: store 16 bytes starting at i in the audio pattern buffer.
         for byte in 0..16 {
-          self.audio_pattern_buffer[byte] = self.memory[self.i as usize + byte];
+          self.audio_pattern_buffer[byte] = self.read_data(self.i as usize + byte);
         }
+        self.audio_pattern_loaded = true;
       },
       (0xF000, _, 0x0000, 0x0007) => {
         // Set VX to value of delay timer
@@ -509,6 +883,8 @@ impl Chip8 {
       },
       (0xF000, _, 0x0000, 0x000A) => {
         // Wait for key press and store in VX
+        // Any key will do
+        self.polled_keys[self.polled_frame] = 0xFFFF;
         if !self.keypad_prev.iter().any(|key| *key) {
           self.pc = self.pc.wrapping_sub(2);
         } else {
@@ -548,19 +924,23 @@ impl Chip8 {
         let digit = self.registers[x] & 0x0F;
         self.i = 0x050 + (digit * 5) as u16;
       }
+      (0xF000, _, 0x0030, 0x000A) => {
+        // XOCHIP: set the playback rate of the audio pattern to VX
+        self.pitch = self.registers[x];
+      },
       (0xF000, _, 0x0030, 0x0003) => {
         // Store BCD representation of VX in memory locations I, I+1, and I+2
-        self.memory[self.i as usize % self.memory.len()] = self.registers[x] / 100;
-        self.memory[(self.i as usize + 1) % self.memory.len()] = (self.registers[x] / 10) % 10;
-        self.memory[(self.i as usize + 2) % self.memory.len()] = (self.registers[x] % 100) % 10;
+        self.write_memory(self.i as usize, self.registers[x] / 100);
+        self.write_memory(self.i as usize + 1, (self.registers[x] / 10) % 10);
+        self.write_memory(self.i as usize + 2, (self.registers[x] % 100) % 10);
       },
       (0xF000, _, 0x0050, 0x0005) => {
         // Store the values of registers V0 to VX inclusive in memory starting at address I
         // I is set to I + X + 1 after operation
         for i in 0..(x + 1) {
-          self.memory[(self.i as usize + i) % self.memory.len()] = self.registers[i];
+          self.write_memory(self.i as usize + i, self.registers[i]);
         }
-        if !matches!(self.variant, Variant::SCHIP_LEGACY | Variant::SCHIP_MODERN) {
+        if self.quirks.memory {
           self.i = self.i.wrapping_add(x as u16 + 1);
         }
       },
@@ -568,9 +948,9 @@ impl Chip8 {
         // Fill registers V0 to VX inclusive with the values stored in memory starting at address I
         // I is set to I + X + 1 after operation
         for i in 0..(x + 1) {
-          self.registers[i] = self.memory[(self.i as usize + i) % self.memory.len()];
+          self.registers[i] = self.read_data(self.i as usize + i);
         }
-        if !matches!(self.variant, Variant::SCHIP_LEGACY | Variant::SCHIP_MODERN) {
+        if self.quirks.memory {
           self.i = self.i.wrapping_add(x as u16 + 1);
         }
       },
@@ -587,10 +967,21 @@ impl Chip8 {
         }
       }
       _ => {
-        println!("Unknown opcode: 0x{:04X}", op);
+        if self.halt_on_unknown_opcode {
+          self.pc = self.pc.wrapping_sub(2);
+          self.halted = Some(HaltReason::UnknownOpcode(op));
+        } else if self.reported_unknown_opcodes.insert(addr) {
+          // Once is enough, a loop over a bad opcode would otherwise log it thousands of times a second
+          println!("Unknown opcode 0x{:04X} at 0x{:03X}", op, addr);
+        }
       }
     }
 
+    if is_skip(op) {
+      let taken = self.pc != addr.wrapping_add(2);
+      self.coverage.branches[addr as usize] |= if taken { 0b10 } else { 0b01 };
+    }
+
     // Update keypad states
     for i in 0..self.keypad.len() {
       self.keypad_prev[i] = self.keypad[i];
@@ -622,6 +1013,20 @@ impl Chip8 {
     STANDARD_LAYOUT.iter().position(|x| *x == value).unwrap_or(0)
   }
 
+  /// Write a byte of memory from an instruction, remembering where for debugging tools
+  fn write_memory(&mut self, addr: usize, value: u8) {
+    let addr = addr % self.memory.len();
+    self.memory[addr] = value;
+    self.memory_writes.push(addr as u16);
+  }
+
+  /// Read a byte of memory as data for an instruction, remembering where for code coverage
+  fn read_data(&mut self, addr: usize) -> u8 {
+    let addr = addr % self.memory.len();
+    self.coverage.data_read[addr] = true;
+    self.memory[addr]
+  }
+
   fn max_rows(&self) -> usize {
     if self.hires_mode { 64 } else { 32 }
   }
@@ -0,0 +1,169 @@
+use crate::chip8::{Coverage, Variant};
+use crate::disassembler::{decode, Instruction};
+
+/// Where ROMs are loaded in memory
+const ROM_START: usize = 0x200;
+/// Most bytes of data to put on one line of the listing
+const MAX_DATA_PER_LINE: usize = 8;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Usage {
+  Executed,
+  Data,
+  Unused,
+}
+
+/// A line of a report, either an instruction or a run of bytes that were used the same way
+struct Line {
+  addr: usize,
+  len: usize,
+  usage: Usage,
+  /// Set for executed instructions, and for unused byte pairs since they may be dead code
+  instruction: Option<Instruction>,
+}
+
+/// How much of a ROM was used
+pub struct Summary {
+  pub rom_bytes: usize,
+  pub executed: usize,
+  pub data: usize,
+  pub unused: usize,
+  /// Possible outcomes of the skip instructions that ran, two for each
+  pub branch_outcomes: usize,
+  pub branch_outcomes_covered: usize,
+}
+
+fn is_skip(instruction: &Instruction) -> bool {
+  matches!(instruction.mnemonic, "SE" | "SNE" | "SKP" | "SKNP")
+}
+
+/// How each byte of a `rom_len` byte ROM was used, executed taking priority over read as data
+fn byte_usage(rom_len: usize, coverage: &Coverage) -> Vec<Usage> {
+  let end = ROM_START + rom_len;
+  (ROM_START..end)
+    .map(|addr| match (coverage.executed[addr], coverage.data_read[addr]) {
+      (true, _) => Usage::Executed,
+      (false, true) => Usage::Data,
+      (false, false) => Usage::Unused,
+    })
+    .collect()
+}
+
+fn lines(memory: &[u8], rom_len: usize, coverage: &Coverage, variant: Variant) -> Vec<Line> {
+  let usage = byte_usage(rom_len, coverage);
+  let end = ROM_START + rom_len;
+  let mut lines = Vec::new();
+  let mut addr = ROM_START;
+  while addr < end {
+    if coverage.executed[addr] {
+      let instruction = decode(memory, addr as u16, variant);
+      let len = (instruction.len as usize).min(end - addr);
+      lines.push(Line { addr, len, usage: Usage::Executed, instruction: Some(instruction) });
+      addr += len;
+      continue;
+    }
+
+    let kind = usage[addr - ROM_START];
+    let max_len = if kind == Usage::Unused { 2 } else { MAX_DATA_PER_LINE };
+    let len = (addr..end)
+      .take(max_len)
+      .take_while(|byte| usage[byte - ROM_START] == kind && !coverage.executed[*byte])
+      .count();
+    let instruction = if kind == Usage::Unused && len == 2 { Some(decode(memory, addr as u16, variant)) } else { None };
+    lines.push(Line { addr, len, usage: kind, instruction });
+    addr += len;
+  }
+  lines
+}
+
+/// Count how the bytes of a `rom_len` byte ROM loaded at 0x200 were used
+pub fn summarize(memory: &[u8], rom_len: usize, coverage: &Coverage, variant: Variant) -> Summary {
+  let usage = byte_usage(rom_len, coverage);
+  let count = |kind: Usage| usage.iter().filter(|usage| **usage == kind).count();
+  let mut summary = Summary {
+    rom_bytes: rom_len,
+    executed: count(Usage::Executed),
+    data: count(Usage::Data),
+    unused: count(Usage::Unused),
+    branch_outcomes: 0,
+    branch_outcomes_covered: 0,
+  };
+  for line in lines(memory, rom_len, coverage, variant) {
+    if line.usage == Usage::Executed && line.instruction.as_ref().is_some_and(is_skip) {
+      summary.branch_outcomes += 2;
+      summary.branch_outcomes_covered += coverage.branches[line.addr].count_ones() as usize;
+    }
+  }
+  summary
+}
+
+/// A disassembly of the ROM with every line marked as executed, read as data or unused,
+/// and every skip instruction marked with which of its outcomes happened
+pub fn annotated_listing(memory: &[u8], rom_len: usize, coverage: &Coverage, variant: Variant) -> String {
+  let summary = summarize(memory, rom_len, coverage, variant);
+  let percent = |bytes: usize| bytes as f64 * 100.0 / summary.rom_bytes.max(1) as f64;
+  let mut listing = format!(
+    "; {} of {} bytes executed ({:.1}%), {} read as data ({:.1}%), {} unused ({:.1}%)\n; {} of {} skip outcomes covered\n\n",
+    summary.executed, summary.rom_bytes, percent(summary.executed),
+    summary.data, percent(summary.data),
+    summary.unused, percent(summary.unused),
+    summary.branch_outcomes_covered, summary.branch_outcomes,
+  );
+
+  for line in lines(memory, rom_len, coverage, variant) {
+    let bytes = memory[line.addr..line.addr + line.len].iter().map(|byte| format!("{:02X}", byte)).collect::<String>();
+    let usage = match line.usage {
+      Usage::Executed => "exec",
+      Usage::Data => "data",
+      Usage::Unused => "----",
+    };
+    let text = match &line.instruction {
+      Some(instruction) => instruction.to_string(),
+      None => {
+        let data = memory[line.addr..line.addr + line.len].iter().map(|byte| format!("0x{:02X}", byte)).collect::<Vec<_>>();
+        format!("DB {}", data.join(", "))
+      }
+    };
+    let note = match &line.instruction {
+      Some(instruction) if line.usage == Usage::Executed && is_skip(instruction) => match coverage.branches[line.addr] {
+        0b01 => "; never skipped",
+        0b10 => "; always skipped",
+        _ => "",
+      },
+      _ => "",
+    };
+    listing += format!("0x{:04X}  {}  {:<16}  {:<24}{}", line.addr, usage, bytes, text, note).trim_end();
+    listing += "\n";
+  }
+  listing
+}
+
+/// An LCOV tracefile for the ROM, with each instruction's address standing in for a line number.
+/// Unused bytes count as lines too, since they may be dead code.
+pub fn lcov(memory: &[u8], rom_len: usize, coverage: &Coverage, variant: Variant, name: &str) -> String {
+  let mut report = format!("TN:\nSF:{}\n", name);
+  let (mut lines_found, mut lines_hit, mut branches_found, mut branches_hit) = (0, 0, 0, 0);
+
+  for line in lines(memory, rom_len, coverage, variant) {
+    let instruction = match &line.instruction {
+      Some(instruction) => instruction,
+      None => continue,
+    };
+    let executed = line.usage == Usage::Executed;
+    report += &format!("DA:{},{}\n", line.addr, executed as u8);
+    lines_found += 1;
+    lines_hit += executed as usize;
+
+    if is_skip(instruction) {
+      for (outcome, bit) in [(0, 0b01), (1, 0b10)] {
+        let taken = if !executed { "-" } else if coverage.branches[line.addr] & bit != 0 { "1" } else { "0" };
+        report += &format!("BRDA:{},0,{},{}\n", line.addr, outcome, taken);
+        branches_found += 1;
+        branches_hit += (taken == "1") as usize;
+      }
+    }
+  }
+
+  report += &format!("BRF:{}\nBRH:{}\nLF:{}\nLH:{}\nend_of_record\n", branches_found, branches_hit, lines_found, lines_hit);
+  report
+}
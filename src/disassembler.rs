@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::chip8::Variant;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Operand {
+  Register(u8),
+  /// A register range as used by the XO-CHIP save and load instructions
+  RegisterRange(u8, u8),
+  Byte(u8),
+  Nibble(u8),
+  Address(u16),
+  /// A raw 16-bit value, for data that doesn't decode to an instruction
+  Word(u16),
+  /// A named operand like I, DT or [I]
+  Name(&'static str),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// A decoded instruction, or a data word if the opcode isn't one the interpreter knows
+pub struct Instruction {
+  pub addr: u16,
+  pub opcode: u16,
+  /// Length in bytes, 4 for the XO-CHIP long load and 2 for everything else
+  pub len: u16,
+  pub mnemonic: &'static str,
+  pub operands: Vec<Operand>,
+}
+
+impl Instruction {
+  /// Format the instruction, writing addresses as their label where the symbols have one
+  pub fn to_string_with(&self, symbols: &Symbols) -> String {
+    let operands = self.operands
+      .iter()
+      .map(|operand| match operand {
+        Operand::Register(x) => format!("V{:X}", x),
+        Operand::RegisterRange(x, y) => format!("V{:X}-V{:X}", x, y),
+        Operand::Byte(nn) => format!("0x{:02X}", nn),
+        Operand::Nibble(n) => n.to_string(),
+        Operand::Address(addr) => match symbols.get(*addr) {
+          Some(label) => label.to_string(),
+          None => format!("0x{:03X}", addr),
+        },
+        Operand::Word(word) => format!("0x{:04X}", word),
+        Operand::Name(name) => name.to_string(),
+      })
+      .collect::<Vec<_>>();
+
+    if operands.is_empty() {
+      self.mnemonic.to_string()
+    } else {
+      format!("{} {}", self.mnemonic, operands.join(", "))
+    }
+  }
+}
+
+impl fmt::Display for Instruction {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.to_string_with(&Symbols::default()))
+  }
+}
+
+/// Decode the instruction at `addr`, using the same opcode table as `Chip8::run`
+pub fn decode(memory: &[u8], addr: u16, variant: Variant) -> Instruction {
+  let read = |addr: u16| memory[addr as usize % memory.len()];
+  let op = ((read(addr) as u16) << 8) | read(addr.wrapping_add(1)) as u16;
+
+  let x = ((op & 0x0F00) >> 8) as u8;
+  let y = ((op & 0x00F0) >> 4) as u8;
+  let nnn = op & 0x0FFF;
+  let nn = (op & 0x00FF) as u8;
+  let n = (op & 0x000F) as u8;
+
+  use Operand::*;
+  let (mnemonic, operands) = match (op & 0xF000, x, y, n) {
+    (0x0000, 0x0, 0xC, _) => ("SCD", vec![Nibble(n)]),
+    (0x0000, 0x0, 0xD, _) => ("SCU", vec![Nibble(n)]),
+    (0x0000, 0x0, 0xE, 0x0) => ("CLS", vec![]),
+    (0x0000, 0x0, 0xE, 0xE) => ("RET", vec![]),
+    (0x0000, 0x0, 0xF, 0xB) => ("SCR", vec![]),
+    (0x0000, 0x0, 0xF, 0xC) => ("SCL", vec![]),
+    (0x0000, 0x0, 0xF, 0xD) => ("EXIT", vec![]),
+    (0x0000, 0x0, 0xF, 0xE) => ("LOW", vec![]),
+    (0x0000, 0x0, 0xF, 0xF) => ("HIGH", vec![]),
+    (0x1000, _, _, _) => ("JP", vec![Address(nnn)]),
+    (0x2000, _, _, _) => ("CALL", vec![Address(nnn)]),
+    (0x3000, _, _, _) => ("SE", vec![Register(x), Byte(nn)]),
+    (0x4000, _, _, _) => ("SNE", vec![Register(x), Byte(nn)]),
+    (0x5000, _, _, 0x0) => ("SE", vec![Register(x), Register(y)]),
+    (0x5000, _, _, 0x2) => ("SAVE", vec![RegisterRange(x, y)]),
+    (0x5000, _, _, 0x3) => ("LOAD", vec![RegisterRange(x, y)]),
+    (0x6000, _, _, _) => ("LD", vec![Register(x), Byte(nn)]),
+    (0x7000, _, _, _) => ("ADD", vec![Register(x), Byte(nn)]),
+    (0x8000, _, _, 0x0) => ("LD", vec![Register(x), Register(y)]),
+    (0x8000, _, _, 0x1) => ("OR", vec![Register(x), Register(y)]),
+    (0x8000, _, _, 0x2) => ("AND", vec![Register(x), Register(y)]),
+    (0x8000, _, _, 0x3) => ("XOR", vec![Register(x), Register(y)]),
+    (0x8000, _, _, 0x4) => ("ADD", vec![Register(x), Register(y)]),
+    (0x8000, _, _, 0x5) => ("SUB", vec![Register(x), Register(y)]),
+    (0x8000, _, _, 0x6) => ("SHR", vec![Register(x), Register(y)]),
+    (0x8000, _, _, 0x7) => ("SUBN", vec![Register(x), Register(y)]),
+    (0x8000, _, _, 0xE) => ("SHL", vec![Register(x), Register(y)]),
+    (0x9000, _, _, 0x0) => ("SNE", vec![Register(x), Register(y)]),
+    (0xA000, _, _, _) => ("LD", vec![Name("I"), Address(nnn)]),
+    (0xB000, _, _, _) => {
+      if matches!(variant, Variant::SCHIP_LEGACY | Variant::SCHIP_MODERN) {
+        ("JP", vec![Register(x), Address(nnn)])
+      } else {
+        ("JP", vec![Register(0), Address(nnn)])
+      }
+    },
+    (0xC000, _, _, _) => ("RND", vec![Register(x), Byte(nn)]),
+    (0xD000, _, _, _) => ("DRW", vec![Register(x), Register(y), Nibble(n)]),
+    (0xE000, _, 0x9, 0xE) => ("SKP", vec![Register(x)]),
+    (0xE000, _, 0xA, 0x1) => ("SKNP", vec![Register(x)]),
+    (0xF000, 0x0, 0x0, 0x0) => {
+      let long = ((read(addr.wrapping_add(2)) as u16) << 8) | read(addr.wrapping_add(3)) as u16;
+      return Instruction {
+        addr,
+        opcode: op,
+        len: 4,
+        mnemonic: "LD",
+        operands: vec![Name("I"), Address(long)],
+      };
+    },
+    (0xF000, 0x0, 0x0, 0x2) => ("AUDIO", vec![]),
+    (0xF000, _, 0x0, 0x7) => ("LD", vec![Register(x), Name("DT")]),
+    (0xF000, _, 0x0, 0xA) => ("LD", vec![Register(x), Name("K")]),
+    (0xF000, _, 0x1, 0x5) => ("LD", vec![Name("DT"), Register(x)]),
+    (0xF000, _, 0x1, 0x8) => ("LD", vec![Name("ST"), Register(x)]),
+    (0xF000, _, 0x1, 0xE) => ("ADD", vec![Name("I"), Register(x)]),
+    (0xF000, _, 0x2, 0x9) => ("LD", vec![Name("F"), Register(x)]),
+    (0xF000, _, 0x3, 0x0) => ("LD", vec![Name("HF"), Register(x)]),
+    (0xF000, _, 0x3, 0x3) => ("LD", vec![Name("B"), Register(x)]),
+    (0xF000, _, 0x3, 0xA) => ("PITCH", vec![Register(x)]),
+    (0xF000, _, 0x5, 0x5) => ("LD", vec![Name("[I]"), Register(x)]),
+    (0xF000, _, 0x6, 0x5) => ("LD", vec![Register(x), Name("[I]")]),
+    (0xF000, _, 0x7, 0x5) => ("LD", vec![Name("R"), Register(x)]),
+    (0xF000, _, 0x8, 0x5) => ("LD", vec![Register(x), Name("R")]),
+    _ => ("DW", vec![Word(op)]),
+  };
+
+  Instruction {
+    addr,
+    opcode: op,
+    len: 2,
+    mnemonic,
+    operands,
+  }
+}
+
+#[derive(Clone, Debug, Default)]
+/// Labels for addresses, shown in place of the raw address in disassembly
+pub struct Symbols {
+  labels: HashMap<u16, String>,
+}
+
+impl Symbols {
+  /// Parse a symbol file, skipping blank lines, `#` comments and anything malformed.
+  /// Each line is `<hex address> <label>`, e.g. `0x0200 main`.
+  pub fn from_text(text: &str) -> Symbols {
+    let labels = text
+      .lines()
+      .map(str::trim)
+      .filter(|line| !line.is_empty() && !line.starts_with('#'))
+      .filter_map(|line| {
+        let (addr, label) = line.split_once(char::is_whitespace)?;
+        let addr = u16::from_str_radix(addr.strip_prefix("0x").unwrap_or(addr), 16).ok()?;
+        Some((addr, label.trim().to_string()))
+      })
+      .collect();
+
+    Self { labels }
+  }
+
+  pub fn get(&self, addr: u16) -> Option<&str> {
+    self.labels.get(&addr).map(String::as_str)
+  }
+}
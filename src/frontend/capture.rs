@@ -0,0 +1,166 @@
+use super::palette::Palette;
+
+/// Scales offered for screenshots and recordings
+pub const CAPTURE_SCALES: [usize; 5] = [1, 2, 4, 8, 10];
+const FRAMES_PER_SECOND: u32 = 60;
+/// The shortest GIF frame delay, in hundredths of a second, that viewers play as written rather
+/// than slowing down to 10
+const MIN_GIF_DELAY: u32 = 2;
+
+#[derive(Clone, Debug, PartialEq)]
+/// A rendered display, as tightly packed RGB triplets
+pub struct Frame {
+  pub width: usize,
+  pub height: usize,
+  pub rgb: Vec<u8>,
+}
+
+impl Frame {
+  /// Blow every pixel up into a `scale` x `scale` square
+  pub fn scaled(&self, scale: usize) -> Frame {
+    let scale = scale.max(1);
+    let mut rgb = Vec::with_capacity(self.rgb.len() * scale * scale);
+    for row in self.rgb.chunks_exact(self.width * 3) {
+      let scaled_row = row.chunks_exact(3).flat_map(|pixel| pixel.repeat(scale)).collect::<Vec<_>>();
+      for _ in 0..scale {
+        rgb.extend_from_slice(&scaled_row);
+      }
+    }
+    Frame { width: self.width * scale, height: self.height * scale, rgb }
+  }
+}
+
+/// Encode a frame as a PNG, scaled up by `scale`
+pub fn encode_png(frame: &Frame, scale: usize) -> Result<Vec<u8>, String> {
+  let frame = frame.scaled(scale);
+  let mut bytes = vec![];
+  let mut encoder = png::Encoder::new(&mut bytes, frame.width as u32, frame.height as u32);
+  encoder.set_color(png::ColorType::Rgb);
+  encoder.set_depth(png::BitDepth::Eight);
+  encoder
+    .write_header()
+    .and_then(|mut writer| writer.write_image_data(&frame.rgb))
+    .map_err(|err| format!("Failed to encode PNG: {}", err))?;
+  Ok(bytes)
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum VideoFormat {
+  Gif,
+  Apng,
+}
+
+impl VideoFormat {
+  pub fn extension(&self) -> &'static str {
+    match self {
+      VideoFormat::Gif => "gif",
+      VideoFormat::Apng => "png",
+    }
+  }
+
+  /// Work out the format from a file name, `.png` and `.apng` both meaning APNG
+  pub fn from_path(path: &str) -> Option<VideoFormat> {
+    let extension = path.rsplit_once('.')?.1.to_ascii_lowercase();
+    match extension.as_str() {
+      "gif" => Some(VideoFormat::Gif),
+      "png" | "apng" => Some(VideoFormat::Apng),
+      _ => None,
+    }
+  }
+}
+
+/// Collects every frame shown while recording, to be encoded as an animation. APNG keeps the exact
+/// 60 fps, while GIF can't go above 50 fps and merges frames to fit.
+pub struct VideoRecorder {
+  pub format: VideoFormat,
+  pub scale: usize,
+  /// Colors to record with instead of the emulator's own, if set
+  pub palette: Option<Palette>,
+  /// Each distinct frame along with how many 60 Hz frames it stayed on screen
+  frames: Vec<(Frame, u32)>,
+}
+
+impl VideoRecorder {
+  pub fn new(format: VideoFormat, scale: usize, palette: Option<Palette>) -> VideoRecorder {
+    Self {
+      format,
+      scale: scale.max(1),
+      palette,
+      frames: vec![],
+    }
+  }
+
+  /// Add the frame shown for the next 60th of a second
+  pub fn push(&mut self, frame: Frame) {
+    match self.frames.last_mut() {
+      Some((last, count)) if *last == frame => *count += 1,
+      _ => self.frames.push((frame, 1)),
+    }
+  }
+
+  /// Encode the recording. Switching resolution changes the frame size partway through, so
+  /// low resolution frames are doubled up to fill the same canvas as high resolution ones.
+  pub fn encode(&self) -> Result<Vec<u8>, String> {
+    if self.frames.is_empty() {
+      return Err("Nothing was recorded".to_string());
+    }
+    let width = self.frames.iter().map(|(frame, _)| frame.width).max().unwrap();
+    let frames = self.frames
+      .iter()
+      .map(|(frame, count)| (frame.scaled(self.scale * width / frame.width), *count))
+      .collect::<Vec<_>>();
+
+    match self.format {
+      VideoFormat::Gif => encode_gif(&frames),
+      VideoFormat::Apng => encode_apng(&frames),
+    }
+  }
+}
+
+fn encode_gif(frames: &[(Frame, u32)]) -> Result<Vec<u8>, String> {
+  let (width, height) = (frames[0].0.width as u16, frames[0].0.height as u16);
+  let mut bytes = vec![];
+  let result = (|| {
+    let mut encoder = gif::Encoder::new(&mut bytes, width, height, &[])?;
+    encoder.set_repeat(gif::Repeat::Infinite)?;
+    // GIF delays are in hundredths of a second, so round each frame's end time rather than
+    // its length to keep the animation from drifting. A frame too short to be played properly
+    // is dropped in favor of the one after it, which takes over its time on screen.
+    let mut shown = 0;
+    let mut elapsed = 0;
+    for (index, (frame, count)) in frames.iter().enumerate() {
+      shown += count;
+      let end = (shown * 100 + FRAMES_PER_SECOND / 2) / FRAMES_PER_SECOND;
+      let last = index == frames.len() - 1;
+      if end - elapsed < MIN_GIF_DELAY && !last {
+        continue;
+      }
+      let mut gif_frame = gif::Frame::from_rgb_speed(width, height, &frame.rgb, 10);
+      gif_frame.delay = (end - elapsed).clamp(MIN_GIF_DELAY, u16::MAX as u32) as u16;
+      elapsed = end;
+      encoder.write_frame(&gif_frame)?;
+    }
+    Ok::<(), gif::EncodingError>(())
+  })();
+  result.map_err(|err| format!("Failed to encode GIF: {}", err))?;
+  Ok(bytes)
+}
+
+fn encode_apng(frames: &[(Frame, u32)]) -> Result<Vec<u8>, String> {
+  let (width, height) = (frames[0].0.width as u32, frames[0].0.height as u32);
+  let mut bytes = vec![];
+  let result = (|| {
+    let mut encoder = png::Encoder::new(&mut bytes, width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_animated(frames.len() as u32, 0)?;
+    let mut writer = encoder.write_header()?;
+    for (frame, count) in frames {
+      writer.set_frame_delay((*count).min(u16::MAX as u32) as u16, FRAMES_PER_SECOND as u16)?;
+      writer.write_image_data(&frame.rgb)?;
+    }
+    writer.finish()
+  })();
+  result.map_err(|err| format!("Failed to encode APNG: {}", err))?;
+  Ok(bytes)
+}
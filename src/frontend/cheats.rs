@@ -0,0 +1,232 @@
+use std::fmt;
+
+use crate::chip8::Chip8;
+
+/// Number of searchable locations, all of memory followed by V0 to VF
+const LOCATIONS: usize = 65536 + 16;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+/// A byte a cheat can read or write
+pub enum CheatTarget {
+  Memory(u16),
+  Register(u8),
+}
+
+impl CheatTarget {
+  fn from_location(location: usize) -> CheatTarget {
+    if location < 65536 { CheatTarget::Memory(location as u16) } else { CheatTarget::Register((location - 65536) as u8) }
+  }
+
+  pub fn write(&self, chip8: &mut Chip8, value: u8) {
+    match self {
+      CheatTarget::Memory(addr) => chip8.set_memory(*addr, &[value]),
+      CheatTarget::Register(x) => chip8.set_register(*x, value),
+    }
+  }
+
+  /// Parse a target written as `0x0123` for memory or `V3` for a register
+  pub fn parse(text: &str) -> Option<CheatTarget> {
+    if let Some(register) = text.strip_prefix('V').or_else(|| text.strip_prefix('v')) {
+      u8::from_str_radix(register, 16).ok().filter(|x| *x < 16).map(CheatTarget::Register)
+    } else {
+      let addr = text.strip_prefix("0x").unwrap_or(text);
+      u16::from_str_radix(addr, 16).ok().map(CheatTarget::Memory)
+    }
+  }
+}
+
+impl fmt::Display for CheatTarget {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      CheatTarget::Memory(addr) => write!(f, "0x{:04X}", addr),
+      CheatTarget::Register(x) => write!(f, "V{:X}", x),
+    }
+  }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+/// How a search narrows down its candidates, comparing against the previous snapshot where needed
+pub enum SearchCondition {
+  Equal(u8),
+  GreaterThan(u8),
+  LessThan(u8),
+  Changed,
+  Unchanged,
+  Increased,
+  Decreased,
+}
+
+impl SearchCondition {
+  /// Every condition's name, as accepted by `parse`
+  pub const NAMES: [&'static str; 7] = ["equal", "greater", "less", "changed", "unchanged", "increased", "decreased"];
+
+  /// Parse a condition by name, `value` only being used by the ones that compare against a value
+  pub fn parse(name: &str, value: u8) -> Option<SearchCondition> {
+    match name {
+      "equal" => Some(SearchCondition::Equal(value)),
+      "greater" => Some(SearchCondition::GreaterThan(value)),
+      "less" => Some(SearchCondition::LessThan(value)),
+      "changed" => Some(SearchCondition::Changed),
+      "unchanged" => Some(SearchCondition::Unchanged),
+      "increased" => Some(SearchCondition::Increased),
+      "decreased" => Some(SearchCondition::Decreased),
+      _ => None,
+    }
+  }
+
+  fn matches(&self, current: u8, previous: u8) -> bool {
+    match self {
+      SearchCondition::Equal(value) => current == *value,
+      SearchCondition::GreaterThan(value) => current > *value,
+      SearchCondition::LessThan(value) => current < *value,
+      SearchCondition::Changed => current != previous,
+      SearchCondition::Unchanged => current == previous,
+      SearchCondition::Increased => current > previous,
+      SearchCondition::Decreased => current < previous,
+    }
+  }
+}
+
+/// Narrows down which memory bytes or registers hold a value of interest, e.g. the lives counter,
+/// by repeatedly filtering the candidates as the game runs
+pub struct CheatSearch {
+  snapshot: Vec<u8>,
+  candidates: Vec<usize>,
+}
+
+impl CheatSearch {
+  /// Start a search with every location as a candidate
+  pub fn new(chip8: &Chip8) -> CheatSearch {
+    Self {
+      snapshot: take_snapshot(chip8),
+      candidates: (0..LOCATIONS).collect(),
+    }
+  }
+
+  /// Keep only the candidates matching `condition`, then snapshot the current values for the next filter
+  pub fn filter(&mut self, chip8: &Chip8, condition: SearchCondition) {
+    let current = take_snapshot(chip8);
+    self.candidates.retain(|location| condition.matches(current[*location], self.snapshot[*location]));
+    self.snapshot = current;
+  }
+
+  pub fn len(&self) -> usize {
+    self.candidates.len()
+  }
+
+  /// The remaining candidates with their value as of the last snapshot
+  pub fn results(&self) -> impl Iterator<Item = (CheatTarget, u8)> + '_ {
+    self.candidates.iter().map(|location| (CheatTarget::from_location(*location), self.snapshot[*location]))
+  }
+}
+
+fn take_snapshot(chip8: &Chip8) -> Vec<u8> {
+  let mut snapshot = chip8.get_memory();
+  snapshot.extend(chip8.get_registers());
+  snapshot
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// A value frozen in place, written back after every frame while enabled
+pub struct Cheat {
+  pub name: String,
+  pub target: CheatTarget,
+  pub value: u8,
+  pub enabled: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CheatList {
+  pub cheats: Vec<Cheat>,
+}
+
+impl CheatList {
+  pub fn any_enabled(&self) -> bool {
+    self.cheats.iter().any(|cheat| cheat.enabled)
+  }
+
+  /// Write every enabled cheat's value into the interpreter
+  pub fn apply(&self, chip8: &mut Chip8) {
+    for cheat in self.cheats.iter().filter(|cheat| cheat.enabled) {
+      cheat.target.write(chip8, cheat.value);
+    }
+  }
+
+  /// Parse cheats saved by `to_text`, skipping blank lines, `#` comments and anything malformed.
+  /// Each line is `<enabled 0/1> <target> <hex value> <name>`, e.g. `1 V3 0x09 Infinite lives`.
+  pub fn from_text(text: &str) -> CheatList {
+    let cheats = text
+      .lines()
+      .map(str::trim)
+      .filter(|line| !line.is_empty() && !line.starts_with('#'))
+      .filter_map(|line| {
+        let mut parts = line.splitn(4, ' ');
+        let enabled = parts.next()? == "1";
+        let target = CheatTarget::parse(parts.next()?)?;
+        let value = parts.next()?;
+        let value = u8::from_str_radix(value.strip_prefix("0x").unwrap_or(value), 16).ok()?;
+        let name = parts.next().unwrap_or("").to_string();
+        Some(Cheat { name, target, value, enabled })
+      })
+      .collect();
+
+    Self { cheats }
+  }
+
+  pub fn to_text(&self) -> String {
+    self.cheats
+      .iter()
+      .map(|cheat| format!("{} {} 0x{:02X} {}\n", cheat.enabled as u8, cheat.target, cheat.value, cheat.name))
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::chip8::Variant;
+
+  #[test]
+  fn search_narrows_down_to_the_value_that_changed() {
+    let mut chip8 = Chip8::new(Variant::CHIP8);
+    let mut search = CheatSearch::new(&chip8);
+    assert_eq!(search.len(), LOCATIONS);
+
+    // Two places start out holding 3 lives
+    chip8.set_memory(0x300, &[3]);
+    chip8.set_register(5, 3);
+    search.filter(&chip8, SearchCondition::Equal(3));
+    assert_eq!(search.results().collect::<Vec<_>>(), [(CheatTarget::Memory(0x300), 3), (CheatTarget::Register(5), 3)]);
+
+    // Only one of them goes down when a life is lost
+    chip8.set_memory(0x300, &[2]);
+    search.filter(&chip8, SearchCondition::Decreased);
+    assert_eq!(search.results().collect::<Vec<_>>(), [(CheatTarget::Memory(0x300), 2)]);
+
+    search.filter(&chip8, SearchCondition::Changed);
+    assert_eq!(search.len(), 0);
+  }
+
+  #[test]
+  fn only_enabled_cheats_are_frozen() {
+    let mut chip8 = Chip8::new(Variant::CHIP8);
+    let cheats = CheatList::from_text("1 0x0300 0x09 Infinite lives\n0 V5 0x07 Level select\n");
+    assert!(cheats.any_enabled());
+
+    chip8.set_memory(0x300, &[2]);
+    cheats.apply(&mut chip8);
+    assert_eq!(chip8.get_memory()[0x300], 9);
+    assert_eq!(chip8.get_registers()[5], 0);
+  }
+
+  #[test]
+  fn cheats_survive_a_round_trip_through_text() {
+    let text = "# Saved cheats\n\n1 V3 0x09 Infinite lives\n0 0x0A1B 0xFF Max score\n1 V3 nonsense\n1 VG 0x01\n";
+    let cheats = CheatList::from_text(text);
+    assert_eq!(cheats.cheats, [
+      Cheat { name: "Infinite lives".to_string(), target: CheatTarget::Register(3), value: 0x09, enabled: true },
+      Cheat { name: "Max score".to_string(), target: CheatTarget::Memory(0x0A1B), value: 0xFF, enabled: false },
+    ]);
+    assert_eq!(CheatList::from_text(&cheats.to_text()), cheats);
+  }
+}
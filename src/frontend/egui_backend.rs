@@ -0,0 +1,130 @@
+use eframe::egui;
+use egui::Key;
+
+use super::{DisplaySink, InputSource};
+
+/// Keyboard keys for each keypad index, in COSMAC layout order
+pub const KEYBOARD_LAYOUT: [(Key, u8); 16] = [
+  (Key::Num1, 0),
+  (Key::Num2, 1),
+  (Key::Num3, 2),
+  (Key::Num4, 3),
+  (Key::Q, 4),
+  (Key::W, 5),
+  (Key::E, 6),
+  (Key::R, 7),
+  (Key::A, 8),
+  (Key::S, 9),
+  (Key::D, 10),
+  (Key::F, 11),
+  (Key::Z, 12),
+  (Key::X, 13),
+  (Key::C, 14),
+  (Key::V, 15),
+];
+
+/// Renders the display into an egui texture
+pub struct EguiDisplay {
+  ctx: egui::Context,
+  texture: Option<egui::TextureHandle>,
+}
+
+impl EguiDisplay {
+  pub fn new(ctx: egui::Context) -> EguiDisplay {
+    Self {
+      ctx,
+      texture: None,
+    }
+  }
+
+  /// Draw the last presented frame at the given size
+  pub fn show(&self, ui: &mut egui::Ui, size: egui::Vec2) -> egui::Response {
+    match &self.texture {
+      Some(texture) => {
+        let sized_image = egui::load::SizedTexture::new(texture.id(), size);
+        ui.add(egui::Image::from_texture(sized_image))
+      },
+      None => ui.allocate_response(size, egui::Sense::hover()),
+    }
+  }
+}
+
+impl DisplaySink for EguiDisplay {
+  fn present(&mut self, width: usize, height: usize, rgb: &[u8]) {
+    let color_image = egui::ColorImage::from_rgb([width, height], rgb);
+    match &mut self.texture {
+      Some(texture) => texture.set(color_image, egui::TextureOptions::NEAREST),
+      None => self.texture = Some(self.ctx.load_texture("Display", color_image, egui::TextureOptions::NEAREST)),
+    }
+  }
+}
+
+/// The keyboard key for each keypad key, in COSMAC layout order
+pub fn default_key_layout() -> [Key; 16] {
+  let mut layout = [Key::Num1; 16];
+  for (key, value) in KEYBOARD_LAYOUT {
+    layout[value as usize] = key;
+  }
+  layout
+}
+
+/// Reads the keypad from the egui keyboard state
+pub struct EguiKeyboard {
+  ctx: egui::Context,
+  layout: [Key; 16],
+}
+
+impl EguiKeyboard {
+  pub fn new(ctx: egui::Context) -> EguiKeyboard {
+    Self {
+      ctx,
+      layout: default_key_layout(),
+    }
+  }
+
+  /// Map keypad keys to keyboard keys, in COSMAC layout order
+  pub fn set_layout(&mut self, layout: [Key; 16]) {
+    self.layout = layout;
+  }
+}
+
+impl InputSource for EguiKeyboard {
+  fn keypad_state(&mut self) -> [bool; 16] {
+    let mut state = [false; 16];
+    self.ctx.input(|i| {
+      for (value, key) in self.layout.iter().enumerate() {
+        state[value] = i.key_down(*key);
+      }
+    });
+    state
+  }
+}
+
+/// Dim the display and tell the user the program has stopped running
+pub fn draw_program_ended_overlay(ui: &egui::Ui, rect: egui::Rect) {
+  draw_overlay(ui, rect, "Program ended");
+}
+
+/// Darken the display to show that a file being dragged over it will be loaded when dropped
+pub fn draw_drop_overlay(ui: &egui::Ui, rect: egui::Rect) {
+  draw_overlay(ui, rect, "Drop to load ROM");
+}
+
+/// Show a message in a strip along the bottom of the display, e.g. why a file couldn't be loaded
+pub fn draw_message_overlay(ui: &egui::Ui, rect: egui::Rect, text: &str) {
+  let galley = ui.painter().layout(text.to_string(), egui::FontId::proportional(16.0), egui::Color32::WHITE, rect.width() - 16.0);
+  let strip = egui::Rect::from_min_max(egui::pos2(rect.left(), rect.bottom() - galley.size().y - 16.0), rect.max);
+  ui.painter().rect_filled(strip, 0.0, egui::Color32::from_black_alpha(200));
+  ui.painter().galley(strip.min + egui::vec2(8.0, 8.0), galley, egui::Color32::WHITE);
+}
+
+fn draw_overlay(ui: &egui::Ui, rect: egui::Rect, text: &str) {
+  ui.painter().rect_filled(rect, 0.0, egui::Color32::from_black_alpha(160));
+  ui.painter().text(
+    rect.center(),
+    egui::Align2::CENTER_CENTER,
+    text,
+    egui::FontId::proportional(32.0),
+    egui::Color32::WHITE,
+  );
+}
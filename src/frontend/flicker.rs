@@ -0,0 +1,182 @@
+use std::collections::VecDeque;
+
+use super::palette::Palette;
+
+/// How much of a pixel's brightness is left one frame after it turns off
+const DECAY_PER_FRAME: f32 = 0.6;
+/// Frames to wait for the program to sync to the delay timer before showing the display anyway
+const MAX_UNSYNCED_FRAMES: u32 = 4;
+pub const BLEND_FRAME_PRESETS: [usize; 3] = [2, 3, 4];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+/// Ways of hiding the flicker caused by sprites being erased and redrawn with XOR
+pub enum FlickerMode {
+  Off,
+  /// Show a pixel if it was lit in any of the last few frames
+  Blend,
+  /// Fade pixels out over a few frames, like a phosphor screen
+  Decay,
+  /// Only show the display once the program has finished drawing and waits on the delay timer
+  VblankOnly,
+}
+
+impl FlickerMode {
+  pub const ALL: [FlickerMode; 4] = [FlickerMode::Off, FlickerMode::Blend, FlickerMode::Decay, FlickerMode::VblankOnly];
+
+  /// The name used for the mode in menus and the web API
+  pub fn name(&self) -> &'static str {
+    match self {
+      FlickerMode::Off => "off",
+      FlickerMode::Blend => "blend",
+      FlickerMode::Decay => "decay",
+      FlickerMode::VblankOnly => "vblank",
+    }
+  }
+
+  pub fn from_name(name: &str) -> Option<FlickerMode> {
+    FlickerMode::ALL.into_iter().find(|mode| mode.name().eq_ignore_ascii_case(name))
+  }
+
+  pub fn label(&self) -> &'static str {
+    match self {
+      FlickerMode::Off => "Off",
+      FlickerMode::Blend => "Frame Blending",
+      FlickerMode::Decay => "Phosphor Decay",
+      FlickerMode::VblankOnly => "Draw on Vblank Only",
+    }
+  }
+}
+
+/// Turns the display buffer at the end of each frame into what's shown on screen
+pub struct FlickerFilter {
+  mode: FlickerMode,
+  blend_frames: usize,
+  /// The displays at the end of the last `blend_frames` frames, newest last
+  history: VecDeque<Vec<u8>>,
+  /// How lit each pixel is on the first and second planes, from 0 to 1
+  intensity: Vec<[f32; 2]>,
+  /// The display as of the last time the program synced to the delay timer
+  latched: Vec<u8>,
+  unsynced_frames: u32,
+}
+
+impl FlickerFilter {
+  pub fn new() -> FlickerFilter {
+    Self {
+      mode: FlickerMode::Off,
+      blend_frames: BLEND_FRAME_PRESETS[0],
+      history: VecDeque::new(),
+      intensity: vec![],
+      latched: vec![],
+      unsynced_frames: 0,
+    }
+  }
+
+  pub fn mode(&self) -> FlickerMode {
+    self.mode
+  }
+
+  pub fn set_mode(&mut self, mode: FlickerMode) {
+    self.mode = mode;
+    self.clear();
+  }
+
+  pub fn blend_frames(&self) -> usize {
+    self.blend_frames
+  }
+
+  /// Set how many frames blending combines, at least 2
+  pub fn set_blend_frames(&mut self, frames: usize) {
+    self.blend_frames = frames.max(2);
+    // The newest frames are at the back
+    while self.history.len() > self.blend_frames {
+      self.history.pop_front();
+    }
+  }
+
+  /// Forget earlier frames, e.g. after a reset
+  pub fn clear(&mut self) {
+    self.history.clear();
+    self.intensity.clear();
+    self.latched.clear();
+    self.unsynced_frames = 0;
+  }
+
+  /// Take in the display at the end of a frame. `synced` is whether the program read the delay
+  /// timer after it last drew anything, which most games do while waiting for the next frame.
+  pub fn end_frame(&mut self, display: &[u8], synced: bool) {
+    // Switching resolution changes the buffer size, and nothing from before is worth keeping
+    if self.history.back().is_some_and(|frame| frame.len() != display.len())
+      || (!self.intensity.is_empty() && self.intensity.len() != display.len())
+      || (!self.latched.is_empty() && self.latched.len() != display.len()) {
+      self.clear();
+    }
+
+    match self.mode {
+      FlickerMode::Off => {},
+      FlickerMode::Blend => {
+        if self.history.len() == self.blend_frames {
+          self.history.pop_front();
+        }
+        self.history.push_back(display.to_vec());
+      },
+      FlickerMode::Decay => {
+        self.intensity.resize(display.len(), [0.0; 2]);
+        for (intensity, pixel) in self.intensity.iter_mut().zip(display) {
+          for (plane, level) in intensity.iter_mut().enumerate() {
+            *level = if pixel & (1 << plane) != 0 { 1.0 } else { *level * DECAY_PER_FRAME };
+          }
+        }
+      },
+      FlickerMode::VblankOnly => {
+        self.unsynced_frames += 1;
+        if synced || self.unsynced_frames >= MAX_UNSYNCED_FRAMES || self.latched.is_empty() {
+          self.latched = display.to_vec();
+          self.unsynced_frames = 0;
+        }
+      },
+    }
+  }
+
+  /// The colors to show for the current display
+  pub fn render(&self, display: &[u8], palette: &Palette) -> Vec<u8> {
+    let pixels = match self.mode {
+      FlickerMode::Blend if self.history.back().is_some_and(|frame| frame.len() == display.len()) => {
+        let mut blended = display.to_vec();
+        for frame in &self.history {
+          for (pixel, earlier) in blended.iter_mut().zip(frame) {
+            *pixel |= earlier;
+          }
+        }
+        blended
+      },
+      FlickerMode::Decay if self.intensity.len() == display.len() => {
+        return self.intensity
+          .iter()
+          .zip(display)
+          .flat_map(|(intensity, pixel)| {
+            // Lit pixels are always at full brightness, even mid-frame
+            let first = if pixel & 1 != 0 { 1.0 } else { intensity[0] };
+            let second = if pixel & 2 != 0 { 1.0 } else { intensity[1] };
+            mix(palette, first, second)
+          })
+          .collect();
+      },
+      FlickerMode::VblankOnly if self.latched.len() == display.len() => self.latched.clone(),
+      _ => display.to_vec(),
+    };
+    pixels.iter().flat_map(|pixel| palette.color(*pixel)).collect()
+  }
+}
+
+/// Blend the four palette colors by how lit each plane is
+fn mix(palette: &Palette, first: f32, second: f32) -> [u8; 3] {
+  let weights = [(1.0 - first) * (1.0 - second), first * (1.0 - second), (1.0 - first) * second, first * second];
+  let mut rgb = [0.0_f32; 3];
+  for (color, weight) in palette.colors.iter().zip(weights) {
+    for (channel, value) in rgb.iter_mut().zip(color) {
+      *channel += *value as f32 * weight;
+    }
+  }
+  rgb.map(|channel| channel.round() as u8)
+}
@@ -0,0 +1,554 @@
+pub mod capture;
+pub mod cheats;
+pub mod egui_backend;
+pub mod flicker;
+pub mod movie;
+pub mod palette;
+pub mod rodio_backend;
+pub mod scheduler;
+
+use std::collections::{HashMap, HashSet};
+
+use web_time::Instant;
+
+use crate::chip8::{Chip8, Variant};
+use crate::square_wave::Tone;
+use crate::wav_recorder::WavRecorder;
+use capture::{Frame, VideoFormat, VideoRecorder};
+use cheats::CheatList;
+use flicker::{FlickerFilter, FlickerMode};
+use movie::{keypad_from_bits, keypad_to_bits, Movie, MovieState};
+use palette::Palette;
+use scheduler::FrameScheduler;
+
+/// Slow motion and normal speed choices offered by the frontends
+pub const SPEED_PRESETS: [f32; 3] = [0.25, 0.5, 1.0];
+/// Speed multipliers offered for while the turbo key is held
+pub const TURBO_PRESETS: [f32; 3] = [2.0, 4.0, 8.0];
+/// Instructions per frame choices offered by the frontends
+pub const IPF_PRESETS: [u32; 10] = [7, 10, 15, 20, 30, 50, 100, 200, 500, 1000];
+
+/// Something that can show the CHIP-8 display to the user
+pub trait DisplaySink {
+  /// Present a frame of `width` x `height` pixels, given as tightly packed RGB triplets
+  fn present(&mut self, width: usize, height: usize, rgb: &[u8]);
+}
+
+/// Something that can play the CHIP-8 buzzer
+pub trait AudioSink {
+  /// Start or stop the buzzer, called once per frame with the current sound timer state
+  fn set_playing(&mut self, playing: bool);
+}
+
+/// Something that can report the state of the 16 keypad keys
+pub trait InputSource {
+  /// Whether each keypad key is currently held, indexed in COSMAC layout order
+  fn keypad_state(&mut self) -> [bool; 16];
+}
+
+impl<T: InputSource + ?Sized> InputSource for &mut T {
+  fn keypad_state(&mut self) -> [bool; 16] {
+    (**self).keypad_state()
+  }
+}
+
+impl InputSource for [bool; 16] {
+  fn keypad_state(&mut self) -> [bool; 16] {
+    *self
+  }
+}
+
+/// Combine two input sources, a key counts as held if either source holds it
+impl<A: InputSource, B: InputSource> InputSource for (A, B) {
+  fn keypad_state(&mut self) -> [bool; 16] {
+    let a = self.0.keypad_state();
+    let b = self.1.keypad_state();
+    std::array::from_fn(|i| a[i] || b[i])
+  }
+}
+
+/// How many instructions to run per frame for a variant when the ROM doesn't say otherwise
+pub fn default_ipf(variant: Variant) -> u32 {
+  match variant {
+    Variant::CHIP8 => 10,
+    Variant::SCHIP_LEGACY | Variant::SCHIP_MODERN => 30,
+    Variant::XOCHIP => 100,
+  }
+}
+
+/// A stable 64-bit FNV-1a hash of a ROM, used to remember per-ROM settings
+pub fn rom_hash(bytes: &[u8]) -> u64 {
+  bytes.iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+/// A frame a breakpoint stopped part way through, finished when emulation resumes
+struct PartialFrame {
+  keypad: [bool; 16],
+  /// Instructions already run this frame
+  executed: u32,
+  /// Whether the program has read the delay timer since it last drew, i.e. it's done drawing
+  synced: bool,
+}
+
+/// Drives a `Chip8` one frame at a time, independent of how it is displayed or heard
+pub struct Emulator {
+  chip8: Chip8,
+  variant: Variant,
+  rom: Option<Vec<u8>>,
+  scheduler: FrameScheduler,
+  ipf: u32,
+  // Instructions per frame chosen for each ROM this session, by ROM hash
+  rom_ipf: HashMap<u64, u32>,
+  palette: Palette,
+  // Palette chosen for each ROM this session, by ROM hash
+  rom_palette: HashMap<u64, Palette>,
+  flicker: FlickerFilter,
+  video: Option<VideoRecorder>,
+  wav: Option<WavRecorder>,
+  speed: f32,
+  turbo_multiplier: f32,
+  turbo_held: bool,
+  movie: MovieState,
+  cheats: CheatList,
+  breakpoints: HashSet<u16>,
+  run_to: Option<u16>,
+  // The breakpoint emulation last stopped at, which mustn't stop it again when resuming
+  stopped_at: Option<u16>,
+  partial_frame: Option<PartialFrame>,
+}
+
+impl Emulator {
+  /// Create a new emulator with the font loaded and no ROM
+  pub fn new(variant: Variant) -> Emulator {
+    let mut chip8 = Chip8::new(variant);
+    chip8.load_font();
+
+    Self {
+      chip8,
+      variant,
+      rom: None,
+      scheduler: FrameScheduler::new(),
+      ipf: default_ipf(variant),
+      rom_ipf: HashMap::new(),
+      palette: Palette::default(),
+      rom_palette: HashMap::new(),
+      flicker: FlickerFilter::new(),
+      video: None,
+      wav: None,
+      speed: 1.0,
+      turbo_multiplier: 4.0,
+      turbo_held: false,
+      movie: MovieState::Idle,
+      cheats: CheatList::default(),
+      breakpoints: HashSet::new(),
+      run_to: None,
+      stopped_at: None,
+      partial_frame: None,
+    }
+  }
+
+  /// Reset the interpreter and load a ROM from a sequence of Uint8s
+  pub fn load_rom(&mut self, bytes: Vec<u8>) {
+    self.ipf = *self.rom_ipf.get(&rom_hash(&bytes)).unwrap_or(&default_ipf(self.variant));
+    self.palette = *self.rom_palette.get(&rom_hash(&bytes)).unwrap_or(&self.palette);
+    self.rom = Some(bytes);
+    self.movie = MovieState::Idle;
+    self.cheats = CheatList::default();
+    self.chip8.set_seed(rand::random());
+    self.hard_reset();
+  }
+
+  /// Restart the current ROM from a clean memory, as if it was just loaded
+  pub fn hard_reset(&mut self) {
+    if let Some(rom) = &self.rom {
+      self.chip8.reset();
+      self.chip8.clear_memory();
+      self.chip8.load_font();
+      self.chip8.load_rom_from_bytes(rom.clone());
+      self.stopped_at = None;
+      self.partial_frame = None;
+      self.flicker.clear();
+    }
+  }
+
+  /// Restart the current ROM like the interpreter's own reset, leaving memory as the program
+  /// left it, including any code or data it modified
+  pub fn soft_reset(&mut self) {
+    if self.rom.is_some() {
+      self.chip8.reset();
+      self.stopped_at = None;
+      self.partial_frame = None;
+      self.flicker.clear();
+    }
+  }
+
+  /// Whether a movie is being recorded or played back, which resets and variant changes would break
+  pub fn is_movie_active(&self) -> bool {
+    !matches!(self.movie, MovieState::Idle)
+  }
+
+  pub fn chip8(&self) -> &Chip8 {
+    &self.chip8
+  }
+
+  pub fn variant(&self) -> Variant {
+    self.variant
+  }
+
+  pub fn chip8_mut(&mut self) -> &mut Chip8 {
+    &mut self.chip8
+  }
+
+  pub fn cheats(&self) -> &CheatList {
+    &self.cheats
+  }
+
+  /// Replace the cheats, which are cleared whenever a new ROM is loaded and held off while a
+  /// movie is recording or playing
+  pub fn set_cheats(&mut self, cheats: CheatList) {
+    self.cheats = cheats;
+  }
+
+  pub fn rom(&self) -> Option<&[u8]> {
+    self.rom.as_deref()
+  }
+
+  /// Hash of the loaded ROM, if there is one
+  pub fn rom_hash(&self) -> Option<u64> {
+    self.rom.as_ref().map(|rom| rom_hash(rom))
+  }
+
+  /// Restart the current ROM with exactly the given settings, so another run with the same
+  /// input, e.g. a movie or a netplay peer, behaves identically
+  pub fn restart_with(&mut self, variant: Variant, seed: u64, ipf: u32) {
+    self.variant = variant;
+    self.chip8.set_variant(variant);
+    self.chip8.set_seed(seed);
+    self.ipf = ipf;
+    self.hard_reset();
+  }
+
+  pub fn ipf(&self) -> u32 {
+    self.ipf
+  }
+
+  /// Set the instructions per frame, remembered for the current ROM
+  pub fn set_ipf(&mut self, ipf: u32) {
+    // A movie only stores one speed, so changing it mid-movie would break playback
+    if !matches!(self.movie, MovieState::Idle) {
+      return;
+    }
+    self.ipf = ipf.max(1);
+    if let Some(rom) = &self.rom {
+      self.rom_ipf.insert(rom_hash(rom), self.ipf);
+    }
+  }
+
+  pub fn palette(&self) -> Palette {
+    self.palette
+  }
+
+  /// Set the display colors, remembered for the current ROM
+  pub fn set_palette(&mut self, palette: Palette) {
+    self.palette = palette;
+    if let Some(rom) = &self.rom {
+      self.rom_palette.insert(rom_hash(rom), palette);
+    }
+  }
+
+  pub fn flicker_mode(&self) -> FlickerMode {
+    self.flicker.mode()
+  }
+
+  pub fn set_flicker_mode(&mut self, mode: FlickerMode) {
+    self.flicker.set_mode(mode);
+  }
+
+  /// How many frames frame blending combines
+  pub fn blend_frames(&self) -> usize {
+    self.flicker.blend_frames()
+  }
+
+  pub fn set_blend_frames(&mut self, frames: usize) {
+    self.flicker.set_blend_frames(frames);
+  }
+
+  pub fn speed(&self) -> f32 {
+    self.speed
+  }
+
+  /// Set the emulation speed, below 1.0 for slow motion
+  pub fn set_speed(&mut self, speed: f32) {
+    self.speed = speed;
+    self.update_scheduler_speed();
+  }
+
+  pub fn turbo_multiplier(&self) -> f32 {
+    self.turbo_multiplier
+  }
+
+  /// Set how much faster emulation runs while the turbo key is held
+  pub fn set_turbo_multiplier(&mut self, multiplier: f32) {
+    self.turbo_multiplier = multiplier;
+    self.update_scheduler_speed();
+  }
+
+  pub fn set_turbo_held(&mut self, held: bool) {
+    if self.turbo_held != held {
+      self.turbo_held = held;
+      self.update_scheduler_speed();
+    }
+  }
+
+  pub fn is_paused(&self) -> bool {
+    self.scheduler.is_paused()
+  }
+
+  pub fn set_paused(&mut self, paused: bool) {
+    self.scheduler.set_paused(paused);
+  }
+
+  /// Run a single frame on the next update, only while paused
+  pub fn advance_frame(&mut self) {
+    self.scheduler.step();
+  }
+
+  /// Run however many frames are due since the last update, keeping emulation at 60 Hz
+  pub fn update(&mut self, now: Instant, input: &mut impl InputSource, audio: &mut impl AudioSink) {
+    let frames = self.frames_due(now);
+    for _ in 0..frames {
+      self.run_frame(input, audio);
+      if self.is_paused() {
+        // A breakpoint was hit, leave the remaining frames for when emulation resumes
+        break;
+      }
+    }
+
+    // Don't leave the buzzer droning while nothing is running
+    if frames == 0 && self.is_paused() {
+      audio.set_playing(false);
+    }
+  }
+
+  /// How many frames are due since the last call, for frontends that run frames themselves
+  pub fn frames_due(&mut self, now: Instant) -> u32 {
+    self.scheduler.frames_due(now)
+  }
+
+  pub fn is_recording_movie(&self) -> bool {
+    matches!(self.movie, MovieState::Recording(_))
+  }
+
+  pub fn is_playing_movie(&self) -> bool {
+    matches!(self.movie, MovieState::Playing { .. })
+  }
+
+  /// Restart the current ROM and record the input of every frame from here on
+  pub fn start_recording_movie(&mut self) -> Result<(), String> {
+    let rom = self.rom.as_ref().ok_or("No ROM loaded to record")?;
+    let movie = Movie::new(self.variant, self.chip8.get_seed(), rom_hash(rom), self.ipf);
+    self.hard_reset();
+    self.movie = MovieState::Recording(movie);
+    Ok(())
+  }
+
+  /// Stop recording and hand back the movie, if one was being recorded
+  pub fn stop_recording_movie(&mut self) -> Option<Movie> {
+    match std::mem::replace(&mut self.movie, MovieState::Idle) {
+      MovieState::Recording(movie) => Some(movie),
+      other => {
+        self.movie = other;
+        None
+      }
+    }
+  }
+
+  /// Restart the current ROM with the movie's settings and replay its input in place of live input
+  pub fn play_movie(&mut self, movie: Movie) -> Result<(), String> {
+    let rom = self.rom.as_ref().ok_or("No ROM loaded to play the movie on")?;
+    if rom_hash(rom) != movie.rom_hash {
+      return Err("Movie was recorded with a different ROM".to_string());
+    }
+    if movie.frames.is_empty() {
+      return Err("Movie has no frames".to_string());
+    }
+
+    self.restart_with(movie.variant, movie.seed, movie.ipf);
+    self.movie = MovieState::Playing { movie, frame: 0 };
+    Ok(())
+  }
+
+  /// Stop playing a movie and go back to live input
+  pub fn stop_movie(&mut self) {
+    if self.is_playing_movie() {
+      self.movie = MovieState::Idle;
+    }
+  }
+
+  pub fn breakpoints(&self) -> &HashSet<u16> {
+    &self.breakpoints
+  }
+
+  /// Set or clear the breakpoint at `addr`
+  pub fn toggle_breakpoint(&mut self, addr: u16) {
+    if !self.breakpoints.remove(&addr) {
+      self.breakpoints.insert(addr);
+    }
+  }
+
+  /// Resume emulation until the program counter reaches `addr` or a breakpoint is hit
+  pub fn run_to(&mut self, addr: u16) {
+    self.run_to = Some(addr);
+    self.set_paused(false);
+  }
+
+  /// The breakpoint emulation is paused at, if it was paused by one
+  pub fn stopped_at(&self) -> Option<u16> {
+    if self.is_paused() { self.stopped_at } else { None }
+  }
+
+  /// The format being recorded to, if recording video
+  pub fn video_format(&self) -> Option<VideoFormat> {
+    self.video.as_ref().map(|video| video.format)
+  }
+
+  /// Start capturing every frame shown, replacing any recording already going
+  pub fn start_recording_video(&mut self, recorder: VideoRecorder) {
+    self.video = Some(recorder);
+  }
+
+  pub fn stop_recording_video(&mut self) -> Option<VideoRecorder> {
+    self.video.take()
+  }
+
+  pub fn is_recording_audio(&self) -> bool {
+    self.wav.is_some()
+  }
+
+  /// Start capturing the buzzer played with `tone`, or the XO-CHIP audio pattern at the same
+  /// volume, replacing any recording already going
+  pub fn start_recording_audio(&mut self, tone: Tone) {
+    let mut wav = WavRecorder::new(tone);
+    wav.set_playing(self.buzzer_on(), self.chip8.audio_pattern(), 0.0);
+    self.wav = Some(wav);
+  }
+
+  pub fn stop_recording_audio(&mut self) -> Option<WavRecorder> {
+    self.wav.take()
+  }
+
+  fn buzzer_on(&self) -> bool {
+    self.chip8.get_sound_timer() > 0 && !self.chip8.is_halted()
+  }
+
+  fn update_scheduler_speed(&mut self) {
+    let multiplier = if self.turbo_held { self.turbo_multiplier } else { 1.0 };
+    self.scheduler.set_speed((self.speed * multiplier) as f64);
+  }
+
+  /// Read input, run one 60 Hz frame's worth of instructions, tick the timers and update the buzzer
+  pub fn run_frame(&mut self, input: &mut impl InputSource, audio: &mut impl AudioSink) {
+    // A frame cut short by a breakpoint carries on with the input it started with
+    let (keypad, already_executed, mut synced) = match self.partial_frame.take() {
+      Some(partial) => (partial.keypad, partial.executed, partial.synced),
+      None => {
+        let keypad = match &self.movie {
+          MovieState::Playing { movie, frame } => keypad_from_bits(movie.frames[*frame]),
+          _ => input.keypad_state(),
+        };
+        if self.rom.is_some() && !self.chip8.is_halted() {
+          self.chip8.clear_memory_writes();
+          self.chip8.start_polling_frame();
+        }
+        (keypad, 0, false)
+      },
+    };
+    for (index, pressed) in keypad.iter().enumerate() {
+      self.chip8.set_keypad_state(index as u8, *pressed);
+    }
+
+    if self.rom.is_some() && !self.chip8.is_halted() {
+      for executed in (already_executed + 1)..=self.ipf {
+        let pc = self.chip8.get_pc();
+        if self.stopped_at.take() != Some(pc) && (self.breakpoints.contains(&pc) || self.run_to == Some(pc)) {
+          self.run_to = None;
+          self.stopped_at = Some(pc);
+          self.scheduler.set_paused(true);
+          // The rest of the frame, and so its place in any movie, waits until emulation resumes
+          self.partial_frame = Some(PartialFrame { keypad, executed: executed - 1, synced });
+          audio.set_playing(false);
+          return;
+        }
+        let opcode = self.chip8.get_current_opcode();
+        self.chip8.run();
+        // The sound timer can be set at any point in the frame, so place the change in the
+        // recording by how far through the frame's instructions it happened
+        let (buzzer_on, pattern) = (self.buzzer_on(), self.chip8.audio_pattern());
+        if let Some(wav) = &mut self.wav {
+          wav.set_playing(buzzer_on, pattern, executed as f32 / self.ipf as f32);
+        }
+        if opcode & 0xF0FF == 0xF007 {
+          synced = true;
+        } else if opcode == 0x00E0 || self.chip8.displayed_this_frame() {
+          synced = false;
+        }
+        if self.chip8.quirks().display_wait && self.chip8.displayed_this_frame() {
+          break;
+        }
+      }
+      self.chip8.decrement_timers();
+      // Cheats would make a movie play back differently from how it was recorded
+      if !self.is_movie_active() {
+        self.cheats.apply(&mut self.chip8);
+      }
+      self.flicker.end_frame(&self.display_pixels(), synced);
+      if let Some(palette) = self.video.as_ref().map(|video| video.palette.unwrap_or(self.palette)) {
+        let frame = self.frame(&palette);
+        self.video.as_mut().unwrap().push(frame);
+      }
+    }
+
+    // Only a whole frame's input goes into a movie
+    match &mut self.movie {
+      MovieState::Playing { movie, frame } => {
+        *frame += 1;
+        if *frame == movie.frames.len() {
+          self.movie = MovieState::Idle;
+        }
+      },
+      MovieState::Recording(movie) => movie.frames.push(keypad_to_bits(&keypad)),
+      MovieState::Idle => {},
+    }
+
+    let (buzzer_on, pattern) = (self.buzzer_on(), self.chip8.audio_pattern());
+    if let Some(wav) = &mut self.wav {
+      wav.end_frame();
+      wav.set_playing(buzzer_on, pattern, 0.0);
+    }
+    audio.set_playing(buzzer_on);
+  }
+
+  fn display_size(&self) -> (usize, usize) {
+    if self.chip8.hires_mode() { (128, 64) } else { (64, 32) }
+  }
+
+  /// The pixels in use at the current resolution
+  fn display_pixels(&self) -> Vec<u8> {
+    let (width, height) = self.display_size();
+    let mut pixels = self.chip8.get_display();
+    pixels.truncate(width * height);
+    pixels
+  }
+
+  /// The display as it's shown, through the flicker filter, in the given colors
+  pub fn frame(&self, palette: &Palette) -> Frame {
+    let (width, height) = self.display_size();
+    Frame { width, height, rgb: self.flicker.render(&self.display_pixels(), palette) }
+  }
+
+  /// Send the current display contents to a display sink
+  pub fn present(&self, display: &mut impl DisplaySink) {
+    let frame = self.frame(&self.palette);
+    display.present(frame.width, frame.height, &frame.rgb);
+  }
+}
@@ -0,0 +1,159 @@
+use std::fs;
+use std::path::Path;
+
+use crate::chip8::Variant;
+
+/// Identifies a SILK-8 movie file, followed by the format version
+const MAGIC: &[u8; 4] = b"S8M\x01";
+/// Size of everything before the per-frame input
+const HEADER_LEN: usize = 4 + 1 + 8 + 8 + 4 + 4;
+
+/// A recording of the keypad state on every frame since a ROM was started, along with
+/// everything else needed to replay it exactly.
+///
+/// The `.s8m` file layout, all little endian:
+/// - `S8M` and a version byte
+/// - variant as an index into `Variant::ALL` (1 byte)
+/// - RNG seed (8 bytes)
+/// - ROM hash, see `rom_hash` (8 bytes)
+/// - instructions per frame (4 bytes)
+/// - frame count (4 bytes)
+/// - one keypad bitmask per frame, bit N set if keypad index N is held (2 bytes each)
+#[derive(Clone, Debug, PartialEq)]
+pub struct Movie {
+  pub variant: Variant,
+  pub seed: u64,
+  pub rom_hash: u64,
+  pub ipf: u32,
+  pub frames: Vec<u16>,
+}
+
+impl Movie {
+  pub fn new(variant: Variant, seed: u64, rom_hash: u64, ipf: u32) -> Movie {
+    Self {
+      variant,
+      seed,
+      rom_hash,
+      ipf,
+      frames: Vec::new(),
+    }
+  }
+
+  pub fn load(path: &Path) -> Result<Movie, String> {
+    let bytes = fs::read(path).map_err(|e| format!("Failed to read movie: {}", e))?;
+    Movie::from_bytes(&bytes)
+  }
+
+  pub fn save(&self, path: &Path) -> Result<(), String> {
+    fs::write(path, self.to_bytes()).map_err(|e| format!("Failed to write movie: {}", e))
+  }
+
+  pub fn from_bytes(bytes: &[u8]) -> Result<Movie, String> {
+    if bytes.len() < HEADER_LEN || &bytes[0..4] != MAGIC {
+      return Err("Not a SILK-8 movie".to_string());
+    }
+
+    let variant = *Variant::ALL.get(bytes[4] as usize).ok_or("Unknown variant in movie")?;
+    let seed = u64::from_le_bytes(bytes[5..13].try_into().unwrap());
+    let rom_hash = u64::from_le_bytes(bytes[13..21].try_into().unwrap());
+    let ipf = u32::from_le_bytes(bytes[21..25].try_into().unwrap());
+    let frame_count = u32::from_le_bytes(bytes[25..29].try_into().unwrap()) as usize;
+
+    let input = &bytes[HEADER_LEN..];
+    // A corrupt frame count can't be trusted not to overflow on 32-bit targets like wasm
+    if frame_count.checked_mul(2) != Some(input.len()) {
+      return Err("Movie is truncated".to_string());
+    }
+    let frames = input
+      .chunks_exact(2)
+      .map(|frame| u16::from_le_bytes([frame[0], frame[1]]))
+      .collect();
+
+    Ok(Self {
+      variant,
+      seed,
+      rom_hash,
+      ipf,
+      frames,
+    })
+  }
+
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(HEADER_LEN + self.frames.len() * 2);
+    bytes.extend_from_slice(MAGIC);
+    bytes.push(Variant::ALL.iter().position(|v| *v == self.variant).unwrap() as u8);
+    bytes.extend_from_slice(&self.seed.to_le_bytes());
+    bytes.extend_from_slice(&self.rom_hash.to_le_bytes());
+    bytes.extend_from_slice(&self.ipf.to_le_bytes());
+    bytes.extend_from_slice(&(self.frames.len() as u32).to_le_bytes());
+    for frame in &self.frames {
+      bytes.extend_from_slice(&frame.to_le_bytes());
+    }
+    bytes
+  }
+}
+
+/// What the emulator is doing with movies
+pub enum MovieState {
+  Idle,
+  Recording(Movie),
+  Playing { movie: Movie, frame: usize },
+}
+
+/// Pack a keypad state into a bitmask, bit N set if keypad index N is held
+pub fn keypad_to_bits(keypad: &[bool; 16]) -> u16 {
+  keypad.iter().enumerate().fold(0, |bits, (index, pressed)| bits | ((*pressed as u16) << index))
+}
+
+pub fn keypad_from_bits(bits: u16) -> [bool; 16] {
+  std::array::from_fn(|index| bits & (1 << index) != 0)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn movie() -> Movie {
+    let mut movie = Movie::new(Variant::SCHIP_MODERN, 0x0123_4567_89AB_CDEF, 0xFEDC_BA98_7654_3210, 30);
+    movie.frames = vec![0, 1 << 5, 0xFFFF, 0x8001];
+    movie
+  }
+
+  #[test]
+  fn round_trips_through_a_file() {
+    let path = std::env::temp_dir().join(format!("silk8-movie-test-{}.s8m", std::process::id()));
+    movie().save(&path).unwrap();
+    let loaded = Movie::load(&path);
+    fs::remove_file(&path).unwrap();
+    assert_eq!(loaded, Ok(movie()));
+    assert_eq!(movie().to_bytes().len(), HEADER_LEN + 4 * 2);
+  }
+
+  #[test]
+  fn rejects_broken_headers() {
+    let bytes = movie().to_bytes();
+    let with = |offset: usize, patch: &[u8]| {
+      let mut bytes = bytes.clone();
+      bytes[offset..offset + patch.len()].copy_from_slice(patch);
+      Movie::from_bytes(&bytes)
+    };
+
+    assert_eq!(Movie::from_bytes(&bytes[..HEADER_LEN - 1]), Err("Not a SILK-8 movie".to_string()));
+    assert_eq!(with(0, b"S8N"), Err("Not a SILK-8 movie".to_string()));
+    assert_eq!(with(3, &[2]), Err("Not a SILK-8 movie".to_string()));
+    assert_eq!(with(4, &[Variant::ALL.len() as u8]), Err("Unknown variant in movie".to_string()));
+    assert_eq!(Movie::from_bytes(&bytes[..bytes.len() - 1]), Err("Movie is truncated".to_string()));
+    assert_eq!(with(25, &3_u32.to_le_bytes()), Err("Movie is truncated".to_string()));
+    // A count whose byte length overflows on 32-bit targets
+    assert_eq!(with(25, &u32::MAX.to_le_bytes()), Err("Movie is truncated".to_string()));
+  }
+
+  #[test]
+  fn keypad_bits_follow_cosmac_order() {
+    let mut keypad = [false; 16];
+    keypad[0] = true;
+    keypad[13] = true;
+    assert_eq!(keypad_to_bits(&keypad), 0b0010_0000_0000_0001);
+    assert_eq!(keypad_from_bits(0b0010_0000_0000_0001), keypad);
+  }
+}
@@ -0,0 +1,76 @@
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+/// Colors to draw the display with, indexed by pixel value. CHIP-8 and SCHIP only use the
+/// background and foreground, the other two are for XO-CHIP's second plane and both planes.
+pub struct Palette {
+  pub colors: [[u8; 3]; 4],
+}
+
+const fn rgb(hex: u32) -> [u8; 3] {
+  [(hex >> 16) as u8, (hex >> 8) as u8, hex as u8]
+}
+
+impl Palette {
+  pub const CLASSIC: Palette = Palette { colors: [rgb(0x000000), rgb(0xFFFFFF), rgb(0xAAAAAA), rgb(0x555555)] };
+
+  /// Built in palettes by name, the XO-CHIP ones matching Octo's
+  pub const PRESETS: [(&'static str, Palette); 10] = [
+    ("Classic", Palette::CLASSIC),
+    ("Amber", Palette { colors: [rgb(0x1A0F00), rgb(0xFFB000), rgb(0xCC8400), rgb(0x664200)] }),
+    ("Green Phosphor", Palette { colors: [rgb(0x001100), rgb(0x33FF33), rgb(0x22AA22), rgb(0x115511)] }),
+    ("LCD Grey", Palette { colors: [rgb(0xC0C8B8), rgb(0x303830), rgb(0x808878), rgb(0x586058)] }),
+    ("Octo", Palette { colors: [rgb(0x996600), rgb(0xFFCC00), rgb(0xFF6600), rgb(0x662200)] }),
+    ("Octo LCD", Palette { colors: [rgb(0xF9FFB3), rgb(0x3D8026), rgb(0xABCC47), rgb(0x00131A)] }),
+    ("Hotdog", Palette { colors: [rgb(0x000000), rgb(0xFF0000), rgb(0xFFFF00), rgb(0xFFFFFF)] }),
+    ("CGA 0", Palette { colors: [rgb(0x000000), rgb(0x00FF00), rgb(0xFF0000), rgb(0xFFFF00)] }),
+    ("CGA 1", Palette { colors: [rgb(0x000000), rgb(0xFF00FF), rgb(0x00FFFF), rgb(0xFFFFFF)] }),
+    ("Silicon-8", Palette { colors: [rgb(0x000000), rgb(0xFFFFFF), rgb(0xFF0000), rgb(0x0000FF)] }),
+  ];
+
+  /// The color of a display pixel
+  pub fn color(&self, pixel: u8) -> [u8; 3] {
+    self.colors[pixel as usize & 3]
+  }
+
+  /// The name of the preset this palette matches, if any
+  pub fn preset_name(&self) -> Option<&'static str> {
+    Palette::PRESETS.iter().find(|(_, palette)| palette == self).map(|(name, _)| *name)
+  }
+
+  /// Parse a preset name, or up to four `#RRGGBB` colors separated by commas as written by
+  /// `to_string`, with any colors left out taken from the classic palette
+  pub fn parse(text: &str) -> Option<Palette> {
+    let text = text.trim();
+    if let Some((_, palette)) = Palette::PRESETS.iter().find(|(name, _)| name.eq_ignore_ascii_case(text)) {
+      return Some(*palette);
+    }
+
+    let mut palette = Palette::CLASSIC;
+    let colors = text.split(',').map(str::trim).collect::<Vec<_>>();
+    if colors.len() > 4 {
+      return None;
+    }
+    for (index, color) in colors.iter().enumerate() {
+      let hex = color.strip_prefix('#').filter(|hex| hex.len() == 6)?;
+      palette.colors[index] = rgb(u32::from_str_radix(hex, 16).ok()?);
+    }
+    Some(palette)
+  }
+}
+
+impl Default for Palette {
+  fn default() -> Palette {
+    Palette::CLASSIC
+  }
+}
+
+impl fmt::Display for Palette {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let colors = self.colors
+      .iter()
+      .map(|[r, g, b]| format!("#{:02X}{:02X}{:02X}", r, g, b))
+      .collect::<Vec<_>>();
+    write!(f, "{}", colors.join(","))
+  }
+}
@@ -0,0 +1,76 @@
+use std::sync::{
+  atomic::{AtomicBool, Ordering},
+  Arc, Mutex
+};
+use std::time::Duration;
+
+use rodio::cpal::traits::HostTrait;
+use rodio::{cpal, DeviceTrait, OutputStream, Sink, Source};
+
+use super::AudioSink;
+use crate::square_wave::{SquareWave, Tone};
+
+/// How often the playing wave checks for a new tone
+const TONE_CHECK_PERIOD: Duration = Duration::from_millis(5);
+
+/// Plays the buzzer as a square wave through the default output device
+pub struct RodioAudio {
+  // Audio stops as soon as the stream is dropped, so it has to live as long as the sink
+  _stream: OutputStream,
+  sink: Sink,
+  gate: Arc<AtomicBool>,
+  tone: Tone,
+  /// A tone waiting to be picked up by the playing wave
+  pending_tone: Arc<Mutex<Option<Tone>>>,
+}
+
+impl RodioAudio {
+  pub fn new(tone: Tone) -> RodioAudio {
+    let device = cpal::default_host().default_output_device().expect("No audio output device");
+    // Generate samples at the device's own rate so they don't have to be resampled
+    let config = device.default_output_config().unwrap();
+    let sample_rate = config.sample_rate().0;
+    let (stream, stream_handle) = OutputStream::try_from_device_config(&device, config).unwrap();
+    let sink = Sink::try_new(&stream_handle).unwrap();
+
+    // The wave plays constantly and is faded in and out through its gate, since pausing
+    // the sink cuts it off mid-wave with a click
+    let source = SquareWave::new(tone, sample_rate);
+    let gate = source.gate();
+    // Likewise, a new tone is handed to the same wave rather than starting another
+    let pending_tone = Arc::new(Mutex::new(None));
+    let pending = pending_tone.clone();
+    sink.append(source.periodic_access(TONE_CHECK_PERIOD, move |wave| {
+      if let Some(tone) = pending.lock().unwrap().take() {
+        wave.set_tone(tone);
+      }
+    }));
+
+    Self {
+      _stream: stream,
+      sink,
+      gate,
+      tone,
+      pending_tone,
+    }
+  }
+
+  /// Scale the buzzer's loudness, from 0 for silent to 1 for its full volume
+  pub fn set_volume(&self, volume: f32) {
+    self.sink.set_volume(volume);
+  }
+
+  /// Change how the buzzer sounds, even while it's playing
+  pub fn set_tone(&mut self, tone: Tone) {
+    if tone != self.tone {
+      self.tone = tone;
+      *self.pending_tone.lock().unwrap() = Some(tone);
+    }
+  }
+}
+
+impl AudioSink for RodioAudio {
+  fn set_playing(&mut self, playing: bool) {
+    self.gate.store(playing, Ordering::Relaxed);
+  }
+}
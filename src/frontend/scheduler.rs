@@ -0,0 +1,146 @@
+use web_time::Instant;
+
+/// Timer ticks per second on every CHIP-8 variant
+pub const FRAMES_PER_SECOND: u128 = 60;
+/// The most frames to run in a single update at normal speed before dropping the rest to catch up
+const MAX_CATCH_UP_FRAMES: u32 = 5;
+const NANOS_PER_SECOND: u128 = 1_000_000_000;
+
+/// Paces emulation at a fixed 60 frames per second regardless of how often the host repaints
+pub struct FrameScheduler {
+  last_update: Option<Instant>,
+  // Elapsed time not yet spent on frames, in nanoseconds scaled by FRAMES_PER_SECOND so that
+  // one frame costs exactly NANOS_PER_SECOND and no rounding error builds up over time
+  budget: u128,
+  speed: f64,
+  paused: bool,
+  step_requested: bool,
+}
+
+impl FrameScheduler {
+  pub fn new() -> FrameScheduler {
+    Self {
+      last_update: None,
+      budget: 0,
+      speed: 1.0,
+      paused: false,
+      step_requested: false,
+    }
+  }
+
+  /// Work out how many frames should run now to keep pace with real time.
+  /// If the host stalled for longer than a few frames, the excess is dropped instead of
+  /// running the emulator in a burst to catch up.
+  pub fn frames_due(&mut self, now: Instant) -> u32 {
+    if self.paused {
+      self.reset();
+      return if std::mem::take(&mut self.step_requested) { 1 } else { 0 };
+    }
+
+    if let Some(last_update) = self.last_update {
+      let elapsed = now.saturating_duration_since(last_update).as_nanos() as f64 * self.speed;
+      self.budget += elapsed as u128 * FRAMES_PER_SECOND;
+    }
+    self.last_update = Some(now);
+
+    let due = self.budget / NANOS_PER_SECOND;
+    self.budget %= NANOS_PER_SECOND;
+
+    // Running faster than real time needs more frames per update just to keep up
+    let max_frames = (MAX_CATCH_UP_FRAMES as f64 * self.speed.max(1.0)).ceil() as u128;
+    due.min(max_frames) as u32
+  }
+
+  /// Forget elapsed time, e.g. after the emulator was paused, so it isn't caught up on
+  pub fn reset(&mut self) {
+    self.last_update = None;
+    self.budget = 0;
+  }
+
+  /// Scale how fast emulated time passes, 1.0 being real time
+  pub fn set_speed(&mut self, speed: f64) {
+    self.speed = speed;
+  }
+
+  pub fn is_paused(&self) -> bool {
+    self.paused
+  }
+
+  pub fn set_paused(&mut self, paused: bool) {
+    self.paused = paused;
+    self.step_requested = false;
+  }
+
+  /// Run exactly one frame on the next update while paused
+  pub fn step(&mut self) {
+    if self.paused {
+      self.step_requested = true;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+
+  /// The frames due from updates every `step` for `total`, after a first update at `start`
+  fn run_for(scheduler: &mut FrameScheduler, start: Instant, step: Duration, total: Duration) -> u32 {
+    let mut frames = 0;
+    let mut elapsed = Duration::ZERO;
+    while elapsed < total {
+      elapsed += step;
+      frames += scheduler.frames_due(start + elapsed);
+    }
+    frames
+  }
+
+  #[test]
+  fn keeps_pace_without_drifting() {
+    let mut scheduler = FrameScheduler::new();
+    let start = Instant::now();
+    assert_eq!(scheduler.frames_due(start), 0);
+    // 7 ms doesn't divide a frame evenly, but the leftovers add up
+    assert_eq!(run_for(&mut scheduler, start, Duration::from_millis(7), Duration::from_millis(7 * 1000)), 7 * 60);
+  }
+
+  #[test]
+  fn speed_scales_the_frame_rate() {
+    let mut scheduler = FrameScheduler::new();
+    let start = Instant::now();
+    scheduler.frames_due(start);
+    scheduler.set_speed(0.5);
+    assert_eq!(run_for(&mut scheduler, start, Duration::from_millis(10), Duration::from_secs(2)), 60);
+  }
+
+  #[test]
+  fn stalls_are_dropped_past_the_catch_up_cap() {
+    let mut scheduler = FrameScheduler::new();
+    let start = Instant::now();
+    scheduler.frames_due(start);
+    assert_eq!(scheduler.frames_due(start + Duration::from_secs(1)), MAX_CATCH_UP_FRAMES);
+    // The rest of the second is gone rather than owed
+    assert_eq!(scheduler.frames_due(start + Duration::from_secs(1) + Duration::from_micros(16_700)), 1);
+
+    // Turbo needs more frames per update just to keep up, so it gets a higher cap
+    scheduler.set_speed(3.0);
+    assert_eq!(scheduler.frames_due(start + Duration::from_secs(2)), MAX_CATCH_UP_FRAMES * 3);
+  }
+
+  #[test]
+  fn paused_time_is_not_caught_up() {
+    let mut scheduler = FrameScheduler::new();
+    let start = Instant::now();
+    scheduler.frames_due(start);
+    scheduler.set_paused(true);
+    assert_eq!(scheduler.frames_due(start + Duration::from_secs(1)), 0);
+
+    scheduler.step();
+    assert_eq!(scheduler.frames_due(start + Duration::from_secs(2)), 1);
+    assert_eq!(scheduler.frames_due(start + Duration::from_secs(3)), 0);
+
+    scheduler.set_paused(false);
+    assert_eq!(scheduler.frames_due(start + Duration::from_secs(4)), 0);
+    assert_eq!(scheduler.frames_due(start + Duration::from_secs(4) + Duration::from_millis(50)), 3);
+  }
+}
@@ -0,0 +1,240 @@
+use std::fmt;
+
+use gilrs::{Axis, Button, Gamepad, Gilrs};
+
+use crate::chip8::STANDARD_LAYOUT;
+use crate::frontend::InputSource;
+
+/// How far a stick has to be pushed before it counts as a press
+const STICK_THRESHOLD: f32 = 0.5;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+/// A gamepad button, or a stick pushed in one direction, that can be mapped to a keypad key
+pub enum GamepadControl {
+  DPadUp,
+  DPadDown,
+  DPadLeft,
+  DPadRight,
+  LeftStickUp,
+  LeftStickDown,
+  LeftStickLeft,
+  LeftStickRight,
+  South,
+  East,
+  West,
+  North,
+  LeftBumper,
+  RightBumper,
+  LeftTrigger,
+  RightTrigger,
+  Select,
+  Start,
+}
+
+impl GamepadControl {
+  /// Every control, in a stable order that is safe to store by index
+  pub const ALL: [GamepadControl; 18] = [
+    GamepadControl::DPadUp,
+    GamepadControl::DPadDown,
+    GamepadControl::DPadLeft,
+    GamepadControl::DPadRight,
+    GamepadControl::LeftStickUp,
+    GamepadControl::LeftStickDown,
+    GamepadControl::LeftStickLeft,
+    GamepadControl::LeftStickRight,
+    GamepadControl::South,
+    GamepadControl::East,
+    GamepadControl::West,
+    GamepadControl::North,
+    GamepadControl::LeftBumper,
+    GamepadControl::RightBumper,
+    GamepadControl::LeftTrigger,
+    GamepadControl::RightTrigger,
+    GamepadControl::Select,
+    GamepadControl::Start,
+  ];
+
+  /// The control's name as written in the settings
+  pub fn name(&self) -> &'static str {
+    match self {
+      GamepadControl::DPadUp => "dpad-up",
+      GamepadControl::DPadDown => "dpad-down",
+      GamepadControl::DPadLeft => "dpad-left",
+      GamepadControl::DPadRight => "dpad-right",
+      GamepadControl::LeftStickUp => "stick-up",
+      GamepadControl::LeftStickDown => "stick-down",
+      GamepadControl::LeftStickLeft => "stick-left",
+      GamepadControl::LeftStickRight => "stick-right",
+      GamepadControl::South => "south",
+      GamepadControl::East => "east",
+      GamepadControl::West => "west",
+      GamepadControl::North => "north",
+      GamepadControl::LeftBumper => "left-bumper",
+      GamepadControl::RightBumper => "right-bumper",
+      GamepadControl::LeftTrigger => "left-trigger",
+      GamepadControl::RightTrigger => "right-trigger",
+      GamepadControl::Select => "select",
+      GamepadControl::Start => "start",
+    }
+  }
+
+  pub fn from_name(name: &str) -> Option<GamepadControl> {
+    GamepadControl::ALL.into_iter().find(|control| control.name().eq_ignore_ascii_case(name))
+  }
+
+  /// The control's name as shown in menus, with the face buttons named for an Xbox pad
+  pub fn label(&self) -> &'static str {
+    match self {
+      GamepadControl::DPadUp => "D-pad Up",
+      GamepadControl::DPadDown => "D-pad Down",
+      GamepadControl::DPadLeft => "D-pad Left",
+      GamepadControl::DPadRight => "D-pad Right",
+      GamepadControl::LeftStickUp => "Stick Up",
+      GamepadControl::LeftStickDown => "Stick Down",
+      GamepadControl::LeftStickLeft => "Stick Left",
+      GamepadControl::LeftStickRight => "Stick Right",
+      GamepadControl::South => "A",
+      GamepadControl::East => "B",
+      GamepadControl::West => "X",
+      GamepadControl::North => "Y",
+      GamepadControl::LeftBumper => "LB",
+      GamepadControl::RightBumper => "RB",
+      GamepadControl::LeftTrigger => "LT",
+      GamepadControl::RightTrigger => "RT",
+      GamepadControl::Select => "Back",
+      GamepadControl::Start => "Start",
+    }
+  }
+
+  fn is_pressed(&self, gamepad: &Gamepad) -> bool {
+    match self {
+      GamepadControl::DPadUp => gamepad.is_pressed(Button::DPadUp),
+      GamepadControl::DPadDown => gamepad.is_pressed(Button::DPadDown),
+      GamepadControl::DPadLeft => gamepad.is_pressed(Button::DPadLeft),
+      GamepadControl::DPadRight => gamepad.is_pressed(Button::DPadRight),
+      // Stick Y is positive upwards
+      GamepadControl::LeftStickUp => gamepad.value(Axis::LeftStickY) > STICK_THRESHOLD,
+      GamepadControl::LeftStickDown => gamepad.value(Axis::LeftStickY) < -STICK_THRESHOLD,
+      GamepadControl::LeftStickLeft => gamepad.value(Axis::LeftStickX) < -STICK_THRESHOLD,
+      GamepadControl::LeftStickRight => gamepad.value(Axis::LeftStickX) > STICK_THRESHOLD,
+      GamepadControl::South => gamepad.is_pressed(Button::South),
+      GamepadControl::East => gamepad.is_pressed(Button::East),
+      GamepadControl::West => gamepad.is_pressed(Button::West),
+      GamepadControl::North => gamepad.is_pressed(Button::North),
+      GamepadControl::LeftBumper => gamepad.is_pressed(Button::LeftTrigger),
+      GamepadControl::RightBumper => gamepad.is_pressed(Button::RightTrigger),
+      GamepadControl::LeftTrigger => gamepad.is_pressed(Button::LeftTrigger2),
+      GamepadControl::RightTrigger => gamepad.is_pressed(Button::RightTrigger2),
+      GamepadControl::Select => gamepad.is_pressed(Button::Select),
+      GamepadControl::Start => gamepad.is_pressed(Button::Start),
+    }
+  }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+/// Which keypad key each gamepad control presses, if any
+pub struct GamepadMapping {
+  keys: [Option<u8>; GamepadControl::ALL.len()],
+}
+
+impl Default for GamepadMapping {
+  /// Directions on 5/7/9/8 and the main action on 6, which is what most CHIP-8 games use
+  fn default() -> GamepadMapping {
+    let mut mapping = GamepadMapping { keys: [None; GamepadControl::ALL.len()] };
+    for (control, key) in [
+      (GamepadControl::DPadUp, 0x5),
+      (GamepadControl::DPadDown, 0x8),
+      (GamepadControl::DPadLeft, 0x7),
+      (GamepadControl::DPadRight, 0x9),
+      (GamepadControl::LeftStickUp, 0x5),
+      (GamepadControl::LeftStickDown, 0x8),
+      (GamepadControl::LeftStickLeft, 0x7),
+      (GamepadControl::LeftStickRight, 0x9),
+      (GamepadControl::South, 0x6),
+      (GamepadControl::East, 0x4),
+      (GamepadControl::West, 0x1),
+      (GamepadControl::North, 0x2),
+      (GamepadControl::Select, 0x0),
+      (GamepadControl::Start, 0xF),
+    ] {
+      mapping.set_key(control, Some(key));
+    }
+    mapping
+  }
+}
+
+impl GamepadMapping {
+  pub fn key(&self, control: GamepadControl) -> Option<u8> {
+    self.keys[control as usize]
+  }
+
+  pub fn set_key(&mut self, control: GamepadControl, key: Option<u8>) {
+    self.keys[control as usize] = key.map(|key| key & 0xF);
+  }
+
+  /// Parse `control=key` pairs separated by commas, e.g. `dpad-up=5,south=6`.
+  /// Controls that aren't listed press nothing.
+  pub fn parse(text: &str) -> Option<GamepadMapping> {
+    let mut mapping = GamepadMapping { keys: [None; GamepadControl::ALL.len()] };
+    for pair in text.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+      let (name, key) = pair.split_once('=')?;
+      let control = GamepadControl::from_name(name.trim())?;
+      let key = u8::from_str_radix(key.trim(), 16).ok().filter(|key| *key < 16)?;
+      mapping.set_key(control, Some(key));
+    }
+    Some(mapping)
+  }
+}
+
+impl fmt::Display for GamepadMapping {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let pairs = GamepadControl::ALL
+      .iter()
+      .filter_map(|control| self.key(*control).map(|key| format!("{}={:X}", control.name(), key)))
+      .collect::<Vec<_>>();
+    write!(f, "{}", pairs.join(","))
+  }
+}
+
+/// Reads the keypad from every connected gamepad, which can be plugged in and out while running
+pub struct GilrsGamepad {
+  /// None if gamepads aren't supported here, in which case no keys are ever held
+  gilrs: Option<Gilrs>,
+  mapping: GamepadMapping,
+}
+
+impl GilrsGamepad {
+  pub fn new() -> GilrsGamepad {
+    let gilrs = Gilrs::new().map_err(|err| println!("Gamepads are unavailable: {}", err)).ok();
+    Self {
+      gilrs,
+      mapping: GamepadMapping::default(),
+    }
+  }
+
+  pub fn set_mapping(&mut self, mapping: GamepadMapping) {
+    self.mapping = mapping;
+  }
+}
+
+impl InputSource for GilrsGamepad {
+  fn keypad_state(&mut self) -> [bool; 16] {
+    let mut state = [false; 16];
+    let gilrs = match &mut self.gilrs {
+      Some(gilrs) => gilrs,
+      None => return state,
+    };
+    // Gamepad state, including which gamepads are connected, only updates as events are read
+    while gilrs.next_event().is_some() {}
+    for (_, gamepad) in gilrs.gamepads() {
+      for control in GamepadControl::ALL {
+        if let Some(key) = self.mapping.key(control) {
+          // The mapping is by key value, but the state is in COSMAC layout order
+          let index = STANDARD_LAYOUT.iter().position(|value| *value == key).unwrap();
+          state[index] |= control.is_pressed(&gamepad);
+        }
+      }
+    }
+    state
+  }
+}
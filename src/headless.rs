@@ -0,0 +1,30 @@
+use crate::frontend::{AudioSink, DisplaySink};
+
+/// Keeps the last presented frame in memory instead of showing it
+#[derive(Clone, Debug, Default)]
+pub struct HeadlessDisplay {
+  pub width: usize,
+  pub height: usize,
+  pub rgb: Vec<u8>,
+}
+
+impl DisplaySink for HeadlessDisplay {
+  fn present(&mut self, width: usize, height: usize, rgb: &[u8]) {
+    self.width = width;
+    self.height = height;
+    self.rgb.clear();
+    self.rgb.extend_from_slice(rgb);
+  }
+}
+
+/// Discards all audio, only tracking whether the buzzer is on
+#[derive(Clone, Debug, Default)]
+pub struct NullAudio {
+  pub playing: bool,
+}
+
+impl AudioSink for NullAudio {
+  fn set_playing(&mut self, playing: bool) {
+    self.playing = playing;
+  }
+}
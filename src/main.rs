@@ -1,172 +1,937 @@
+mod cfg;
 mod chip8;
+mod coverage;
+mod disassembler;
+mod frontend;
+mod gilrs_backend;
+mod headless;
+mod menu;
+mod netplay;
+mod octo;
+mod settings;
 mod square_wave;
+mod wav_recorder;
+mod windows;
 
-use std::collections::HashMap;
+use std::path::Path;
+use std::time::Duration;
 
 use eframe::egui;
 use egui::Key;
-use muda::{accelerator::{Accelerator, Code, Modifiers}, Menu, MenuEvent, MenuId, MenuItem, PredefinedMenuItem, Submenu};
+use muda::MenuEvent;
 use rfd::FileDialog;
-use rodio::{source::Source, OutputStream, Sink};
-use winit::raw_window_handle::{HasWindowHandle, RawWindowHandle};
+use web_time::Instant;
 
-use chip8::{Chip8, Variant};
-use square_wave::SquareWave;
+use chip8::{Chip8, Quirks, Variant};
+use frontend::egui_backend::{draw_drop_overlay, draw_message_overlay, draw_program_ended_overlay, EguiDisplay, EguiKeyboard};
+use frontend::rodio_backend::RodioAudio;
+use frontend::capture::{encode_png, VideoFormat, VideoRecorder};
+use frontend::flicker::FlickerMode;
+use frontend::movie::Movie;
+use frontend::palette::Palette;
+use frontend::{default_ipf, Emulator};
+use gilrs_backend::GilrsGamepad;
+use headless::NullAudio;
+use menu::{menu_model, show_menu_bar, MenuEntry, NativeMenu};
+use netplay::NetplaySession;
+use settings::{palette_name, Settings};
+use windows::cheats::{load_cheats, CheatsWindow};
+use windows::coverage::CoverageWindow;
+use windows::disassembly::DisassemblyWindow;
+use windows::keypad::KeypadPanel;
+use windows::memory::MemoryWindow;
+use windows::netplay::NetplayWindow;
+use windows::palette::PaletteWindow;
+use windows::profiler::ProfilerWindow;
+use windows::settings::SettingsWindow;
+
+/// Whether the menu is attached to the window natively, rather than drawn in it by egui
+const NATIVE_MENUBAR: bool = cfg!(any(target_os = "windows", target_os = "macos"));
+
+/// Height of the menu bar when egui draws it
+const MENU_BAR_HEIGHT: f32 = 24.0;
+
+/// File extensions of the ROMs that can be loaded, including Octo source which is assembled on load
+const ROM_EXTENSIONS: [&str; 4] = ["ch8", "sc8", "xo8", "8o"];
+/// How long messages like load errors stay on screen
+const MESSAGE_DURATION: Duration = Duration::from_secs(5);
 
 fn main() -> Result<(), eframe::Error> {
-    // Set window options, main important one here is min_inner_size so our window accounts for menubar insertion
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let command = match args.first().map(String::as_str) {
+        Some("cfg") => Some(run_cfg_command as fn(&[String]) -> Result<(), String>),
+        Some("capture") => Some(run_capture_command as fn(&[String]) -> Result<(), String>),
+        _ => None,
+    };
+    if let Some(command) = command {
+        if let Err(err) = command(&args[1..]) {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    let launch = match LaunchOptions::parse(&args) {
+        Ok(launch) => launch,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+
+    // Set window options, main important one here is min_inner_size so our window accounts for menubar insertion.
+    // Where egui draws the menu bar it takes up part of the window, so there has to be room for it as well
+    let scale = launch.scale.unwrap_or(10) as f32;
+    let menu_height = if NATIVE_MENUBAR { 0.0 } else { MENU_BAR_HEIGHT };
+    let size = [64.0 * scale, 32.0 * scale + menu_height];
     let options = eframe::NativeOptions {
-        viewport: egui::ViewportBuilder::default().with_inner_size([640.0, 320.0]).with_min_inner_size([640.0, 320.0]),
+        viewport: egui::ViewportBuilder::default()
+            .with_inner_size(size)
+            .with_min_inner_size(size)
+            .with_fullscreen(launch.fullscreen),
         ..Default::default()
     };
 
-    let mut chip8 = Chip8::new(Variant::XOCHIP);
-    chip8.load_font();
-
-    // Set up rodio
-    let (_stream, stream_handle) = OutputStream::try_default().unwrap();
-    let sink = Sink::try_new(&stream_handle).unwrap();
-    let source = SquareWave::new(440.0).amplify(0.10);
-    sink.append(source);
-    sink.pause();
-
-    let silk8 = SILK8 {
-        show_about_window: false,
-        menubar: None,
-        menubar_items: HashMap::new(),
-        menubar_interaction: "".to_string(),
-        chip8,
-        variant: Variant::XOCHIP,
-        rom_loaded: false,
-        sink,
-    };
     eframe::run_native(
         "SILK-8",
         options,
-        Box::new(|_cc| Box::<SILK8>::new(silk8)),
+        Box::new(|cc| Box::<SILK8>::new(SILK8::new(&cc.egui_ctx, launch))),
     )
 }
 
+fn parse_variant(name: &str) -> Result<Variant, String> {
+    Variant::from_name(name).ok_or_else(|| {
+        let names = Variant::ALL.iter().map(|variant| variant.name()).collect::<Vec<_>>();
+        format!("Unknown variant '{}', expected one of: {}", name, names.join(", "))
+    })
+}
+
+fn parse_palette(value: &str) -> Result<Palette, String> {
+    Palette::parse(value).ok_or_else(|| {
+        let names = Palette::PRESETS.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+        format!("Unknown palette '{}', expected #RRGGBB colors or one of: {}", value, names.join(", "))
+    })
+}
+
+/// Switches one of the interpreter's behaviours on or off
+type QuirkSetter = fn(&mut Chip8, bool);
+
+/// Interpreter behaviours that can be switched with `--quirk <name>=<on|off>`, starting from the variant's own
+const QUIRKS: [(&str, QuirkSetter); 8] = [
+    ("vf-reset", |chip8, on| chip8.set_quirks(Quirks { vf_reset: on, ..chip8.quirks() })),
+    ("memory", |chip8, on| chip8.set_quirks(Quirks { memory: on, ..chip8.quirks() })),
+    ("shifting", |chip8, on| chip8.set_quirks(Quirks { shifting: on, ..chip8.quirks() })),
+    ("jumping", |chip8, on| chip8.set_quirks(Quirks { jumping: on, ..chip8.quirks() })),
+    ("clipping", |chip8, on| chip8.set_quirks(Quirks { clipping: on, ..chip8.quirks() })),
+    ("display-wait", |chip8, on| chip8.set_quirks(Quirks { display_wait: on, ..chip8.quirks() })),
+    ("halt-on-unknown-opcode", Chip8::set_halt_on_unknown_opcode),
+    ("halt-on-stack-underflow", Chip8::set_halt_on_stack_underflow),
+];
+
+/// How the window was asked to start from the command line:
+/// `silk8 [rom] [--variant <name>] [--ipf <n>] [--scale <n>] [--palette <name>] [--fullscreen] [--mute] [--seed <n>] [--quirk <name>=<on|off>]`
+#[derive(Default)]
+struct LaunchOptions {
+    rom: Option<Vec<u8>>,
+    variant: Option<Variant>,
+    ipf: Option<u32>,
+    /// Window size as a multiple of the 64x32 display
+    scale: Option<usize>,
+    palette: Option<Palette>,
+    fullscreen: bool,
+    mute: bool,
+    seed: Option<u64>,
+    quirks: Vec<(QuirkSetter, bool)>,
+}
+
+impl LaunchOptions {
+    const USAGE: &'static str = "Usage: silk8 [rom] [--variant <name>] [--ipf <n>] [--scale <n>] [--palette <name>] [--fullscreen] [--mute] [--seed <n>] [--quirk <name>=<on|off>]
+       silk8 cfg <rom> [--variant <name>] [--output <file>]
+       silk8 capture <rom> --output <file> [options]
+
+Quirks: vf-reset, memory, shifting, jumping, clipping, display-wait, halt-on-unknown-opcode, halt-on-stack-underflow";
+
+    fn parse(args: &[String]) -> Result<LaunchOptions, String> {
+        let mut launch = LaunchOptions::default();
+        let mut rom = None;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--help" | "-h" => {
+                    println!("{}", LaunchOptions::USAGE);
+                    std::process::exit(0);
+                },
+                "--variant" => {
+                    launch.variant = Some(parse_variant(args.next().ok_or("--variant needs a variant name")?)?);
+                },
+                "--ipf" => {
+                    let value = args.next().ok_or("--ipf needs a number of instructions")?;
+                    launch.ipf = Some(value.parse().ok().filter(|ipf| *ipf > 0).ok_or_else(|| format!("Invalid instructions per frame '{}'", value))?);
+                },
+                "--scale" => {
+                    let value = args.next().ok_or("--scale needs a number")?;
+                    launch.scale = Some(value.parse().ok().filter(|scale| *scale > 0).ok_or_else(|| format!("Invalid scale '{}'", value))?);
+                },
+                "--palette" => {
+                    launch.palette = Some(parse_palette(args.next().ok_or("--palette needs a palette name or colors")?)?);
+                },
+                "--fullscreen" => launch.fullscreen = true,
+                "--mute" => launch.mute = true,
+                "--seed" => {
+                    let value = args.next().ok_or("--seed needs a number")?;
+                    launch.seed = Some(value.parse().map_err(|_| format!("Invalid seed '{}'", value))?);
+                },
+                "--quirk" => {
+                    let value = args.next().ok_or("--quirk needs a <name>=<on|off> setting")?;
+                    launch.quirks.push(parse_quirk(value)?);
+                },
+                _ if arg.starts_with('-') => {
+                    return Err(format!("Unknown option '{}'\n{}", arg, LaunchOptions::USAGE));
+                },
+                _ if rom.is_none() => rom = Some(arg),
+                _ => return Err(format!("Unexpected argument '{}'\n{}", arg, LaunchOptions::USAGE)),
+            }
+        }
+
+        if let Some(rom) = rom {
+            launch.rom = Some(read_rom(rom)?);
+        }
+        Ok(launch)
+    }
+}
+
+/// The ROM in a file, assembling it first if it's Octo source
+fn rom_from_file(path: &Path, bytes: Vec<u8>) -> Result<Vec<u8>, String> {
+    if !path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("8o")) {
+        return Ok(bytes);
+    }
+    String::from_utf8(bytes)
+        .map_err(|_| "it isn't UTF-8 text".to_string())
+        .and_then(|source| octo::assemble(&source))
+        .map_err(|err| format!("Failed to assemble {}: {}", path.display(), err))
+}
+
+/// Read a ROM named on the command line
+fn read_rom(path: &str) -> Result<Vec<u8>, String> {
+    let bytes = std::fs::read(path).map_err(|err| format!("Failed to read {}: {}", path, err))?;
+    rom_from_file(Path::new(path), bytes)
+}
+
+/// Parse a `<name>=<on|off>` quirk setting into the setter for that quirk and its value
+fn parse_quirk(setting: &str) -> Result<(QuirkSetter, bool), String> {
+    let (name, value) = setting.split_once('=').ok_or_else(|| format!("Invalid quirk '{}', expected <name>=<on|off>", setting))?;
+    let (name, set_quirk) = QUIRKS.into_iter().find(|(quirk, _)| quirk.eq_ignore_ascii_case(name)).ok_or_else(|| {
+        let names = QUIRKS.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+        format!("Unknown quirk '{}', expected one of: {}", name, names.join(", "))
+    })?;
+    let enabled = match value.to_ascii_lowercase().as_str() {
+        "on" | "true" | "1" => true,
+        "off" | "false" | "0" => false,
+        _ => return Err(format!("Invalid value '{}' for quirk {}, expected on or off", value, name)),
+    };
+    Ok((set_quirk, enabled))
+}
+
+/// `silk8 cfg <rom> [--variant <name>] [--output <file>]`: write the ROM's control-flow graph
+/// as Graphviz DOT, to stdout unless an output file is given
+fn run_cfg_command(args: &[String]) -> Result<(), String> {
+    let mut rom = None;
+    let mut variant = Variant::XOCHIP;
+    let mut output = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--variant" => {
+                variant = parse_variant(args.next().ok_or("--variant needs a variant name")?)?;
+            },
+            "--output" | "-o" => {
+                output = Some(args.next().ok_or("--output needs a file name")?);
+            },
+            _ if rom.is_none() => rom = Some(arg),
+            _ => return Err(format!("Unexpected argument '{}'", arg)),
+        }
+    }
+
+    let rom = rom.ok_or("Usage: silk8 cfg <rom> [--variant <name>] [--output <file>]")?;
+    let bytes = read_rom(rom)?;
+    let dot = cfg::control_flow_graph(&bytes, variant).to_dot();
+    match output {
+        Some(path) => std::fs::write(path, dot).map_err(|err| format!("Failed to write {}: {}", path, err)),
+        None => {
+            print!("{}", dot);
+            Ok(())
+        }
+    }
+}
+
+/// Run a ROM without a window for a number of frames, then save a screenshot of the last frame
+/// or, with `--record`, an animation of every frame
+fn run_capture_command(args: &[String]) -> Result<(), String> {
+    let usage = "Usage: silk8 capture <rom> --output <file> [--frames <n>] [--record] [--wav <file>] [--scale <n>] [--palette <name>] [--variant <name>] [--movie <file>]";
+    let mut rom = None;
+    let mut output = None;
+    let mut frames = 60;
+    let mut record = false;
+    let mut scale = 4;
+    let mut palette = Palette::default();
+    let mut variant = Variant::XOCHIP;
+    let mut movie = None;
+    let mut wav = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--output" | "-o" => {
+                output = Some(args.next().ok_or("--output needs a file name")?);
+            },
+            "--frames" => {
+                let value = args.next().ok_or("--frames needs a number of frames")?;
+                frames = value.parse().map_err(|_| format!("Invalid frame count '{}'", value))?;
+            },
+            "--record" => record = true,
+            "--wav" => {
+                wav = Some(args.next().ok_or("--wav needs a file name")?);
+            },
+            "--scale" => {
+                let value = args.next().ok_or("--scale needs a number")?;
+                scale = value.parse().ok().filter(|scale| *scale > 0).ok_or_else(|| format!("Invalid scale '{}'", value))?;
+            },
+            "--palette" => {
+                palette = parse_palette(args.next().ok_or("--palette needs a palette name or colors")?)?;
+            },
+            "--variant" => {
+                variant = parse_variant(args.next().ok_or("--variant needs a variant name")?)?;
+            },
+            "--movie" => {
+                movie = Some(args.next().ok_or("--movie needs a file name")?);
+            },
+            _ if rom.is_none() => rom = Some(arg),
+            _ => return Err(format!("Unexpected argument '{}'", arg)),
+        }
+    }
+
+    let (rom, output) = rom.zip(output).ok_or(usage)?;
+    let bytes = read_rom(rom)?;
+    let mut emulator = Emulator::new(variant);
+    emulator.load_rom(bytes);
+    emulator.set_palette(palette);
+    if let Some(movie) = movie {
+        emulator.play_movie(Movie::load(Path::new(&movie))?)?;
+    }
+    if record {
+        let format = VideoFormat::from_path(output).ok_or("Recordings can only be saved as .gif, .png or .apng")?;
+        emulator.start_recording_video(VideoRecorder::new(format, scale, None));
+    }
+    if wav.is_some() {
+        // Sounding the same as recordings made in the window
+        emulator.start_recording_audio(Settings::load().tone());
+    }
+
+    for _ in 0..frames {
+        emulator.run_frame(&mut [false; 16], &mut NullAudio::default());
+    }
+
+    if let (Some(path), Some(recording)) = (wav, emulator.stop_recording_audio()) {
+        std::fs::write(path, recording.to_wav()).map_err(|err| format!("Failed to write {}: {}", path, err))?;
+    }
+    let encoded = match emulator.stop_recording_video() {
+        Some(video) => video.encode()?,
+        None => encode_png(&emulator.frame(&palette), scale)?,
+    };
+    std::fs::write(output, encoded).map_err(|err| format!("Failed to write {}: {}", output, err))
+}
+
 struct SILK8 {
     /// Immediate viewports are show immediately, so passing state to/from them is easy.
     /// The downside is that their painting is linked with the parent viewport:
     /// if either needs repainting, they are both repainted.
     show_about_window: bool,
 
-    menubar: Option<Menu>,
-    menubar_items: HashMap<MenuId, String>,
+    menu: Vec<MenuEntry>,
+    /// The menu attached to the window, or None where egui draws it instead
+    menubar: Option<NativeMenu>,
     menubar_interaction: String,
 
-    chip8: Chip8,
-    variant: Variant,
-    rom_loaded: bool,
+    emulator: Emulator,
+    display: EguiDisplay,
+    keyboard: EguiKeyboard,
+    gamepad: GilrsGamepad,
+    keypad_panel: KeypadPanel,
+    audio: RodioAudio,
+
+    netplay: Option<NetplaySession>,
+    netplay_window: NetplayWindow,
+    cheats_window: CheatsWindow,
+    memory_window: MemoryWindow,
+    disassembly_window: DisassemblyWindow,
+    profiler_window: ProfilerWindow,
+    coverage_window: CoverageWindow,
+    palette_window: PaletteWindow,
+    settings_window: SettingsWindow,
 
-    sink: Sink,
+    settings: Settings,
+    /// Command-line options, which take priority over the settings for this run until the user
+    /// changes the same setting themselves
+    launch: LaunchOptions,
+
+    capture_scale: usize,
+    /// Colors for screenshots and recordings, or the emulator's own if unset
+    capture_palette: Option<Palette>,
+
+    /// A message shown over the display, such as why a ROM couldn't be loaded, and when it was shown
+    message: Option<(String, Instant)>,
+}
+
+impl SILK8 {
+    fn new(ctx: &egui::Context, mut launch: LaunchOptions) -> SILK8 {
+        let settings = Settings::load();
+        let mut silk8 = Self {
+            show_about_window: false,
+            menu: menu_model(),
+            menubar: None,
+            menubar_interaction: "".to_string(),
+            emulator: Emulator::new(settings.variant(None)),
+            display: EguiDisplay::new(ctx.clone()),
+            keyboard: EguiKeyboard::new(ctx.clone()),
+            gamepad: GilrsGamepad::new(),
+            keypad_panel: KeypadPanel::new(),
+            audio: RodioAudio::new(settings.tone()),
+            netplay: None,
+            netplay_window: NetplayWindow::new(),
+            cheats_window: CheatsWindow::new(),
+            memory_window: MemoryWindow::new(),
+            disassembly_window: DisassemblyWindow::new(),
+            profiler_window: ProfilerWindow::new(),
+            coverage_window: CoverageWindow::new(),
+            palette_window: PaletteWindow::new(),
+            settings_window: SettingsWindow::new(),
+            settings,
+            launch: LaunchOptions::default(),
+            capture_scale: 4,
+            capture_palette: None,
+            message: None,
+        };
+        let rom = launch.rom.take();
+        silk8.launch = launch;
+        if let Some(rom) = rom {
+            silk8.start_rom(rom);
+        }
+        silk8.apply_settings();
+        silk8
+    }
+
+    /// Start a ROM with its saved cheats. Every ROM gets a fresh random seed, except that the one
+    /// from `--seed` is kept for every ROM loaded while the app is open.
+    fn start_rom(&mut self, rom: Vec<u8>) {
+        self.emulator.load_rom(rom);
+        if let Some(seed) = self.launch.seed {
+            self.emulator.chip8_mut().set_seed(seed);
+        }
+        load_cheats(&mut self.emulator);
+    }
+
+    /// Forget the command-line overrides for any of the settings the user has changed since `before`
+    fn drop_launch_overrides(&mut self, before: &Settings) {
+        let rom_hash = self.emulator.rom_hash();
+        let rom_ipf = |settings: &Settings| rom_hash.and_then(|rom_hash| settings.rom(rom_hash).ipf);
+        if self.settings.variant(rom_hash) != before.variant(rom_hash) {
+            self.launch.variant = None;
+        }
+        if rom_ipf(&self.settings) != rom_ipf(before) {
+            self.launch.ipf = None;
+        }
+        if self.settings.palette(rom_hash) != before.palette(rom_hash) {
+            self.launch.palette = None;
+        }
+    }
+
+    /// Bring the emulator in line with the settings, including any overrides for the loaded ROM,
+    /// and the command-line options over both
+    fn apply_settings(&mut self) {
+        let rom_hash = self.emulator.rom_hash();
+        self.emulator.set_speed(self.settings.speed);
+        self.emulator.set_flicker_mode(self.settings.flicker_mode());
+        self.emulator.set_blend_frames(self.settings.blend_frames);
+        self.emulator.set_palette(self.launch.palette.unwrap_or(self.settings.palette(rom_hash)));
+        self.keyboard.set_layout(self.settings.key_layout());
+        self.gamepad.set_mapping(self.settings.gamepad_mapping(rom_hash));
+        self.keypad_panel.open = self.settings.show_keypad;
+        self.audio.set_tone(self.settings.tone());
+        // The volume is part of the tone, so only muting is left to the output
+        self.audio.set_volume(if self.launch.mute { 0.0 } else { 1.0 });
+
+        let variant = self.launch.variant.unwrap_or(self.settings.variant(rom_hash));
+        let ipf = self.launch.ipf
+            .or(rom_hash.and_then(|rom_hash| self.settings.rom(rom_hash).ipf))
+            .unwrap_or(default_ipf(variant));
+        if variant != self.emulator.variant() {
+            // Switching variants means starting the ROM over
+            let seed = self.emulator.chip8().get_seed();
+            self.emulator.restart_with(variant, seed, ipf);
+        } else if ipf != self.emulator.ipf() {
+            self.emulator.set_ipf(ipf);
+        }
+        // A variant brings its own quirks, so the command-line ones go back on top
+        for (set_quirk, enabled) in &self.launch.quirks {
+            set_quirk(self.emulator.chip8_mut(), *enabled);
+        }
+    }
+
+    /// Remember a variant for the loaded ROM, or for every ROM if none is loaded
+    fn save_variant_setting(&mut self, variant: Variant) {
+        let name = variant.name().to_string();
+        match self.emulator.rom_hash() {
+            Some(rom_hash) => {
+                let mut rom = self.settings.rom(rom_hash);
+                rom.variant = Some(name);
+                self.settings.set_rom(rom_hash, rom);
+            },
+            None => self.settings.variant = name,
+        }
+    }
+
+    /// Remember a palette for the loaded ROM, or for every ROM if none is loaded
+    fn save_palette_setting(&mut self) {
+        let name = palette_name(&self.emulator.palette());
+        match self.emulator.rom_hash() {
+            Some(rom_hash) => {
+                let mut rom = self.settings.rom(rom_hash);
+                rom.palette = Some(name);
+                self.settings.set_rom(rom_hash, rom);
+            },
+            None => self.settings.palette = name,
+        }
+    }
+
+    fn load_rom_from_dialog(&mut self) {
+        let file = FileDialog::new()
+            .add_filter("ROMs", &ROM_EXTENSIONS)
+            .set_directory(self.settings.rom_directory.clone().unwrap_or_else(|| "./roms".into()))
+            .pick_file();
+        if let Some(path) = file {
+            self.load_rom_from_path(&path);
+        }
+    }
+
+    /// Show a message over the display for a few seconds
+    fn show_message(&mut self, message: String) {
+        self.message = Some((message, Instant::now()));
+    }
+
+    /// Load a ROM, assembling it first if it's Octo source, and put it at the top of File → Recent.
+    /// The settings are left for the caller to save.
+    fn load_rom_from_path(&mut self, path: &Path) {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                self.show_message(format!("Failed to read {}: {}", path.display(), err));
+                // It's been moved or deleted, so there's no use offering it again
+                self.settings.recent_roms.retain(|recent| recent != path);
+                self.update_recent_menu();
+                return;
+            }
+        };
+        match rom_from_file(path, bytes) {
+            Ok(rom) => {
+                self.start_rom(rom);
+                self.settings.rom_directory = path.parent().map(Path::to_path_buf);
+                self.settings.add_recent_rom(path);
+                self.apply_settings();
+                self.message = None;
+            },
+            Err(err) => self.show_message(err),
+        }
+        self.update_recent_menu();
+    }
+
+    /// Load the first ROM dropped onto the window
+    fn load_dropped_rom(&mut self, files: &[egui::DroppedFile]) {
+        let path = match files.iter().find_map(|file| file.path.as_ref()) {
+            Some(path) => path,
+            None => return,
+        };
+        let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or("").to_ascii_lowercase();
+        if !ROM_EXTENSIONS.contains(&extension.as_str()) {
+            let name = path.file_name().map_or_else(|| path.display().to_string(), |name| name.to_string_lossy().into_owned());
+            self.show_message(format!("Can't load {}, ROMs end in .{}", name, ROM_EXTENSIONS.join(", .")));
+        } else {
+            self.load_rom_from_path(path);
+            self.settings.save();
+            self.sync_menubar_checks();
+        }
+    }
+
+    /// The entries for File → Recent, which are looked up by position since paths don't have to be valid UTF-8
+    fn recent_entries(&self) -> Vec<MenuEntry> {
+        self.settings.recent_roms
+            .iter()
+            .enumerate()
+            .map(|(index, path)| {
+                let name = path.file_name().map_or_else(|| path.display().to_string(), |name| name.to_string_lossy().into_owned());
+                MenuEntry::item(format!("Recent:{}", index), name)
+            })
+            .collect()
+    }
+
+    fn dynamic_entries(&self, id: &str) -> Vec<MenuEntry> {
+        match id {
+            "Recent" => self.recent_entries(),
+            _ => vec![],
+        }
+    }
+
+    /// Rebuild File → Recent from the settings
+    fn update_recent_menu(&mut self) {
+        let entries = self.recent_entries();
+        if let Some(menubar) = &mut self.menubar {
+            menubar.set_dynamic("Recent", &entries);
+        }
+    }
+
+    fn toggle_movie_recording(&mut self) {
+        if let Some(movie) = self.emulator.stop_recording_movie() {
+            let file = FileDialog::new()
+                .add_filter("SILK-8 Movies", &["s8m"])
+                .set_directory("./movies")
+                .set_file_name("movie.s8m")
+                .save_file();
+            if let Some(path) = file {
+                if let Err(err) = movie.save(&path) {
+                    self.show_message(err);
+                }
+            }
+        } else if let Err(err) = self.emulator.start_recording_movie() {
+            self.show_message(err);
+        } else if self.emulator.cheats().any_enabled() {
+            self.show_message("Cheats are off while the movie records".to_string());
+        }
+    }
+
+    fn play_movie_from_dialog(&mut self) {
+        let file = FileDialog::new()
+            .add_filter("SILK-8 Movies", &["s8m"])
+            .set_directory("./movies")
+            .pick_file();
+        if let Some(path) = file {
+            let result = Movie::load(&path).and_then(|movie| self.emulator.play_movie(movie));
+            if let Err(err) = result {
+                self.show_message(err);
+            }
+        }
+    }
+
+    fn save_screenshot(&self) {
+        let palette = self.capture_palette.unwrap_or(self.emulator.palette());
+        let file = FileDialog::new()
+            .add_filter("PNG", &["png"])
+            .set_directory("./captures")
+            .set_file_name("screenshot.png")
+            .save_file();
+        if let Some(path) = file {
+            let result = encode_png(&self.emulator.frame(&palette), self.capture_scale)
+                .and_then(|png| std::fs::write(&path, png).map_err(|err| err.to_string()));
+            if let Err(err) = result {
+                println!("Failed to save screenshot: {}", err);
+            }
+        }
+    }
+
+    /// Start recording in the given format, or stop and save the recording already going
+    fn toggle_video_recording(&mut self, format: VideoFormat) {
+        let video = match self.emulator.stop_recording_video() {
+            Some(video) => video,
+            None => {
+                self.emulator.start_recording_video(VideoRecorder::new(format, self.capture_scale, self.capture_palette));
+                return;
+            }
+        };
+        let (name, filter) = match video.format {
+            VideoFormat::Gif => ("recording.gif", "GIF"),
+            VideoFormat::Apng => ("recording.png", "APNG"),
+        };
+        let file = FileDialog::new()
+            .add_filter(filter, &[video.format.extension()])
+            .set_directory("./captures")
+            .set_file_name(name)
+            .save_file();
+        if let Some(path) = file {
+            let result = video.encode().and_then(|bytes| std::fs::write(&path, bytes).map_err(|err| err.to_string()));
+            if let Err(err) = result {
+                println!("Failed to save recording: {}", err);
+            }
+        }
+    }
+
+    fn toggle_audio_recording(&mut self) {
+        let recording = match self.emulator.stop_recording_audio() {
+            Some(recording) => recording,
+            None => {
+                self.emulator.start_recording_audio(self.settings.tone());
+                return;
+            }
+        };
+        let file = FileDialog::new()
+            .add_filter("WAV", &["wav"])
+            .set_directory("./captures")
+            .set_file_name("recording.wav")
+            .save_file();
+        if let Some(path) = file {
+            if let Err(err) = std::fs::write(&path, recording.to_wav()) {
+                println!("Failed to save recording: {}", err);
+            }
+        }
+    }
+
+    fn handle_menu_item(&mut self, ctx: &egui::Context, item: &str) {
+        let settings = self.settings.clone();
+        match item {
+            "Load ROM" => {
+                self.load_rom_from_dialog();
+            },
+            "Quit" => {
+                ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+            },
+            "About" => {
+                self.show_about_window = true;
+            },
+            "Settings" => {
+                self.settings_window.open = true;
+            },
+            "Netplay" => {
+                self.netplay_window.open = true;
+            },
+            "Cheats" => {
+                self.cheats_window.open = true;
+            },
+            "Memory" => {
+                self.memory_window.open = true;
+            },
+            "Disassembly" => {
+                self.disassembly_window.open = true;
+            },
+            "Profiler" => {
+                self.profiler_window.open = true;
+            },
+            "Coverage" => {
+                self.coverage_window.open = true;
+            },
+            "Custom Palette" => {
+                self.palette_window.open = true;
+            },
+            "Screenshot" => {
+                self.save_screenshot();
+            },
+            "Record GIF" => {
+                self.toggle_video_recording(VideoFormat::Gif);
+            },
+            "Record APNG" => {
+                self.toggle_video_recording(VideoFormat::Apng);
+            },
+            "Record WAV" => {
+                self.toggle_audio_recording();
+            },
+            "Pause" => {
+                self.emulator.set_paused(!self.emulator.is_paused());
+            },
+            "Frame Advance" => {
+                self.emulator.advance_frame();
+            },
+            "Soft Reset" => {
+                if !self.emulator.is_movie_active() {
+                    self.emulator.soft_reset();
+                }
+            },
+            "Hard Reset" => {
+                if !self.emulator.is_movie_active() {
+                    self.emulator.hard_reset();
+                }
+            },
+            "Keypad" => {
+                self.keypad_panel.open = !self.keypad_panel.open;
+                self.settings.show_keypad = self.keypad_panel.open;
+            },
+            "Record Movie" => {
+                self.toggle_movie_recording();
+            },
+            "Play Movie" => {
+                self.play_movie_from_dialog();
+            },
+            "Stop Movie" => {
+                self.emulator.stop_movie();
+            },
+            _ => {
+                if let Some(index) = item.strip_prefix("Recent:") {
+                    if let Some(path) = self.settings.recent_roms.get(index.parse::<usize>().unwrap()).cloned() {
+                        self.load_rom_from_path(&path);
+                    }
+                } else if let Some(name) = item.strip_prefix("Variant:") {
+                    if !self.emulator.is_movie_active() {
+                        self.save_variant_setting(Variant::from_name(name).unwrap());
+                        self.launch.variant = None;
+                        self.apply_settings();
+                    }
+                } else if let Some(speed) = item.strip_prefix("Speed:") {
+                    self.emulator.set_speed(speed.parse().unwrap());
+                    self.settings.speed = self.emulator.speed();
+                } else if let Some(multiplier) = item.strip_prefix("Turbo:") {
+                    self.emulator.set_turbo_multiplier(multiplier.parse().unwrap());
+                } else if let Some(ipf) = item.strip_prefix("IPF:") {
+                    self.emulator.set_ipf(ipf.parse().unwrap());
+                    self.launch.ipf = None;
+                    if let Some(rom_hash) = self.emulator.rom_hash() {
+                        let mut rom = self.settings.rom(rom_hash);
+                        rom.ipf = Some(self.emulator.ipf());
+                        self.settings.set_rom(rom_hash, rom);
+                    }
+                } else if let Some(name) = item.strip_prefix("Palette:") {
+                    self.emulator.set_palette(Palette::parse(name).unwrap());
+                    self.launch.palette = None;
+                    self.save_palette_setting();
+                } else if let Some(mode) = item.strip_prefix("Flicker:") {
+                    self.emulator.set_flicker_mode(FlickerMode::from_name(mode).unwrap());
+                    self.settings.flicker = mode.to_string();
+                } else if let Some(frames) = item.strip_prefix("BlendFrames:") {
+                    self.emulator.set_blend_frames(frames.parse().unwrap());
+                    self.settings.blend_frames = self.emulator.blend_frames();
+                } else if let Some(scale) = item.strip_prefix("CaptureScale:") {
+                    self.capture_scale = scale.parse().unwrap();
+                } else if let Some(name) = item.strip_prefix("CapturePalette:") {
+                    self.capture_palette = Palette::parse(name);
+                }
+            }
+        }
+        if self.settings != settings {
+            self.settings.save();
+        }
+        self.sync_menubar_checks();
+    }
+
+    /// Make the checkmarks in the menubar match the emulator state, which can also change from
+    /// keyboard shortcuts or loading a ROM with its own speed
+    fn sync_menubar_checks(&self) {
+        if let Some(menubar) = &self.menubar {
+            menubar.sync_checks(|item| self.is_checked(item));
+        }
+    }
+
+    /// Whether a checkable menu item should show a checkmark
+    fn is_checked(&self, item: &str) -> bool {
+        match item {
+            "Pause" => self.emulator.is_paused(),
+            "Record Movie" => self.emulator.is_recording_movie(),
+            "Record GIF" => self.emulator.video_format() == Some(VideoFormat::Gif),
+            "Record APNG" => self.emulator.video_format() == Some(VideoFormat::Apng),
+            "Record WAV" => self.emulator.is_recording_audio(),
+            "Keypad" => self.keypad_panel.open,
+            _ => {
+                if let Some(name) = item.strip_prefix("Variant:") {
+                    self.emulator.variant().name() == name
+                } else if let Some(speed) = item.strip_prefix("Speed:") {
+                    speed.parse::<f32>().unwrap() == self.emulator.speed()
+                } else if let Some(multiplier) = item.strip_prefix("Turbo:") {
+                    multiplier.parse::<f32>().unwrap() == self.emulator.turbo_multiplier()
+                } else if let Some(ipf) = item.strip_prefix("IPF:") {
+                    ipf.parse::<u32>().unwrap() == self.emulator.ipf()
+                } else if let Some(name) = item.strip_prefix("Palette:") {
+                    self.emulator.palette().preset_name() == Some(name)
+                } else if let Some(mode) = item.strip_prefix("Flicker:") {
+                    self.emulator.flicker_mode().name() == mode
+                } else if let Some(frames) = item.strip_prefix("BlendFrames:") {
+                    frames.parse::<usize>().unwrap() == self.emulator.blend_frames()
+                } else if let Some(scale) = item.strip_prefix("CaptureScale:") {
+                    scale.parse::<usize>().unwrap() == self.capture_scale
+                } else if let Some(name) = item.strip_prefix("CapturePalette:") {
+                    self.capture_palette.and_then(|palette| palette.preset_name()).unwrap_or("Current") == name
+                } else {
+                    false
+                }
+            }
+        }
+    }
 }
 
 impl eframe::App for SILK8 {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         egui_extras::install_image_loaders(ctx);
-        ctx.request_repaint();
+        if self.emulator.chip8().is_halted() || self.emulator.is_paused() {
+            // Nothing left to emulate, so only wake up occasionally to catch menu events
+            ctx.request_repaint_after(Duration::from_millis(100));
+        } else {
+            ctx.request_repaint();
+        }
 
         // Check for interactions on the menubar
-        if let Ok(event) = MenuEvent::receiver().try_recv() {
-            let item_string = self.menubar_items.get(event.id()).unwrap();
-            match item_string.as_str() {
-                "Load ROM" => {
-                    let file = FileDialog::new()
-                        .add_filter("ROMs", &["ch8"])
-                        .set_directory("./roms")
-                        .pick_file();
-                    if let Some(path) = file {
-                        self.chip8.reset();
-                        self.chip8.load_rom_from_file(path.to_str().unwrap());
-                        self.rom_loaded = true;
-                    }
-                },
-                "Quit" => {
-                    ctx.send_viewport_cmd(egui::ViewportCommand::Close);
-                },
-                "About" => {
-                    self.show_about_window = true;
-                }
-                _ => {}
-            }
+        let event_item = MenuEvent::receiver().try_recv().ok()
+            .and_then(|event| self.menubar.as_ref().and_then(|menubar| menubar.item_id(event.id())).map(str::to_string));
+        if let Some(item_string) = event_item {
+            self.handle_menu_item(ctx, &item_string);
         } else if self.menubar_interaction != "" {
             // I don't love this but it's conceptually easier than messing around
             // with the Windows API I'd have to interact with for accelerators
-            match self.menubar_interaction.to_owned().as_str() {
-                "Load ROM" => {
-                    let file = FileDialog::new()
-                        .add_filter("ROMs", &["ch8"])
-                        .set_directory("./roms")
-                        .pick_file();
-                    if let Some(path) = file {
-                        self.chip8.reset();
-                        self.chip8.load_rom_from_file(path.to_str().unwrap());
-                        self.rom_loaded = true;
-                    }
-                },
-                _ => {}
-            }
+            let item_string = self.menubar_interaction.to_owned();
+            self.handle_menu_item(ctx, &item_string);
             self.menubar_interaction = "".to_string();
         }
 
-        if self.rom_loaded {
-            // Run the interpreter
-            for _ in 0..10 {
-                self.chip8.run();
-                if matches!(self.variant, Variant::CHIP8 | Variant::SCHIP_LEGACY) && self.chip8.displayed_this_frame() {
-                    break;
-                }
+        // Run the interpreter at a fixed 60 Hz, scaled by the speed setting and turbo key,
+        // independent of the repaint rate, and render the display to a texture for egui
+        if let Some(netplay) = &mut self.netplay {
+            // Both players have to run in lockstep, so there's no turbo and a frame
+            // only runs once the other player's input for it has arrived
+            let due = self.emulator.frames_due(Instant::now());
+            if let Err(err) = netplay.run_frames(due, &mut self.emulator, &mut (&mut self.keyboard, (&mut self.gamepad, &mut self.keypad_panel)), &mut self.audio) {
+                self.netplay = None;
+                self.show_message(format!("Netplay error: {}", err));
             }
-            self.chip8.decrement_timers();
-
-            // Handle audio playback
-            let sound_timer = self.chip8.get_sound_timer();
-            if sound_timer > 0 && self.sink.is_paused() {
-                self.sink.play();
-            } else if sound_timer == 0 && !self.sink.is_paused() {
-                self.sink.pause();
+        } else {
+            let was_paused = self.emulator.is_paused();
+            self.emulator.set_turbo_held(ctx.input(|i| i.key_down(Key::Tab)));
+            self.emulator.update(Instant::now(), &mut (&mut self.keyboard, (&mut self.gamepad, &mut self.keypad_panel)), &mut self.audio);
+            if self.emulator.is_paused() != was_paused {
+                // A breakpoint was hit
+                self.sync_menubar_checks();
             }
         }
+        self.emulator.present(&mut self.display);
 
-        // Render the display to a texture for egui
-        let display_height = if self.chip8.hires_mode() { 64 } else { 32 };
-        let display_width = if self.chip8.hires_mode() { 128 } else { 64 };
-        let pixels = self.chip8.get_display()
-            .iter()
-            .map(|b| if *b == 1 { [255_u8, 255_u8, 255_u8] } else { [0_u8, 0_u8, 0_u8] })
-            .flatten()
-            .collect::<Vec<u8>>();
-        let pixels_range = &pixels[0..display_height * display_width * 3];
-        let color_image = egui::ColorImage::from_rgb([display_width, display_height], pixels_range);
-        let handle = ctx.load_texture("Display", color_image, egui::TextureOptions::NEAREST);
-
-        // Draw main window
-        egui::CentralPanel::default().frame(egui::Frame::none()).show(ctx, |ui| {
-            if self.menubar.is_none() {
-                let (menubar, menubar_items) = create_menubar();
-                #[cfg(target_os = "windows")]
-                {
-                    let handle = _frame.window_handle().unwrap().as_raw();
-                    let hwnd = match handle {
-                        RawWindowHandle::Win32(handle) => handle.hwnd.get(),
-                        _ => panic!("Cannot handle other platform window handles yet!"),
-                    };
-                    menubar.init_for_hwnd(hwnd).unwrap();
-                }
-                #[cfg(target_os = "macos")]
-                {
-                    menubar.init_for_nsapp();
-                }
-                self.menubar = Some(menubar);
-                self.menubar_items = menubar_items;
+        if NATIVE_MENUBAR && self.menubar.is_none() {
+            let menubar = NativeMenu::new(&self.menu);
+            menubar.attach(_frame);
+            self.menubar = Some(menubar);
+            self.update_recent_menu();
+            self.sync_menubar_checks();
+        }
+        if self.menubar.is_none() {
+            let mut clicked = None;
+            egui::TopBottomPanel::top("menubar").exact_height(MENU_BAR_HEIGHT).show(ctx, |ui| {
+                clicked = show_menu_bar(ui, &self.menu, &|item| self.is_checked(item), &|id| self.dynamic_entries(id));
+            });
+            if let Some(item_string) = clicked {
+                self.handle_menu_item(ctx, &item_string);
             }
+        }
 
-            let sized_image = egui::load::SizedTexture::new(handle.id(), egui::vec2(640.0, 320.0));
-            let image = egui::Image::from_texture(sized_image);
-            ui.add(image);
+        self.keypad_panel.show(ctx, &self.emulator);
+
+        // Draw main window, with the display as large as fits
+        egui::CentralPanel::default().frame(egui::Frame::none().fill(egui::Color32::BLACK)).show(ctx, |ui| {
+            let available = ui.available_rect_before_wrap();
+            let scale = (available.width() / 64.0).min(available.height() / 32.0);
+            let rect = egui::Rect::from_center_size(available.center(), egui::vec2(64.0, 32.0) * scale);
+            let response = ui.allocate_ui_at_rect(rect, |ui| self.display.show(ui, rect.size())).inner;
+
+            if ctx.input(|i| !i.raw.hovered_files.is_empty()) {
+                draw_drop_overlay(ui, response.rect);
+            } else if self.emulator.chip8().is_halted() {
+                draw_program_ended_overlay(ui, response.rect);
+            }
+            if let Some((message, shown)) = &self.message {
+                let remaining = MESSAGE_DURATION.saturating_sub(shown.elapsed());
+                if remaining.is_zero() {
+                    self.message = None;
+                } else {
+                    draw_message_overlay(ui, response.rect, message);
+                    ctx.request_repaint_after(remaining);
+                }
+            }
         });
 
         // Draw about window, if activve
@@ -196,82 +961,61 @@ impl eframe::App for SILK8 {
             );
         }
 
-        // Handle input
-        for (key, value) in [
-            (Key::Num1, 0),
-            (Key::Num2, 1),
-            (Key::Num3, 2),
-            (Key::Num4, 3),
-            (Key::Q, 4),
-            (Key::W, 5),
-            (Key::E, 6),
-            (Key::R, 7),
-            (Key::A, 8),
-            (Key::S, 9),
-            (Key::D, 10),
-            (Key::F, 11),
-            (Key::Z, 12),
-            (Key::X, 13),
-            (Key::C, 14),
-            (Key::V, 15),
-        ] {
-            if ctx.input(|i| i.key_down(key)) {
-                self.chip8.set_keypad_state(value, true);
-            } else if ctx.input(|i| i.key_released(key)) {
-                self.chip8.set_keypad_state(value, false);
+        if self.netplay_window.open {
+            self.netplay_window.show(ctx, &mut self.netplay);
+        }
+        if self.cheats_window.open {
+            self.cheats_window.show(ctx, &mut self.emulator);
+        }
+        if self.memory_window.open {
+            self.memory_window.show(ctx, &mut self.emulator);
+        }
+        if self.disassembly_window.open {
+            self.disassembly_window.show(ctx, &mut self.emulator);
+        }
+        if self.profiler_window.open {
+            self.profiler_window.show(ctx, &mut self.emulator);
+        }
+        if self.coverage_window.open {
+            self.coverage_window.show(ctx, &mut self.emulator);
+        }
+        if self.palette_window.open {
+            let palette = self.emulator.palette();
+            self.palette_window.show(ctx, &mut self.emulator);
+            if self.emulator.palette() != palette {
+                self.launch.palette = None;
+                self.save_palette_setting();
+                self.settings.save();
+                // Picking colors by hand can move on to or off of a preset
+                self.sync_menubar_checks();
+            }
+        }
+        if self.settings_window.open {
+            let before = self.settings.clone();
+            if self.settings_window.show(ctx, &mut self.settings, self.emulator.rom_hash()) {
+                self.drop_launch_overrides(&before);
+                self.apply_settings();
+                self.settings.save();
+                self.sync_menubar_checks();
             }
         }
 
+        let dropped_files = ctx.input(|i| i.raw.dropped_files.clone());
+        if !dropped_files.is_empty() {
+            self.load_dropped_rom(&dropped_files);
+        }
+
         if ctx.input(|i| i.modifiers.ctrl) && ctx.input(|i| i.key_pressed(Key::O)) {
             self.menubar_interaction = "Load ROM".to_string();
+        } else if ctx.input(|i| i.modifiers.ctrl) && ctx.input(|i| i.key_pressed(Key::P)) {
+            self.menubar_interaction = "Pause".to_string();
+        } else if ctx.input(|i| i.modifiers.ctrl) && ctx.input(|i| i.key_pressed(Key::R)) {
+            let item = if ctx.input(|i| i.modifiers.shift) { "Hard Reset" } else { "Soft Reset" };
+            self.menubar_interaction = item.to_string();
+        } else if ctx.input(|i| i.modifiers.ctrl) && ctx.input(|i| i.key_pressed(Key::Period)) {
+            self.menubar_interaction = "Frame Advance".to_string();
+        } else if ctx.input(|i| i.key_pressed(Key::F12)) {
+            self.menubar_interaction = "Screenshot".to_string();
         }
     }
 }
-
-fn create_menubar() -> (Menu, HashMap<MenuId, String>) {
-    let menu = Menu::new();
-
-    // File Tab
-    let load_rom = MenuItem::new(
-        "Load ROM",
-        true,
-        Some(Accelerator::new(Some(Modifiers::CONTROL), Code::KeyO)),
-    );
-    let quit = MenuItem::new(
-        "Quit",
-        true,
-        None,
-    );
-    let file_tab = Submenu::with_items(
-        "File",
-        true,
-        &[
-            &load_rom,
-            &PredefinedMenuItem::separator(),
-            &quit,
-        ],
-    ).unwrap();
-    menu.append(&file_tab).unwrap();
-
-    // Help Tab
-    let about = MenuItem::new(
-        "About",
-        true,
-        None,
-    );
-    let help_tab = Submenu::with_items(
-        "Help",
-        true,
-        &[
-            &about,
-        ],
-    ).unwrap();
-    menu.append(&help_tab).unwrap();
-
-    let mut menu_ids = HashMap::new();
-    menu_ids.insert(load_rom.id().clone(), "Load ROM".to_string());
-    menu_ids.insert(quit.id().clone(), "Quit".to_string());
-    menu_ids.insert(about.id().clone(), "About".to_string());
-
-    (menu, menu_ids)
-}
\ No newline at end of file
@@ -1,20 +1,31 @@
+pub mod cfg;
 mod chip8;
+pub mod disassembler;
+mod frontend;
 mod square_wave;
+mod wav_recorder;
 
 use std::sync::{
-  atomic::{AtomicBool, Ordering},
+  atomic::{AtomicBool, AtomicU16, Ordering},
   Mutex
 };
+use std::time::Duration;
 
 use eframe::egui;
-use egui::Key;
 use lazy_static::lazy_static;
-use rodio::{source::Source, OutputStream, Sink};
+use web_time::Instant;
 #[cfg(target_arch = "wasm32")]
 use wasm_bindgen::prelude::*;
 
-use chip8::{Chip8, Variant};
-use square_wave::SquareWave;
+pub use chip8::Variant;
+use frontend::egui_backend::{draw_program_ended_overlay, EguiDisplay, EguiKeyboard};
+use frontend::rodio_backend::RodioAudio;
+use frontend::cheats::{CheatList, CheatSearch, SearchCondition};
+use frontend::flicker::FlickerMode;
+use frontend::movie::Movie;
+use frontend::palette::Palette;
+use frontend::{rom_hash, Emulator};
+use square_wave::Tone;
 
 // I need to allow for ROMs to be loaded when called from a function outside the event loop
 // and this was the best way I could think to do it. Also helps to forward keypad state when
@@ -23,7 +34,35 @@ lazy_static! {
   static ref HAS_ROM: AtomicBool = AtomicBool::new(false);
   static ref ROM_CHANGED: AtomicBool = AtomicBool::new(false);
   static ref ROM_BYTES: Mutex<Vec<u8>> = Mutex::new(vec![]);
-  static ref KEYPAD_STATE: Mutex<Vec<bool>> = Mutex::new(vec![false; 16]);
+  static ref KEYPAD_STATE: Mutex<[bool; 16]> = Mutex::new([false; 16]);
+  static ref PAUSED: AtomicBool = AtomicBool::new(false);
+  static ref FRAME_ADVANCE: AtomicBool = AtomicBool::new(false);
+  static ref TURBO_HELD: AtomicBool = AtomicBool::new(false);
+  static ref PENDING_IPF: Mutex<Option<u32>> = Mutex::new(None);
+  static ref PENDING_SPEED: Mutex<Option<f32>> = Mutex::new(None);
+  static ref PENDING_TURBO_MULTIPLIER: Mutex<Option<f32>> = Mutex::new(None);
+  static ref PENDING_PALETTE: Mutex<Option<Palette>> = Mutex::new(None);
+  static ref PENDING_FLICKER_MODE: Mutex<Option<FlickerMode>> = Mutex::new(None);
+  static ref PENDING_BLEND_FRAMES: Mutex<Option<usize>> = Mutex::new(None);
+  static ref PENDING_TONE: Mutex<Option<Tone>> = Mutex::new(None);
+  static ref PALETTE_TEXT: Mutex<String> = Mutex::new(Palette::default().to_string());
+  static ref START_RECORDING: AtomicBool = AtomicBool::new(false);
+  static ref STOP_RECORDING: AtomicBool = AtomicBool::new(false);
+  static ref STOP_MOVIE: AtomicBool = AtomicBool::new(false);
+  static ref PENDING_MOVIE: Mutex<Option<Vec<u8>>> = Mutex::new(None);
+  static ref RECORDED_MOVIE: Mutex<Vec<u8>> = Mutex::new(vec![]);
+  static ref ROM_HASH: Mutex<String> = Mutex::new(String::new());
+  static ref CHEAT_COMMANDS: Mutex<Vec<CheatCommand>> = Mutex::new(vec![]);
+  static ref CHEAT_RESULTS: Mutex<String> = Mutex::new(String::new());
+  static ref CHEATS_TEXT: Mutex<String> = Mutex::new(String::new());
+  static ref POLLED_KEYS: AtomicU16 = AtomicU16::new(0);
+}
+
+/// Cheat changes requested from outside the event loop, applied in order on the next frame
+enum CheatCommand {
+  NewSearch,
+  Filter(SearchCondition),
+  SetCheats(String),
 }
 
 #[cfg(target_arch = "wasm32")]
@@ -34,29 +73,12 @@ fn main() {
 
   let web_options = eframe::WebOptions::default();
 
-  // Set up rodio
-  let (_stream, stream_handle) = OutputStream::try_default().unwrap();
-  let sink = Sink::try_new(&stream_handle).unwrap();
-  let source = SquareWave::new(440.0).amplify(0.10);
-  sink.append(source);
-  sink.pause();
-
-  let mut chip8 = Chip8::new(Variant::XOCHIP);
-  chip8.load_font();
-
-  let silk8 = SILK8 {
-      chip8,
-      variant: Variant::XOCHIP,
-      rom_loaded: false,
-      sink,
-  };
-
   wasm_bindgen_futures::spawn_local(async {
     eframe::WebRunner::new()
         .start(
             "chipCanvas", // hardcode it
             web_options,
-            Box::new(|cc| Box::new(silk8)),
+            Box::new(|cc| Box::new(SILK8::new(&cc.egui_ctx))),
         )
         .await
         .expect("failed to start eframe");
@@ -65,98 +87,154 @@ fn main() {
 }
 
 struct SILK8 {
-  chip8: Chip8,
-  variant: Variant,
-  rom_loaded: bool,
+  emulator: Emulator,
+  display: EguiDisplay,
+  keyboard: EguiKeyboard,
+  audio: RodioAudio,
+  cheat_search: Option<CheatSearch>,
+}
+
+impl SILK8 {
+  fn new(ctx: &egui::Context) -> SILK8 {
+    Self {
+      emulator: Emulator::new(Variant::XOCHIP),
+      display: EguiDisplay::new(ctx.clone()),
+      keyboard: EguiKeyboard::new(ctx.clone()),
+      audio: RodioAudio::new(Tone::default()),
+      cheat_search: None,
+    }
+  }
+}
 
-  sink: Sink,
+impl SILK8 {
+  fn handle_cheat_commands(&mut self) {
+    let commands = std::mem::take(&mut *CHEAT_COMMANDS.lock().unwrap());
+    if commands.is_empty() {
+      return;
+    }
+
+    for command in commands {
+      match command {
+        CheatCommand::NewSearch => self.cheat_search = Some(CheatSearch::new(self.emulator.chip8())),
+        CheatCommand::Filter(condition) => {
+          if let Some(search) = &mut self.cheat_search {
+            search.filter(self.emulator.chip8(), condition);
+          }
+        },
+        CheatCommand::SetCheats(text) => self.emulator.set_cheats(CheatList::from_text(&text)),
+      }
+    }
+
+    // Listing every byte of memory isn't useful, so wait until the search is narrowed down
+    *CHEAT_RESULTS.lock().unwrap() = match &self.cheat_search {
+      Some(search) if search.len() <= 200 => search
+        .results()
+        .map(|(target, value)| format!("{} 0x{:02X}\n", target, value))
+        .collect(),
+      _ => String::new(),
+    };
+    *CHEATS_TEXT.lock().unwrap() = self.emulator.cheats().to_text();
+  }
 }
 
 impl eframe::App for SILK8 {
   fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
       egui_extras::install_image_loaders(ctx);
-      ctx.request_repaint();
-
-      if !HAS_ROM.load(Ordering::Relaxed) {
-        if ROM_CHANGED.load(Ordering::Relaxed) {
-          ROM_CHANGED.store(false, Ordering::Relaxed);
-          HAS_ROM.store(true, Ordering::Relaxed);
-          self.chip8.load_rom_from_bytes(ROM_BYTES.lock().unwrap().to_owned());
-          self.rom_loaded = true;
-        } else {
-          return;
-        }
+      if self.emulator.chip8().is_halted() || self.emulator.is_paused() {
+          // Nothing left to emulate, so only wake up occasionally to check for a new ROM
+          ctx.request_repaint_after(Duration::from_millis(100));
+      } else {
+          ctx.request_repaint();
       }
 
-      if self.rom_loaded {
-          // Run the interpreter
-          for _ in 0..10 {
-              self.chip8.run();
-              if matches!(self.variant, Variant::CHIP8 | Variant::SCHIP_LEGACY) && self.chip8.displayed_this_frame() {
-                  break;
-              }
-          }
-          self.chip8.decrement_timers();
-
-          // Handle audio playback
-          let sound_timer = self.chip8.get_sound_timer();
-          if sound_timer > 0 && self.sink.is_paused() {
-              self.sink.play();
-          } else if sound_timer == 0 && !self.sink.is_paused() {
-              self.sink.pause();
-          }
+      // A newly loaded ROM also resets a program that has ended
+      if ROM_CHANGED.load(Ordering::Relaxed) {
+        ROM_CHANGED.store(false, Ordering::Relaxed);
+        HAS_ROM.store(true, Ordering::Relaxed);
+        self.emulator.load_rom(ROM_BYTES.lock().unwrap().to_owned());
+      } else if !HAS_ROM.load(Ordering::Relaxed) {
+        return;
+      }
+
+      // Apply speed changes made from outside the event loop. The IPF comes after a ROM change
+      // so it overrides that ROM's remembered speed rather than being overwritten by it.
+      if let Some(ipf) = PENDING_IPF.lock().unwrap().take() {
+        self.emulator.set_ipf(ipf);
+      }
+      if let Some(speed) = PENDING_SPEED.lock().unwrap().take() {
+        self.emulator.set_speed(speed);
+      }
+      if let Some(multiplier) = PENDING_TURBO_MULTIPLIER.lock().unwrap().take() {
+        self.emulator.set_turbo_multiplier(multiplier);
+      }
+      // Same for the palette, which is also remembered per ROM
+      if let Some(palette) = PENDING_PALETTE.lock().unwrap().take() {
+        self.emulator.set_palette(palette);
+      }
+      *PALETTE_TEXT.lock().unwrap() = self.emulator.palette().to_string();
+      if let Some(mode) = PENDING_FLICKER_MODE.lock().unwrap().take() {
+        self.emulator.set_flicker_mode(mode);
+      }
+      if let Some(frames) = PENDING_BLEND_FRAMES.lock().unwrap().take() {
+        self.emulator.set_blend_frames(frames);
+      }
+      if let Some(tone) = PENDING_TONE.lock().unwrap().take() {
+        self.audio.set_tone(tone);
+      }
+      let paused = PAUSED.load(Ordering::Relaxed);
+      if paused != self.emulator.is_paused() {
+        self.emulator.set_paused(paused);
+      }
+      if FRAME_ADVANCE.swap(false, Ordering::Relaxed) {
+        self.emulator.advance_frame();
+      }
+      // Movie commands, which restart the ROM, come after the ROM change for the same reason
+      if START_RECORDING.swap(false, Ordering::Relaxed) {
+        if let Err(err) = self.emulator.start_recording_movie() {
+          log::error!("{}", err);
+        }
+      }
+      if STOP_RECORDING.swap(false, Ordering::Relaxed) {
+        if let Some(movie) = self.emulator.stop_recording_movie() {
+          *RECORDED_MOVIE.lock().unwrap() = movie.to_bytes();
+        }
       }
+      if let Some(bytes) = PENDING_MOVIE.lock().unwrap().take() {
+        if let Err(err) = Movie::from_bytes(&bytes).and_then(|movie| self.emulator.play_movie(movie)) {
+          log::error!("{}", err);
+        }
+      }
+      if STOP_MOVIE.swap(false, Ordering::Relaxed) {
+        self.emulator.stop_movie();
+      }
+
+      self.handle_cheat_commands();
 
-      // Render the display to a texture for egui
-      let display_height = if self.chip8.hires_mode() { 64 } else { 32 };
-      let display_width = if self.chip8.hires_mode() { 128 } else { 64 };
-      let pixels = self.chip8.get_display()
-          .iter()
-          .map(|b| if *b == 1 { [255_u8, 255_u8, 255_u8] } else { [0_u8, 0_u8, 0_u8] })
-          .flatten()
-          .collect::<Vec<u8>>();
-      let pixels_range = &pixels[0..display_height * display_width * 3];
-      let color_image = egui::ColorImage::from_rgb([display_width, display_height], pixels_range);
-      let handle = ctx.load_texture("Display", color_image, egui::TextureOptions::NEAREST);
+      let turbo_held = ctx.input(|i| i.key_down(egui::Key::Tab)) || TURBO_HELD.load(Ordering::Relaxed);
+      self.emulator.set_turbo_held(turbo_held);
+
+      // Run the interpreter at a fixed 60 Hz, scaled by the speed setting and turbo key, with keys
+      // held on either the keyboard or the in-world keypad, and render the display to a texture for egui
+      let mut input = (&mut self.keyboard, *KEYPAD_STATE.lock().unwrap());
+      self.emulator.update(Instant::now(), &mut input, &mut self.audio);
+      self.emulator.present(&mut self.display);
+      POLLED_KEYS.store(self.emulator.chip8().polled_keys(), Ordering::Relaxed);
 
       // Draw main window
       egui::CentralPanel::default().frame(egui::Frame::none()).show(ctx, |ui| {
-          let sized_image = egui::load::SizedTexture::new(handle.id(), egui::vec2(640.0, 320.0));
-          let image = egui::Image::from_texture(sized_image);
-          ui.add(image);
-      });
+          let response = self.display.show(ui, egui::vec2(640.0, 320.0));
 
-      // Handle input
-      let state = KEYPAD_STATE.lock().unwrap().clone();
-      for (key, value) in [
-          (Key::Num1, 0),
-          (Key::Num2, 1),
-          (Key::Num3, 2),
-          (Key::Num4, 3),
-          (Key::Q, 4),
-          (Key::W, 5),
-          (Key::E, 6),
-          (Key::R, 7),
-          (Key::A, 8),
-          (Key::S, 9),
-          (Key::D, 10),
-          (Key::F, 11),
-          (Key::Z, 12),
-          (Key::X, 13),
-          (Key::C, 14),
-          (Key::V, 15),
-      ] {
-          if ctx.input(|i| i.key_down(key)) || state[value as usize] {
-              self.chip8.set_keypad_state(value, true);
-          } else if ctx.input(|i| i.key_released(key)) || !state[value as usize] {
-              self.chip8.set_keypad_state(value, false);
+          if self.emulator.chip8().is_halted() {
+              draw_program_ended_overlay(ui, response.rect);
           }
-      }
+      });
   }
 }
 
 #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
 pub fn load_rom(bytes: Vec<u8>) {
+  *ROM_HASH.lock().unwrap() = format!("{:016x}", rom_hash(&bytes));
+  CHEATS_TEXT.lock().unwrap().clear();
   ROM_BYTES.lock().unwrap().clear();
   ROM_BYTES.lock().unwrap().extend_from_slice(&bytes);
   ROM_CHANGED.store(true, Ordering::Relaxed);
@@ -165,4 +243,156 @@ pub fn load_rom(bytes: Vec<u8>) {
 #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
 pub fn set_keypad_state(keypad: u8, state: bool) {
   KEYPAD_STATE.lock().unwrap()[keypad as usize] = state;
-}
\ No newline at end of file
+}
+
+/// The keys the ROM has read in roughly the last second, as a bitmask with bit N for key value N
+/// (not keypad index), for highlighting only the keys that matter
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+pub fn get_polled_keys() -> u16 {
+  POLLED_KEYS.load(Ordering::Relaxed)
+}
+
+/// Set how many instructions run per frame, remembered for the current ROM
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+pub fn set_ipf(ipf: u32) {
+  *PENDING_IPF.lock().unwrap() = Some(ipf);
+}
+
+/// Set the emulation speed, e.g. 0.25 or 0.5 for slow motion
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+pub fn set_speed(speed: f32) {
+  *PENDING_SPEED.lock().unwrap() = Some(speed);
+}
+
+/// Set how much faster emulation runs while turbo is held
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+pub fn set_turbo_multiplier(multiplier: f32) {
+  *PENDING_TURBO_MULTIPLIER.lock().unwrap() = Some(multiplier);
+}
+
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+pub fn set_turbo_held(held: bool) {
+  TURBO_HELD.store(held, Ordering::Relaxed);
+}
+
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+pub fn set_paused(paused: bool) {
+  PAUSED.store(paused, Ordering::Relaxed);
+}
+
+/// Run a single frame, only while paused
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+pub fn frame_advance() {
+  FRAME_ADVANCE.store(true, Ordering::Relaxed);
+}
+
+/// Restart the ROM and record input on every frame until `stop_movie_recording` is called
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+pub fn start_movie_recording() {
+  START_RECORDING.store(true, Ordering::Relaxed);
+}
+
+/// Stop recording, the movie is available from `take_recorded_movie` on the next frame
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+pub fn stop_movie_recording() {
+  STOP_RECORDING.store(true, Ordering::Relaxed);
+}
+
+/// Get the bytes of the last recorded movie, empty if none is ready yet
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+pub fn take_recorded_movie() -> Vec<u8> {
+  std::mem::take(&mut *RECORDED_MOVIE.lock().unwrap())
+}
+
+/// Replay a movie recorded for the currently loaded ROM
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+pub fn play_movie(bytes: Vec<u8>) {
+  *PENDING_MOVIE.lock().unwrap() = Some(bytes);
+}
+
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+pub fn stop_movie() {
+  STOP_MOVIE.store(true, Ordering::Relaxed);
+}
+
+/// Set how the buzzer sounds: its frequency in Hz, the fraction of each period spent high, its
+/// volume from 0 to 1 and how many milliseconds it takes to fade in and out
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+pub fn set_tone(frequency: f32, duty: f32, volume: f32, attack_ms: f32, release_ms: f32) {
+  *PENDING_TONE.lock().unwrap() = Some(Tone::new(frequency, duty, volume, attack_ms, release_ms));
+}
+
+/// Set the display colors, either a preset name such as `Amber` or `Octo LCD`, or up to four
+/// `#RRGGBB` colors separated by commas for the background, foreground, XO-CHIP's second plane
+/// and both planes. The palette is remembered for the loaded ROM until the page is closed.
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+pub fn set_palette(palette: String) {
+  match Palette::parse(&palette) {
+    Some(palette) => *PENDING_PALETTE.lock().unwrap() = Some(palette),
+    None => log::error!("Invalid palette: {}", palette),
+  }
+}
+
+/// The current display colors as of the last frame, in the form `set_palette` takes
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+pub fn get_palette() -> String {
+  PALETTE_TEXT.lock().unwrap().clone()
+}
+
+/// Choose how sprite flicker is hidden: `off`, `blend` to combine the last few frames, `decay`
+/// to fade pixels out like a phosphor screen, or `vblank` to only show finished frames
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+pub fn set_flicker_reduction(mode: String) {
+  match FlickerMode::from_name(&mode) {
+    Some(mode) => *PENDING_FLICKER_MODE.lock().unwrap() = Some(mode),
+    None => log::error!("Unknown flicker reduction mode: {}", mode),
+  }
+}
+
+/// How many frames `blend` flicker reduction combines, at least 2
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+pub fn set_blend_frames(frames: u32) {
+  *PENDING_BLEND_FRAMES.lock().unwrap() = Some(frames as usize);
+}
+
+/// Hash of the last loaded ROM as hex, for storing per-ROM data such as cheats
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+pub fn get_rom_hash() -> String {
+  ROM_HASH.lock().unwrap().clone()
+}
+
+/// Start a new cheat search with all of memory and the registers as candidates
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+pub fn cheat_search_new() {
+  CHEAT_COMMANDS.lock().unwrap().push(CheatCommand::NewSearch);
+}
+
+/// Narrow down the cheat search. `condition` is one of equal, greater, less, changed, unchanged,
+/// increased or decreased, and `value` is only used by the first three.
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+pub fn cheat_search_filter(condition: String, value: u8) {
+  match SearchCondition::parse(&condition, value) {
+    Some(condition) => CHEAT_COMMANDS.lock().unwrap().push(CheatCommand::Filter(condition)),
+    None => log::error!("Unknown cheat search condition: {}", condition),
+  }
+}
+
+/// The remaining cheat search candidates as of the last frame, one `<target> <value>` per line.
+/// Empty until the search has been narrowed down to 200 candidates or fewer.
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+pub fn cheat_search_results() -> String {
+  CHEAT_RESULTS.lock().unwrap().clone()
+}
+
+/// Replace the frozen values, one `<enabled 0/1> <target> <hex value> <name>` per line,
+/// e.g. `1 V3 0x09 Infinite lives`. Cheats are cleared whenever a ROM is loaded.
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+pub fn set_cheats(cheats: String) {
+  *CHEATS_TEXT.lock().unwrap() = cheats.clone();
+  CHEAT_COMMANDS.lock().unwrap().push(CheatCommand::SetCheats(cheats));
+}
+
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+pub fn get_cheats() -> String {
+  CHEATS_TEXT.lock().unwrap().clone()
+}
@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+
+use eframe::egui;
+use egui::{Key, KeyboardShortcut, Modifiers};
+use muda::{accelerator::{self, Accelerator, Code}, CheckMenuItem, IsMenuItem, Menu, MenuId, MenuItem, PredefinedMenuItem, Submenu};
+#[cfg(target_os = "windows")]
+use winit::raw_window_handle::{HasWindowHandle, RawWindowHandle};
+
+use crate::chip8::Variant;
+use crate::frontend::capture::CAPTURE_SCALES;
+use crate::frontend::flicker::{FlickerMode, BLEND_FRAME_PRESETS};
+use crate::frontend::palette::Palette;
+use crate::frontend::{IPF_PRESETS, SPEED_PRESETS, TURBO_PRESETS};
+
+/// One entry in the menu bar. The menu is described once with these, then either attached to
+/// the window as the platform's native menu or drawn inside it by egui.
+pub enum MenuEntry {
+  /// Sends its id to `handle_menu_item` when clicked
+  Item { id: String, label: String, shortcut: Option<KeyboardShortcut> },
+  /// An item with a checkmark, shown checked whenever the app says its id is
+  Check { id: String, label: String, shortcut: Option<KeyboardShortcut> },
+  Separator,
+  Submenu { label: String, entries: Vec<MenuEntry> },
+  /// A submenu whose entries change while running, e.g. File → Recent, filled in by the app by id
+  Dynamic { id: String, label: String },
+}
+
+impl MenuEntry {
+  pub fn item(id: impl Into<String>, label: impl Into<String>) -> MenuEntry {
+    MenuEntry::Item { id: id.into(), label: label.into(), shortcut: None }
+  }
+
+  fn check(id: impl Into<String>, label: impl Into<String>) -> MenuEntry {
+    MenuEntry::Check { id: id.into(), label: label.into(), shortcut: None }
+  }
+
+  fn submenu(label: &str, entries: Vec<MenuEntry>) -> MenuEntry {
+    MenuEntry::Submenu { label: label.to_string(), entries }
+  }
+
+  fn with_shortcut(self, modifiers: Modifiers, key: Key) -> MenuEntry {
+    let shortcut = Some(KeyboardShortcut::new(modifiers, key));
+    match self {
+      MenuEntry::Item { id, label, .. } => MenuEntry::Item { id, label, shortcut },
+      MenuEntry::Check { id, label, .. } => MenuEntry::Check { id, label, shortcut },
+      entry => entry,
+    }
+  }
+}
+
+/// Everything in the menu bar, one submenu per tab
+pub fn menu_model() -> Vec<MenuEntry> {
+  vec![
+    MenuEntry::submenu("File", vec![
+      MenuEntry::item("Load ROM", "Load ROM").with_shortcut(Modifiers::CTRL, Key::O),
+      MenuEntry::Dynamic { id: "Recent".to_string(), label: "Recent".to_string() },
+      MenuEntry::item("Netplay", "Netplay..."),
+      MenuEntry::item("Settings", "Settings..."),
+      MenuEntry::Separator,
+      MenuEntry::item("Quit", "Quit"),
+    ]),
+    MenuEntry::submenu("Emulation", vec![
+      MenuEntry::check("Pause", "Pause").with_shortcut(Modifiers::CTRL, Key::P),
+      MenuEntry::item("Frame Advance", "Frame Advance").with_shortcut(Modifiers::CTRL, Key::Period),
+      MenuEntry::Separator,
+      MenuEntry::item("Soft Reset", "Soft Reset").with_shortcut(Modifiers::CTRL, Key::R),
+      MenuEntry::item("Hard Reset", "Hard Reset").with_shortcut(Modifiers::CTRL | Modifiers::SHIFT, Key::R),
+      MenuEntry::submenu("Variant", Variant::ALL
+        .iter()
+        .map(|variant| MenuEntry::check(format!("Variant:{}", variant.name()), variant.name()))
+        .collect()),
+      MenuEntry::Separator,
+      MenuEntry::submenu("Speed", SPEED_PRESETS
+        .iter()
+        .map(|speed| MenuEntry::check(format!("Speed:{}", speed), format!("{}x", speed)))
+        .collect()),
+      MenuEntry::submenu("Turbo (Hold Tab)", TURBO_PRESETS
+        .iter()
+        .map(|multiplier| MenuEntry::check(format!("Turbo:{}", multiplier), format!("{}x", multiplier)))
+        .collect()),
+      MenuEntry::submenu("Instructions per Frame", IPF_PRESETS
+        .iter()
+        .map(|ipf| MenuEntry::check(format!("IPF:{}", ipf), ipf.to_string()))
+        .collect()),
+    ]),
+    MenuEntry::submenu("Movie", vec![
+      MenuEntry::check("Record Movie", "Record Movie"),
+      MenuEntry::Separator,
+      MenuEntry::item("Play Movie", "Play Movie..."),
+      MenuEntry::item("Stop Movie", "Stop Movie"),
+    ]),
+    MenuEntry::submenu("View", vec![
+      MenuEntry::submenu("Palette", Palette::PRESETS
+        .iter()
+        .map(|(name, _)| MenuEntry::check(format!("Palette:{}", name), *name))
+        .chain([MenuEntry::Separator, MenuEntry::item("Custom Palette", "Custom Palette...")])
+        .collect()),
+      MenuEntry::submenu("Flicker Reduction", FlickerMode::ALL
+        .iter()
+        .map(|mode| MenuEntry::check(format!("Flicker:{}", mode.name()), mode.label()))
+        .chain([MenuEntry::Separator])
+        .chain(BLEND_FRAME_PRESETS
+          .iter()
+          .map(|frames| MenuEntry::check(format!("BlendFrames:{}", frames), format!("Blend {} Frames", frames))))
+        .collect()),
+      MenuEntry::Separator,
+      MenuEntry::check("Keypad", "On-screen Keypad"),
+    ]),
+    MenuEntry::submenu("Capture", vec![
+      MenuEntry::item("Screenshot", "Save Screenshot...").with_shortcut(Modifiers::NONE, Key::F12),
+      MenuEntry::Separator,
+      MenuEntry::check("Record GIF", "Record GIF"),
+      MenuEntry::check("Record APNG", "Record APNG"),
+      MenuEntry::check("Record WAV", "Record WAV"),
+      MenuEntry::Separator,
+      MenuEntry::submenu("Scale", CAPTURE_SCALES
+        .iter()
+        .map(|scale| MenuEntry::check(format!("CaptureScale:{}", scale), format!("{}x", scale)))
+        .collect()),
+      MenuEntry::submenu("Palette", ["Current"]
+        .into_iter()
+        .chain(Palette::PRESETS.iter().map(|(name, _)| *name))
+        .map(|name| MenuEntry::check(format!("CapturePalette:{}", name), name))
+        .collect()),
+    ]),
+    MenuEntry::submenu("Tools", vec![
+      MenuEntry::item("Memory", "Memory Viewer..."),
+      MenuEntry::item("Disassembly", "Disassembly..."),
+      MenuEntry::item("Profiler", "Profiler..."),
+      MenuEntry::item("Coverage", "Coverage..."),
+      MenuEntry::item("Cheats", "Cheats..."),
+    ]),
+    MenuEntry::submenu("Help", vec![
+      MenuEntry::item("About", "About"),
+    ]),
+  ]
+}
+
+/// The native accelerator for a shortcut, for the keys the menu uses
+fn accelerator(shortcut: &KeyboardShortcut) -> Option<Accelerator> {
+  let code = match shortcut.logical_key {
+    Key::O => Code::KeyO,
+    Key::P => Code::KeyP,
+    Key::R => Code::KeyR,
+    Key::Period => Code::Period,
+    Key::F12 => Code::F12,
+    _ => return None,
+  };
+  let modifiers = match (shortcut.modifiers.ctrl, shortcut.modifiers.shift) {
+    (true, true) => Some(accelerator::Modifiers::CONTROL | accelerator::Modifiers::SHIFT),
+    (true, false) => Some(accelerator::Modifiers::CONTROL),
+    (false, true) => Some(accelerator::Modifiers::SHIFT),
+    (false, false) => None,
+  };
+  Some(Accelerator::new(modifiers, code))
+}
+
+/// The menu model built as a native menu, which can be attached to the window on Windows and macOS
+pub struct NativeMenu {
+  menu: Menu,
+  items: HashMap<MenuId, String>,
+  checks: Vec<(String, CheckMenuItem)>,
+  dynamic: HashMap<String, Submenu>,
+}
+
+impl NativeMenu {
+  pub fn new(entries: &[MenuEntry]) -> NativeMenu {
+    let mut native = Self {
+      menu: Menu::new(),
+      items: HashMap::new(),
+      checks: Vec::new(),
+      dynamic: HashMap::new(),
+    };
+    for entry in entries {
+      let item = native.build(entry);
+      native.menu.append(item.as_ref()).unwrap();
+    }
+    native
+  }
+
+  /// Attach the menu to the window, which only does anything on Windows and macOS
+  pub fn attach(&self, _frame: &eframe::Frame) {
+    #[cfg(target_os = "windows")]
+    {
+      let handle = _frame.window_handle().unwrap().as_raw();
+      let hwnd = match handle {
+        RawWindowHandle::Win32(handle) => handle.hwnd.get(),
+        _ => panic!("Cannot handle other platform window handles yet!"),
+      };
+      self.menu.init_for_hwnd(hwnd).unwrap();
+    }
+    #[cfg(target_os = "macos")]
+    {
+      self.menu.init_for_nsapp();
+    }
+  }
+
+  /// The id of a native menu item that was clicked
+  pub fn item_id(&self, id: &MenuId) -> Option<&str> {
+    self.items.get(id).map(String::as_str)
+  }
+
+  pub fn sync_checks(&self, is_checked: impl Fn(&str) -> bool) {
+    for (id, check) in &self.checks {
+      check.set_checked(is_checked(id));
+    }
+  }
+
+  /// Replace the entries of a dynamic submenu, disabling it when there are none
+  pub fn set_dynamic(&mut self, id: &str, entries: &[MenuEntry]) {
+    let submenu = match self.dynamic.get(id) {
+      Some(submenu) => submenu.clone(),
+      None => return,
+    };
+    while let Some(item) = submenu.remove_at(0) {
+      self.items.remove(item.id());
+      self.checks.retain(|(_, check)| check.id() != item.id());
+    }
+    for entry in entries {
+      let item = self.build(entry);
+      submenu.append(item.as_ref()).unwrap();
+    }
+    submenu.set_enabled(!entries.is_empty());
+  }
+
+  fn build(&mut self, entry: &MenuEntry) -> Box<dyn IsMenuItem> {
+    match entry {
+      MenuEntry::Item { id, label, shortcut } => {
+        let item = MenuItem::new(label, true, shortcut.as_ref().and_then(accelerator));
+        self.items.insert(item.id().clone(), id.clone());
+        Box::new(item)
+      },
+      MenuEntry::Check { id, label, shortcut } => {
+        let item = CheckMenuItem::new(label, true, false, shortcut.as_ref().and_then(accelerator));
+        self.items.insert(item.id().clone(), id.clone());
+        self.checks.push((id.clone(), item.clone()));
+        Box::new(item)
+      },
+      MenuEntry::Separator => Box::new(PredefinedMenuItem::separator()),
+      MenuEntry::Submenu { label, entries } => {
+        let submenu = Submenu::new(label, true);
+        for entry in entries {
+          let item = self.build(entry);
+          submenu.append(item.as_ref()).unwrap();
+        }
+        Box::new(submenu)
+      },
+      MenuEntry::Dynamic { id, label } => {
+        let submenu = Submenu::new(label, false);
+        self.dynamic.insert(id.clone(), submenu.clone());
+        Box::new(submenu)
+      },
+    }
+  }
+}
+
+/// Draw the menu model as an egui menu bar, for platforms without a native menu to attach.
+/// Returns the id of the item clicked this frame, if any.
+pub fn show_menu_bar(
+  ui: &mut egui::Ui,
+  entries: &[MenuEntry],
+  is_checked: &dyn Fn(&str) -> bool,
+  dynamic: &dyn Fn(&str) -> Vec<MenuEntry>,
+) -> Option<String> {
+  let mut clicked = None;
+  egui::menu::bar(ui, |ui| {
+    for entry in entries {
+      show_entry(ui, entry, is_checked, dynamic, &mut clicked);
+    }
+  });
+  clicked
+}
+
+fn show_entry(
+  ui: &mut egui::Ui,
+  entry: &MenuEntry,
+  is_checked: &dyn Fn(&str) -> bool,
+  dynamic: &dyn Fn(&str) -> Vec<MenuEntry>,
+  clicked: &mut Option<String>,
+) {
+  match entry {
+    MenuEntry::Item { id, label, shortcut } | MenuEntry::Check { id, label, shortcut } => {
+      let mut button = egui::Button::new(label);
+      if let Some(shortcut) = shortcut {
+        button = button.shortcut_text(ui.ctx().format_shortcut(shortcut));
+      }
+      if matches!(entry, MenuEntry::Check { .. }) {
+        button = button.selected(is_checked(id));
+      }
+      if ui.add(button).clicked() {
+        *clicked = Some(id.clone());
+        ui.close_menu();
+      }
+    },
+    MenuEntry::Separator => {
+      ui.separator();
+    },
+    MenuEntry::Submenu { label, entries } => {
+      ui.menu_button(label, |ui| {
+        for entry in entries {
+          show_entry(ui, entry, is_checked, dynamic, clicked);
+        }
+      });
+    },
+    MenuEntry::Dynamic { id, label } => {
+      let entries = dynamic(id);
+      if entries.is_empty() {
+        ui.add_enabled(false, egui::Button::new(label));
+      } else {
+        ui.menu_button(label, |ui| {
+          for entry in &entries {
+            show_entry(ui, entry, is_checked, dynamic, clicked);
+          }
+        });
+      }
+    },
+  }
+}
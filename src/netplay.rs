@@ -0,0 +1,501 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+
+use crate::chip8::Variant;
+use crate::frontend::movie::{keypad_from_bits, keypad_to_bits};
+use crate::frontend::{AudioSink, Emulator, InputSource};
+
+/// Identifies a SILK-8 netplay packet, followed by the protocol version
+const MAGIC: &[u8; 4] = b"S8N\x01";
+const PACKET_HELLO: u8 = 0;
+const PACKET_INPUT: u8 = 1;
+/// How many recent frames of input every packet repeats, so a lost packet doesn't stall the game
+const REDUNDANT_FRAMES: u32 = 16;
+/// Marks an input packet that doesn't carry a state hash yet
+const NO_HASH: u32 = u32::MAX;
+/// The most frames a session will owe after waiting on the peer, half a second at normal speed,
+/// so a long stall isn't followed by a long burst of fast-forward
+const MAX_OWED_FRAMES: u32 = 30;
+
+/// Keypad indices owned by player 1, the left two columns of the COSMAC keypad (1 2 4 5 7 8 A 0).
+/// Player 2 owns the rest, so e.g. Pong's 1/4 and C/D paddles end up on separate machines.
+pub const PLAYER_1_KEYS: u16 = 0b0011_0011_0011_0011;
+pub const PLAYER_2_KEYS: u16 = !PLAYER_1_KEYS;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum NetplayStatus {
+  /// Waiting for the peer to agree on the ROM and settings
+  Connecting,
+  Running,
+  /// The two interpreters no longer match, as of the given frame
+  Desynced(u32),
+  /// The peer is running a different ROM
+  RomMismatch,
+}
+
+/// The settings both instances must share to run in lockstep
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct SessionSettings {
+  rom_hash: u64,
+  seed: u64,
+  variant: Variant,
+  ipf: u32,
+}
+
+/// Lockstep input exchange with one other instance over UDP.
+///
+/// Each instance owns a subset of the keypad. Local input is delayed by a fixed number of frames
+/// and sent to the peer ahead of time, and a frame only runs once the input of both players for
+/// it is known. After each frame both sides exchange a hash of the interpreter state to catch
+/// desyncs. Player 1 decides the variant, RNG seed and speed, and player 2 restarts with them.
+pub struct NetplaySession {
+  socket: UdpSocket,
+  peer: SocketAddr,
+  is_player_1: bool,
+  owned_keys: u16,
+  delay: u32,
+  status: NetplayStatus,
+  settings: Option<SessionSettings>,
+  frame: u32,
+  owed_frames: u32,
+  local_inputs: HashMap<u32, u16>,
+  remote_inputs: HashMap<u32, u16>,
+  local_hashes: HashMap<u32, u64>,
+  remote_hashes: HashMap<u32, u64>,
+  last_hash_frame: u32,
+  last_hash: u64,
+}
+
+impl NetplaySession {
+  /// Bind to `local` and play with the instance at `peer`, delaying local input by `delay` frames
+  pub fn new(local: SocketAddr, peer: SocketAddr, is_player_1: bool, delay: u32) -> io::Result<NetplaySession> {
+    Self::with_socket(UdpSocket::bind(local)?, peer, is_player_1, delay)
+  }
+
+  /// Play with the instance at `peer` over a socket that's already bound
+  pub fn with_socket(socket: UdpSocket, peer: SocketAddr, is_player_1: bool, delay: u32) -> io::Result<NetplaySession> {
+    socket.set_nonblocking(true)?;
+
+    Ok(Self {
+      socket,
+      peer,
+      is_player_1,
+      owned_keys: if is_player_1 { PLAYER_1_KEYS } else { PLAYER_2_KEYS },
+      delay,
+      status: NetplayStatus::Connecting,
+      settings: None,
+      frame: 0,
+      owed_frames: 0,
+      local_inputs: HashMap::new(),
+      remote_inputs: HashMap::new(),
+      local_hashes: HashMap::new(),
+      remote_hashes: HashMap::new(),
+      last_hash_frame: NO_HASH,
+      last_hash: 0,
+    })
+  }
+
+  pub fn status(&self) -> NetplayStatus {
+    self.status
+  }
+
+  /// The next frame to run
+  pub fn frame(&self) -> u32 {
+    self.frame
+  }
+
+  /// Run the `due` frames the scheduler asked for along with any still owed from earlier calls
+  /// that had to wait on the peer, so the game catches up once the peer's input arrives
+  pub fn run_frames(&mut self, due: u32, emulator: &mut Emulator, input: &mut impl InputSource, audio: &mut impl AudioSink) -> io::Result<()> {
+    self.owed_frames = (self.owed_frames + due).min(MAX_OWED_FRAMES);
+    while self.owed_frames > 0 && self.advance(emulator, input, audio)? {
+      self.owed_frames -= 1;
+    }
+    if self.status != NetplayStatus::Running {
+      // Time spent connecting or stopped by a desync isn't owed to anyone
+      self.owed_frames = 0;
+    }
+    Ok(())
+  }
+
+  /// Exchange packets with the peer and run the next frame if both players' input for it is known.
+  /// Returns whether a frame ran, `false` meaning the game has to wait for the peer.
+  pub fn advance(&mut self, emulator: &mut Emulator, input: &mut impl InputSource, audio: &mut impl AudioSink) -> io::Result<bool> {
+    if self.settings.is_none() && self.is_player_1 {
+      if let Some(rom_hash) = emulator.rom_hash() {
+        // Player 1 restarts too, so both sides begin from the very first frame
+        let settings = SessionSettings {
+          rom_hash,
+          seed: emulator.chip8().get_seed(),
+          variant: emulator.variant(),
+          ipf: emulator.ipf(),
+        };
+        emulator.restart_with(settings.variant, settings.seed, settings.ipf);
+        self.settings = Some(settings);
+      }
+    }
+
+    self.receive(emulator)?;
+
+    if self.status != NetplayStatus::Running {
+      self.send_hello()?;
+      return Ok(false);
+    }
+
+    // Queue up this player's input for the frame `delay` frames from now
+    let input_frame = self.frame + self.delay;
+    if !self.local_inputs.contains_key(&input_frame) {
+      let keypad = keypad_to_bits(&input.keypad_state()) & self.owned_keys;
+      self.local_inputs.insert(input_frame, keypad);
+    }
+    self.send_input()?;
+
+    let local = self.local_input(self.frame);
+    let remote = match self.remote_input(self.frame) {
+      Some(remote) => remote,
+      None => return Ok(false),
+    };
+
+    let mut keypad = keypad_from_bits(local | remote);
+    emulator.run_frame(&mut keypad, audio);
+
+    self.last_hash = emulator.chip8().state_hash();
+    self.last_hash_frame = self.frame;
+    self.local_hashes.insert(self.frame, self.last_hash);
+    self.check_hashes();
+    self.frame += 1;
+    self.forget_old_frames();
+
+    Ok(true)
+  }
+
+  fn local_input(&self, frame: u32) -> u16 {
+    // Nobody can have pressed anything during the initial delay
+    if frame < self.delay { 0 } else { self.local_inputs[&frame] }
+  }
+
+  fn remote_input(&self, frame: u32) -> Option<u16> {
+    if frame < self.delay {
+      Some(0)
+    } else {
+      self.remote_inputs.get(&frame).map(|keys| keys & !self.owned_keys)
+    }
+  }
+
+  fn check_hashes(&mut self) {
+    let frames = self.remote_hashes.keys().copied().collect::<Vec<_>>();
+    for frame in frames {
+      if let Some(local) = self.local_hashes.get(&frame) {
+        if *local != self.remote_hashes[&frame] {
+          self.status = NetplayStatus::Desynced(frame);
+          return;
+        }
+        self.local_hashes.remove(&frame);
+        self.remote_hashes.remove(&frame);
+      }
+    }
+  }
+
+  fn forget_old_frames(&mut self) {
+    let oldest = self.frame.saturating_sub(REDUNDANT_FRAMES * 4);
+    self.local_inputs.retain(|frame, _| *frame >= oldest);
+    self.remote_inputs.retain(|frame, _| *frame >= self.frame);
+    self.local_hashes.retain(|frame, _| *frame >= oldest);
+    self.remote_hashes.retain(|frame, _| *frame >= oldest);
+  }
+
+  fn receive(&mut self, emulator: &mut Emulator) -> io::Result<()> {
+    let mut buf = [0_u8; 512];
+    loop {
+      let (len, from) = match self.socket.recv_from(&mut buf) {
+        Ok(received) => received,
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+        // Windows reports an ICMP port unreachable as an error when the peer isn't up yet
+        Err(e) if e.kind() == io::ErrorKind::ConnectionReset => continue,
+        Err(e) => return Err(e),
+      };
+      if from != self.peer || len < 5 || &buf[0..4] != MAGIC {
+        continue;
+      }
+
+      match buf[4] {
+        PACKET_HELLO => self.handle_hello(&buf[5..len], emulator),
+        PACKET_INPUT => self.handle_input(&buf[5..len]),
+        _ => {}
+      }
+    }
+  }
+
+  fn handle_hello(&mut self, body: &[u8], emulator: &mut Emulator) {
+    if body.len() < 21 {
+      return;
+    }
+    let variant = match Variant::ALL.get(body[16] as usize) {
+      Some(variant) => *variant,
+      None => return,
+    };
+    let settings = SessionSettings {
+      rom_hash: u64::from_le_bytes(body[0..8].try_into().unwrap()),
+      seed: u64::from_le_bytes(body[8..16].try_into().unwrap()),
+      variant,
+      ipf: u32::from_le_bytes(body[17..21].try_into().unwrap()),
+    };
+
+    if emulator.rom_hash() != Some(settings.rom_hash) {
+      self.status = NetplayStatus::RomMismatch;
+      return;
+    }
+
+    if self.is_player_1 {
+      // Player 2 echoes our settings back once it has restarted with them
+      if self.status == NetplayStatus::Connecting && self.settings == Some(settings) {
+        self.status = NetplayStatus::Running;
+      }
+    } else if self.settings.is_none() {
+      emulator.restart_with(settings.variant, settings.seed, settings.ipf);
+      self.settings = Some(settings);
+      self.status = NetplayStatus::Running;
+    }
+  }
+
+  fn handle_input(&mut self, body: &[u8]) {
+    if body.len() < 5 {
+      return;
+    }
+    let first_frame = u32::from_le_bytes(body[0..4].try_into().unwrap());
+    let count = body[4] as usize;
+    let hash_start = 5 + count * 2;
+    if body.len() < hash_start + 12 {
+      return;
+    }
+
+    for (index, keys) in body[5..hash_start].chunks_exact(2).enumerate() {
+      let frame = first_frame + index as u32;
+      if frame >= self.frame {
+        self.remote_inputs.insert(frame, u16::from_le_bytes([keys[0], keys[1]]));
+      }
+    }
+
+    let hash_frame = u32::from_le_bytes(body[hash_start..hash_start + 4].try_into().unwrap());
+    if hash_frame != NO_HASH {
+      let hash = u64::from_le_bytes(body[hash_start + 4..hash_start + 12].try_into().unwrap());
+      self.remote_hashes.insert(hash_frame, hash);
+    }
+  }
+
+  fn send_hello(&self) -> io::Result<()> {
+    // Player 2 has nothing to say until it has heard player 1's settings
+    let settings = match self.settings {
+      Some(settings) => settings,
+      None => return Ok(()),
+    };
+    let mut packet = Vec::with_capacity(26);
+    packet.extend_from_slice(MAGIC);
+    packet.push(PACKET_HELLO);
+    packet.extend_from_slice(&settings.rom_hash.to_le_bytes());
+    packet.extend_from_slice(&settings.seed.to_le_bytes());
+    packet.push(Variant::ALL.iter().position(|v| *v == settings.variant).unwrap() as u8);
+    packet.extend_from_slice(&settings.ipf.to_le_bytes());
+    self.send(&packet)
+  }
+
+  fn send_input(&self) -> io::Result<()> {
+    // Player 1 may not have seen player 2's echo yet, so keep repeating the settings
+    if !self.is_player_1 {
+      self.send_hello()?;
+    }
+
+    let last_frame = self.frame + self.delay;
+    let first_frame = last_frame.saturating_sub(REDUNDANT_FRAMES - 1).max(self.delay);
+    let mut packet = Vec::with_capacity(10 + REDUNDANT_FRAMES as usize * 2 + 12);
+    packet.extend_from_slice(MAGIC);
+    packet.push(PACKET_INPUT);
+    packet.extend_from_slice(&first_frame.to_le_bytes());
+    packet.push((last_frame + 1 - first_frame) as u8);
+    for frame in first_frame..=last_frame {
+      packet.extend_from_slice(&self.local_inputs[&frame].to_le_bytes());
+    }
+    packet.extend_from_slice(&self.last_hash_frame.to_le_bytes());
+    packet.extend_from_slice(&self.last_hash.to_le_bytes());
+    self.send(&packet)
+  }
+
+  fn send(&self, packet: &[u8]) -> io::Result<()> {
+    match self.socket.send_to(packet, self.peer) {
+      Ok(_) => Ok(()),
+      // The peer may not be listening yet, the packet will be sent again next frame
+      Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset) => Ok(()),
+      Err(e) => Err(e),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::net::Ipv4Addr;
+  use std::time::{Duration, Instant};
+
+  use super::*;
+  use crate::headless::{HeadlessDisplay, NullAudio};
+
+  /// Rolls a random number every loop and counts the loops run while keys 5 (player 1's) and 3
+  /// (player 2's) are up, so both the seed and the input of either player feed the state hash
+  const ROM: [u8; 16] = [
+    0x61, 0x05, // v1 := 5
+    0x63, 0x03, // v3 := 3
+    0xC0, 0xFF, // v0 := random 0xFF
+    0xE1, 0x9E, // if v1 -key then
+    0x72, 0x01, //   v2 += 1
+    0xE3, 0x9E, // if v3 -key then
+    0x74, 0x01, //   v4 += 1
+    0x12, 0x04, // jump 0x204
+  ];
+  const FRAMES: usize = 120;
+  const DELAY: u32 = 2;
+  /// The keys each player holds every third frame, as COSMAC layout indices: 5 and 3
+  const PLAYER_1_KEY: usize = 5;
+  const PLAYER_2_KEY: usize = 2;
+
+  struct Player {
+    session: NetplaySession,
+    emulator: Emulator,
+    display: HeadlessDisplay,
+    audio: NullAudio,
+    hashes: Vec<u64>,
+  }
+
+  impl Player {
+    fn new(socket: UdpSocket, peer: SocketAddr, is_player_1: bool, seed: u64) -> Player {
+      let mut emulator = Emulator::new(Variant::CHIP8);
+      emulator.load_rom(ROM.to_vec());
+      emulator.chip8_mut().set_seed(seed);
+      Self {
+        session: NetplaySession::with_socket(socket, peer, is_player_1, DELAY).unwrap(),
+        emulator,
+        display: HeadlessDisplay::default(),
+        audio: NullAudio::default(),
+        hashes: Vec::new(),
+      }
+    }
+
+    /// Try to run a frame, holding `key` (a COSMAC layout index) every third frame
+    fn advance(&mut self, key: usize) -> bool {
+      let mut input = [false; 16];
+      input[key] = self.session.frame().is_multiple_of(3);
+      let ran = self.session.advance(&mut self.emulator, &mut input, &mut self.audio).unwrap();
+      if ran {
+        self.emulator.present(&mut self.display);
+        self.hashes.push(self.emulator.chip8().state_hash());
+      }
+      ran
+    }
+  }
+
+  /// Two sessions talking over loopback, with ports picked by the OS
+  fn connect() -> (Player, Player) {
+    let socket_1 = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let socket_2 = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let (addr_1, addr_2) = (socket_1.local_addr().unwrap(), socket_2.local_addr().unwrap());
+    // Player 2 starts with another seed, which it should replace with player 1's
+    (Player::new(socket_1, addr_2, true, 1), Player::new(socket_2, addr_1, false, 2))
+  }
+
+  /// Advance both players until `done` holds, failing if that takes too long
+  fn run_until(player_1: &mut Player, player_2: &mut Player, mut done: impl FnMut(&Player, &Player) -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !done(player_1, player_2) {
+      assert!(Instant::now() < deadline, "timed out at frames {} and {}", player_1.hashes.len(), player_2.hashes.len());
+      let ran_1 = player_1.advance(PLAYER_1_KEY);
+      let ran_2 = player_2.advance(PLAYER_2_KEY);
+      if !ran_1 && !ran_2 {
+        std::thread::sleep(Duration::from_millis(1));
+      }
+    }
+  }
+
+  /// The state after each frame of the same game run on one machine holding `keys`, pressed on
+  /// the frames netplay should press them: every third frame, `DELAY` frames after being read
+  fn local_hashes(keys: &[usize]) -> Vec<u64> {
+    let mut emulator = Emulator::new(Variant::CHIP8);
+    emulator.load_rom(ROM.to_vec());
+    let ipf = emulator.ipf();
+    emulator.restart_with(Variant::CHIP8, 1, ipf);
+    (0..FRAMES as u32)
+      .map(|frame| {
+        let mut keypad = [false; 16];
+        for key in keys {
+          keypad[*key] = frame >= DELAY && (frame - DELAY).is_multiple_of(3);
+        }
+        emulator.run_frame(&mut keypad, &mut NullAudio::default());
+        emulator.chip8().state_hash()
+      })
+      .collect()
+  }
+
+  fn desynced_at(player: &Player) -> Option<u32> {
+    match player.session.status() {
+      NetplayStatus::Desynced(frame) => Some(frame),
+      _ => None,
+    }
+  }
+
+  #[test]
+  fn lockstep_over_loopback() {
+    let (mut player_1, mut player_2) = connect();
+    run_until(&mut player_1, &mut player_2, |a, b| {
+      assert_eq!((desynced_at(a), desynced_at(b)), (None, None), "desynced before the seeds diverged");
+      a.hashes.len() >= FRAMES && b.hashes.len() >= FRAMES
+    });
+
+    assert_eq!(player_1.session.status(), NetplayStatus::Running);
+    assert_eq!(player_2.session.status(), NetplayStatus::Running);
+    // Both machines ran the game as if both players were at one keypad, with each other's keys
+    // arriving on time
+    let expected = local_hashes(&[PLAYER_1_KEY, PLAYER_2_KEY]);
+    for (frame, expected) in expected.iter().enumerate() {
+      assert_eq!(player_1.hashes[frame], *expected, "player 1 is off at frame {}", frame);
+      assert_eq!(player_2.hashes[frame], *expected, "player 2 is off at frame {}", frame);
+    }
+    // Which only proves anything if the game noticed each player's key
+    assert_ne!(local_hashes(&[PLAYER_1_KEY])[FRAMES - 1], expected[FRAMES - 1]);
+    assert_ne!(local_hashes(&[PLAYER_2_KEY])[FRAMES - 1], expected[FRAMES - 1]);
+    assert_eq!(player_1.display.rgb, player_2.display.rgb);
+
+    // Knock player 2's RNG out of step, which the hash exchange should catch. Whichever side sees
+    // it first stops, so the other may be left waiting.
+    let seed = player_2.emulator.chip8().get_seed();
+    player_2.emulator.chip8_mut().set_seed(seed + 1);
+    run_until(&mut player_1, &mut player_2, |a, b| desynced_at(a).is_some() || desynced_at(b).is_some());
+
+    for frame in [desynced_at(&player_1), desynced_at(&player_2)].into_iter().flatten() {
+      assert!(frame as usize >= FRAMES, "desync reported at frame {} before the seeds diverged", frame);
+    }
+  }
+
+  #[test]
+  fn frames_owed_while_waiting_run_once_the_peer_catches_up() {
+    let (mut player_1, mut player_2) = connect();
+    run_until(&mut player_1, &mut player_2, |a, b| a.hashes.len() >= 10 && b.hashes.len() >= 10);
+
+    // Player 1 is asked for more frames than player 2's input covers
+    let start = player_1.session.frame();
+    let run_frames = |player: &mut Player, due: u32| {
+      player.session.run_frames(due, &mut player.emulator, &mut [false; 16], &mut player.audio).unwrap();
+      player.session.frame()
+    };
+    assert!(run_frames(&mut player_1, 20) < start + 20);
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while run_frames(&mut player_1, 0) < start + 20 {
+      assert!(Instant::now() < deadline, "player 1 stuck at frame {}", player_1.session.frame());
+      player_2.advance(PLAYER_2_KEY);
+      std::thread::sleep(Duration::from_millis(1));
+    }
+    // And no more than it was asked for
+    for _ in 0..10 {
+      player_2.advance(PLAYER_2_KEY);
+      assert_eq!(run_frames(&mut player_1, 0), start + 20);
+    }
+  }
+}
@@ -0,0 +1,1052 @@
+use std::collections::HashMap;
+
+/// Where programs are assembled to and start running
+const PROGRAM_START: usize = 0x200;
+/// Most macro and string mode expansions in one program, to catch macros that expand forever
+const MAX_EXPANSIONS: usize = 100_000;
+
+#[derive(Clone, Debug)]
+struct Token {
+  text: String,
+  line: usize,
+}
+
+/// How a reference to a name that isn't defined yet is filled in once it is
+#[derive(Copy, Clone, Debug)]
+enum Fixup {
+  /// The low 12 bits of an instruction, e.g. 1NNN
+  Addr12,
+  /// A whole 16-bit word, e.g. after F000
+  Addr16,
+  /// The low byte of `6XNN`, holding a nibble and the top 4 bits of the address
+  UnpackNibble(u8),
+  /// The low byte of `6XNN`, holding the top 8 bits of the address
+  UnpackHigh,
+  /// The low byte of `6XNN`, holding the bottom 8 bits of the address
+  UnpackLow,
+}
+
+struct Reference {
+  addr: usize,
+  fixup: Fixup,
+  name: String,
+  line: usize,
+}
+
+struct Macro {
+  args: Vec<String>,
+  body: Vec<Token>,
+  calls: usize,
+}
+
+/// An open `if ... begin` or `loop`, waiting for its `else`, `end` or `again`
+enum Block {
+  If { jump: usize },
+  Else { jump: usize },
+  Loop { start: usize, breaks: Vec<usize> },
+}
+
+/// What a condition compares a register against
+enum Operand {
+  Register(u8),
+  Value(u8),
+}
+
+/// Assemble Octo source into a ROM to load at 0x200.
+///
+/// Supports the Octo language including `:macro`, `:calc`, `:stringmode`, structured
+/// `if`/`loop`/`while` and the XO-CHIP instructions. Errors give the line they were found on.
+pub fn assemble(source: &str) -> Result<Vec<u8>, String> {
+  let mut assembler = Assembler::new(tokenize(source)?);
+  while assembler.pos < assembler.tokens.len() {
+    let line = assembler.tokens[assembler.pos].line;
+    assembler.statement().map_err(|err| format!("Line {}: {}", line, err))?;
+  }
+  assembler.finish()
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+  let mut tokens = Vec::new();
+  for (index, line) in source.lines().enumerate() {
+    let mut chars = line.chars().peekable();
+    loop {
+      while chars.next_if(|c| c.is_whitespace()).is_some() {}
+      let first = match chars.next() {
+        Some('#') | None => break,
+        Some(c) => c,
+      };
+      let mut text = first.to_string();
+      if first == '"' {
+        // Strings keep their quotes and spaces, and end at the next unescaped quote
+        loop {
+          match chars.next() {
+            Some('\\') => text.push(chars.next().unwrap_or('\\')),
+            Some('"') => break,
+            Some(c) => text.push(c),
+            None => return Err(format!("Line {}: Unterminated string", index + 1)),
+          }
+        }
+        text.push('"');
+      } else {
+        while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+          text.push(c);
+        }
+      }
+      tokens.push(Token { text, line: index + 1 });
+    }
+  }
+  Ok(tokens)
+}
+
+/// A number as Octo writes them: decimal, `0x` hex or `0b` binary, optionally negative
+fn parse_number(text: &str) -> Option<f64> {
+  let (negative, digits) = match text.strip_prefix('-') {
+    Some(digits) => (true, digits),
+    None => (false, text),
+  };
+  let value = if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+    i64::from_str_radix(hex, 16).ok()? as f64
+  } else if let Some(binary) = digits.strip_prefix("0b").or_else(|| digits.strip_prefix("0B")) {
+    i64::from_str_radix(binary, 2).ok()? as f64
+  } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+    digits.parse::<f64>().ok()?
+  } else {
+    return None;
+  };
+  Some(if negative { -value } else { value })
+}
+
+fn parse_register(text: &str) -> Option<u8> {
+  let digit = text.strip_prefix('v').or_else(|| text.strip_prefix('V'))?;
+  if digit.len() == 1 { u8::from_str_radix(digit, 16).ok() } else { None }
+}
+
+/// The characters between a string token's quotes
+fn string_contents(token: &Token) -> Result<&str, String> {
+  token.text
+    .strip_prefix('"')
+    .and_then(|text| text.strip_suffix('"'))
+    .ok_or_else(|| format!("Expected a string, found '{}'", token.text))
+}
+
+struct Assembler {
+  tokens: Vec<Token>,
+  pos: usize,
+  memory: Vec<u8>,
+  here: usize,
+  /// One past the highest address written
+  end: usize,
+  /// Whether 0x200 is still held for a jump to `main`, which isn't needed if `main` comes first
+  main_jump: bool,
+  labels: HashMap<String, usize>,
+  constants: HashMap<String, f64>,
+  aliases: HashMap<String, u8>,
+  macros: HashMap<String, Macro>,
+  /// The body to expand for each character, for each string mode
+  string_modes: HashMap<String, HashMap<char, (usize, Vec<Token>)>>,
+  references: Vec<Reference>,
+  blocks: Vec<Block>,
+  expansions: usize,
+}
+
+impl Assembler {
+  fn new(tokens: Vec<Token>) -> Assembler {
+    Self {
+      tokens,
+      pos: 0,
+      memory: vec![0; 0x10000],
+      here: PROGRAM_START + 2,
+      end: PROGRAM_START + 2,
+      main_jump: true,
+      labels: HashMap::new(),
+      constants: HashMap::new(),
+      aliases: HashMap::new(),
+      macros: HashMap::new(),
+      string_modes: HashMap::new(),
+      references: Vec::new(),
+      blocks: Vec::new(),
+      expansions: 0,
+    }
+  }
+
+  fn next(&mut self) -> Result<Token, String> {
+    let token = self.tokens.get(self.pos).cloned().ok_or("Unexpected end of file")?;
+    self.pos += 1;
+    Ok(token)
+  }
+
+  fn peek(&self) -> Option<&str> {
+    self.tokens.get(self.pos).map(|token| token.text.as_str())
+  }
+
+  fn expect(&mut self, text: &str) -> Result<(), String> {
+    let token = self.next()?;
+    if token.text != text {
+      return Err(format!("Expected '{}', found '{}'", text, token.text));
+    }
+    Ok(())
+  }
+
+  /// A name that's about to be defined, which mustn't clash with anything built in
+  fn new_name(&mut self) -> Result<String, String> {
+    let name = self.next()?.text;
+    if parse_number(&name).is_some() || parse_register(&name).is_some() || name.starts_with([':', '"', '{', '}']) {
+      return Err(format!("'{}' can't be used as a name", name));
+    }
+    Ok(name)
+  }
+
+  fn byte(&mut self, value: u8) -> Result<(), String> {
+    if self.here >= self.memory.len() {
+      return Err("Program is too big for memory".to_string());
+    }
+    self.memory[self.here] = value;
+    self.here += 1;
+    self.end = self.end.max(self.here);
+    Ok(())
+  }
+
+  fn inst(&mut self, word: u16) -> Result<(), String> {
+    self.byte((word >> 8) as u8)?;
+    self.byte(word as u8)
+  }
+
+  /// Point the jump instruction at `at` to `target`
+  fn patch_jump(&mut self, at: usize, target: usize) {
+    self.memory[at] = 0x10 | ((target >> 8) & 0xF) as u8;
+    self.memory[at + 1] = target as u8;
+  }
+
+  fn fill(&mut self, addr: usize, fixup: Fixup, value: usize) -> Result<(), String> {
+    match fixup {
+      Fixup::Addr12 => {
+        if value > 0xFFF {
+          return Err(format!("Address 0x{:X} is out of range for a 12-bit operand", value));
+        }
+        self.memory[addr] = (self.memory[addr] & 0xF0) | (value >> 8) as u8;
+        self.memory[addr + 1] = value as u8;
+      },
+      Fixup::Addr16 => {
+        self.memory[addr] = (value >> 8) as u8;
+        self.memory[addr + 1] = value as u8;
+      },
+      Fixup::UnpackNibble(nibble) => self.memory[addr + 1] = (nibble << 4) | ((value >> 8) & 0xF) as u8,
+      Fixup::UnpackHigh => self.memory[addr + 1] = (value >> 8) as u8,
+      Fixup::UnpackLow => self.memory[addr + 1] = value as u8,
+    }
+    Ok(())
+  }
+
+  /// Read an address operand to be written at `addr`, which is filled in later if the name it
+  /// refers to isn't defined yet
+  fn address(&mut self, fixup: Fixup, addr: usize) -> Result<usize, String> {
+    let token = self.next()?;
+    if let Some(value) = self.known_value(&token)? {
+      return Ok(value as i64 as usize & 0xFFFF);
+    }
+    self.references.push(Reference { addr, fixup, name: token.text, line: token.line });
+    Ok(0)
+  }
+
+  /// The value of a number, constant, label or `{ expression }`, or None for a name that
+  /// hasn't been defined yet
+  fn known_value(&mut self, token: &Token) -> Result<Option<f64>, String> {
+    if token.text == "{" {
+      return self.calc_block().map(Some);
+    }
+    if let Some(value) = parse_number(&token.text) {
+      return Ok(Some(value));
+    }
+    if let Some(value) = self.constants.get(&token.text) {
+      return Ok(Some(*value));
+    }
+    if let Some(addr) = self.labels.get(&token.text) {
+      return Ok(Some(*addr as f64));
+    }
+    if parse_register(&token.text).is_some() || self.aliases.contains_key(&token.text) {
+      return Err(format!("Expected a value, found register '{}'", token.text));
+    }
+    Ok(None)
+  }
+
+  fn value(&mut self) -> Result<f64, String> {
+    let token = self.next()?;
+    self.known_value(&token)?.ok_or_else(|| format!("Undefined name '{}'", token.text))
+  }
+
+  /// A value that fits in a byte, with negative numbers wrapping around like -1 for 0xFF
+  fn byte_value(&mut self) -> Result<u8, String> {
+    let value = self.value()?.floor() as i64;
+    if !(-128..=255).contains(&value) {
+      return Err(format!("{} doesn't fit in a byte", value));
+    }
+    Ok(value as u8)
+  }
+
+  fn nibble_value(&mut self) -> Result<u8, String> {
+    let value = self.value()?.floor() as i64;
+    if !(0..=15).contains(&value) {
+      return Err(format!("{} doesn't fit in 4 bits", value));
+    }
+    Ok(value as u8)
+  }
+
+  fn register(&mut self) -> Result<u8, String> {
+    let token = self.next()?;
+    self.register_named(&token.text).ok_or_else(|| format!("Expected a register, found '{}'", token.text))
+  }
+
+  fn register_named(&self, text: &str) -> Option<u8> {
+    parse_register(text).or_else(|| self.aliases.get(text).copied())
+  }
+
+  /// The tokens up to the `}` that closes an already read `{`
+  fn braced(&mut self) -> Result<Vec<Token>, String> {
+    let mut depth = 1;
+    let mut body = Vec::new();
+    loop {
+      let token = self.next().map_err(|_| "Missing '}'".to_string())?;
+      match token.text.as_str() {
+        "{" => depth += 1,
+        "}" => {
+          depth -= 1;
+          if depth == 0 {
+            return Ok(body);
+          }
+        },
+        _ => {},
+      }
+      body.push(token);
+    }
+  }
+
+  /// Put tokens next in line to be assembled
+  fn expand(&mut self, tokens: Vec<Token>) -> Result<(), String> {
+    self.expansions += 1;
+    if self.expansions > MAX_EXPANSIONS {
+      return Err("Too many macro expansions, is a macro calling itself?".to_string());
+    }
+    self.tokens.splice(self.pos..self.pos, tokens);
+    Ok(())
+  }
+
+  /// Evaluate a `:calc` expression whose `{` has already been read
+  fn calc_block(&mut self) -> Result<f64, String> {
+    let tokens = self.braced()?;
+    let mut pos = 0;
+    let value = self.calc(&tokens, &mut pos)?;
+    if let Some(token) = tokens.get(pos) {
+      return Err(format!("Unexpected '{}' in expression", token.text));
+    }
+    Ok(value)
+  }
+
+  /// Octo expressions have no precedence, and binary operators group to the right
+  fn calc(&self, tokens: &[Token], pos: &mut usize) -> Result<f64, String> {
+    let left = self.calc_term(tokens, pos)?;
+    let op = match tokens.get(*pos) {
+      Some(token) if token.text != ")" => token.text.clone(),
+      _ => return Ok(left),
+    };
+    *pos += 1;
+    let right = self.calc(tokens, pos)?;
+    let (a, b) = (left as i64, right as i64);
+    let flag = |condition: bool| if condition { 1.0 } else { 0.0 };
+    Ok(match op.as_str() {
+      "+" => left + right,
+      "-" => left - right,
+      "*" => left * right,
+      "/" => left / right,
+      "%" => left % right,
+      "pow" => left.powf(right),
+      "min" => left.min(right),
+      "max" => left.max(right),
+      "&" => (a & b) as f64,
+      "|" => (a | b) as f64,
+      "^" => (a ^ b) as f64,
+      "<<" => (a << b) as f64,
+      ">>" => (a >> b) as f64,
+      "<" => flag(left < right),
+      ">" => flag(left > right),
+      "<=" => flag(left <= right),
+      ">=" => flag(left >= right),
+      "==" => flag(left == right),
+      "!=" => flag(left != right),
+      _ => return Err(format!("Unknown operator '{}' in expression", op)),
+    })
+  }
+
+  fn calc_term(&self, tokens: &[Token], pos: &mut usize) -> Result<f64, String> {
+    let token = tokens.get(*pos).ok_or("Expression ended early")?;
+    *pos += 1;
+    let text = token.text.as_str();
+    if text == "(" {
+      let value = self.calc(tokens, pos)?;
+      match tokens.get(*pos) {
+        Some(token) if token.text == ")" => *pos += 1,
+        _ => return Err("Missing ')' in expression".to_string()),
+      }
+      return Ok(value);
+    }
+    let unary: Option<fn(f64) -> f64> = match text {
+      "-" => Some(|x| -x),
+      "~" => Some(|x| !(x as i64) as f64),
+      "!" => Some(|x| if x == 0.0 { 1.0 } else { 0.0 }),
+      "sin" => Some(f64::sin),
+      "cos" => Some(f64::cos),
+      "tan" => Some(f64::tan),
+      "exp" => Some(f64::exp),
+      "log" => Some(f64::ln),
+      "abs" => Some(f64::abs),
+      "sqrt" => Some(f64::sqrt),
+      "sign" => Some(f64::signum),
+      "ceil" => Some(f64::ceil),
+      "floor" => Some(f64::floor),
+      _ => None,
+    };
+    if let Some(unary) = unary {
+      return Ok(unary(self.calc_term(tokens, pos)?));
+    }
+    if text == "@" {
+      // The byte assembled at an address so far
+      let addr = self.calc_term(tokens, pos)? as i64 as usize & 0xFFFF;
+      return Ok(self.memory[addr] as f64);
+    }
+    if text == "strlen" {
+      let string = tokens.get(*pos).ok_or("Expected a string after strlen")?;
+      *pos += 1;
+      return Ok(string_contents(string)?.chars().count() as f64);
+    }
+    match text {
+      "HERE" => return Ok(self.here as f64),
+      "PI" => return Ok(std::f64::consts::PI),
+      "E" => return Ok(std::f64::consts::E),
+      _ => {},
+    }
+    parse_number(text)
+      .or_else(|| self.constants.get(text).copied())
+      .or_else(|| self.labels.get(text).map(|addr| *addr as f64))
+      .or_else(|| self.register_named(text).map(|register| register as f64))
+      .ok_or_else(|| format!("Undefined name '{}' in expression", text))
+  }
+
+  fn define_label(&mut self, name: String, addr: usize) -> Result<(), String> {
+    if self.labels.contains_key(&name) || self.constants.contains_key(&name) {
+      return Err(format!("'{}' is already defined", name));
+    }
+    self.labels.insert(name, addr);
+    Ok(())
+  }
+
+  fn statement(&mut self) -> Result<(), String> {
+    let token = self.next()?;
+    let text = token.text.as_str();
+    match text {
+      ":" => {
+        let name = self.new_name()?;
+        if name == "main" && self.main_jump && self.here == PROGRAM_START + 2 && self.end == PROGRAM_START + 2 {
+          // Nothing comes before main, so there's no need to jump to it
+          self.main_jump = false;
+          self.here = PROGRAM_START;
+          self.end = PROGRAM_START;
+        }
+        self.define_label(name, self.here)?;
+      },
+      ":next" => {
+        // Label the second byte of the next instruction, usually its operand for self-modifying code
+        let name = self.new_name()?;
+        self.define_label(name, self.here + 1)?;
+      },
+      ":alias" => {
+        let name = self.new_name()?;
+        let register = match self.peek() {
+          Some("{") => {
+            self.pos += 1;
+            let value = self.calc_block()? as i64;
+            u8::try_from(value).ok().filter(|register| *register < 16).ok_or_else(|| format!("v{} isn't a register", value))?
+          },
+          _ => self.register()?,
+        };
+        self.aliases.insert(name, register);
+      },
+      ":const" => {
+        let name = self.new_name()?;
+        let value = self.value()?;
+        self.constants.insert(name, value);
+      },
+      ":calc" => {
+        let name = self.new_name()?;
+        self.expect("{")?;
+        let value = self.calc_block()?;
+        self.constants.insert(name, value);
+      },
+      ":byte" => {
+        let value = self.byte_value()?;
+        self.byte(value)?;
+      },
+      ":pointer" => {
+        let addr = self.address(Fixup::Addr16, self.here)?;
+        self.inst(addr as u16)?;
+      },
+      ":call" => {
+        let addr = self.address(Fixup::Addr12, self.here)?;
+        self.inst(0x2000 | addr as u16)?;
+      },
+      ":org" => {
+        let addr = self.value()? as i64;
+        self.here = usize::try_from(addr).ok().filter(|addr| *addr < self.memory.len()).ok_or_else(|| format!("Can't assemble at 0x{:X}", addr))?;
+      },
+      ":unpack" => {
+        let kind = self.next()?;
+        let (high_fixup, high_base) = if kind.text == "long" {
+          (Fixup::UnpackHigh, None)
+        } else {
+          let nibble = self.known_value(&kind)?.ok_or_else(|| format!("Undefined name '{}'", kind.text))? as i64;
+          (Fixup::UnpackNibble((nibble & 0xF) as u8), Some((nibble & 0xF) as u8))
+        };
+        let start = self.here;
+        let addr = self.address(high_fixup, start)?;
+        let high = match high_base {
+          Some(nibble) => (nibble << 4) | ((addr >> 8) & 0xF) as u8,
+          None => (addr >> 8) as u8,
+        };
+        self.inst(0x6000 | high as u16)?;
+        // A forward reference needs filling in on both instructions
+        if let Some(reference) = self.references.last().filter(|reference| reference.addr == start) {
+          let (name, line) = (reference.name.clone(), reference.line);
+          self.references.push(Reference { addr: self.here, fixup: Fixup::UnpackLow, name, line });
+        }
+        self.inst(0x6100 | (addr & 0xFF) as u16)?;
+      },
+      ":breakpoint" => {
+        self.next()?;
+      },
+      ":monitor" => {
+        self.next()?;
+        self.next()?;
+      },
+      ":proto" => {
+        self.next()?;
+      },
+      ":assert" => {
+        let message = match self.peek() {
+          Some(text) if text.starts_with('"') => Some(self.next()?),
+          _ => None,
+        };
+        self.expect("{")?;
+        if self.calc_block()? == 0.0 {
+          return Err(match message {
+            Some(message) => format!("Assertion failed: {}", string_contents(&message)?),
+            None => "Assertion failed".to_string(),
+          });
+        }
+      },
+      ":macro" => {
+        let name = self.new_name()?;
+        let mut args = Vec::new();
+        loop {
+          let token = self.next()?;
+          if token.text == "{" {
+            break;
+          }
+          args.push(token.text);
+        }
+        let body = self.braced()?;
+        self.macros.insert(name, Macro { args, body, calls: 0 });
+      },
+      ":stringmode" => {
+        let name = self.new_name()?;
+        let alphabet = self.next()?;
+        let alphabet = string_contents(&alphabet)?.chars().collect::<Vec<_>>();
+        self.expect("{")?;
+        let body = self.braced()?;
+        let mode = self.string_modes.entry(name).or_default();
+        for (index, c) in alphabet.into_iter().enumerate() {
+          mode.insert(c, (index, body.clone()));
+        }
+      },
+      ";" | "return" => self.inst(0x00EE)?,
+      "clear" => self.inst(0x00E0)?,
+      "exit" => self.inst(0x00FD)?,
+      "lores" => self.inst(0x00FE)?,
+      "hires" => self.inst(0x00FF)?,
+      "scroll-down" => {
+        let n = self.nibble_value()?;
+        self.inst(0x00C0 | n as u16)?;
+      },
+      "scroll-up" => {
+        let n = self.nibble_value()?;
+        self.inst(0x00D0 | n as u16)?;
+      },
+      "scroll-right" => self.inst(0x00FB)?,
+      "scroll-left" => self.inst(0x00FC)?,
+      "audio" => self.inst(0xF002)?,
+      "plane" => {
+        let n = self.nibble_value()?;
+        self.inst(0xF001 | (n as u16) << 8)?;
+      },
+      "bcd" => {
+        let x = self.register()?;
+        self.inst(0xF033 | (x as u16) << 8)?;
+      },
+      "save" | "load" => {
+        let x = self.register()?;
+        if self.peek() == Some("-") {
+          self.pos += 1;
+          let y = self.register()?;
+          let op = if text == "save" { 0x5002 } else { 0x5003 };
+          self.inst(op | (x as u16) << 8 | (y as u16) << 4)?;
+        } else {
+          let op = if text == "save" { 0xF055 } else { 0xF065 };
+          self.inst(op | (x as u16) << 8)?;
+        }
+      },
+      "saveflags" => {
+        let x = self.register()?;
+        self.inst(0xF075 | (x as u16) << 8)?;
+      },
+      "loadflags" => {
+        let x = self.register()?;
+        self.inst(0xF085 | (x as u16) << 8)?;
+      },
+      "sprite" => {
+        let x = self.register()?;
+        let y = self.register()?;
+        let n = self.nibble_value()?;
+        self.inst(0xD000 | (x as u16) << 8 | (y as u16) << 4 | n as u16)?;
+      },
+      "jump" => {
+        let addr = self.address(Fixup::Addr12, self.here)?;
+        self.inst(0x1000 | (addr & 0xFFF) as u16)?;
+      },
+      "jump0" => {
+        let addr = self.address(Fixup::Addr12, self.here)?;
+        self.inst(0xB000 | (addr & 0xFFF) as u16)?;
+      },
+      "native" => {
+        let addr = self.address(Fixup::Addr12, self.here)?;
+        self.inst((addr & 0xFFF) as u16)?;
+      },
+      "delay" | "buzzer" | "pitch" => {
+        self.expect(":=")?;
+        let x = self.register()?;
+        let op = match text {
+          "delay" => 0xF015,
+          "buzzer" => 0xF018,
+          _ => 0xF03A,
+        };
+        self.inst(op | (x as u16) << 8)?;
+      },
+      "i" => self.index_statement()?,
+      "if" => self.if_statement()?,
+      "else" => match self.blocks.pop() {
+        Some(Block::If { jump }) => {
+          let end_jump = self.here;
+          self.inst(0x1000)?;
+          self.patch_jump(jump, self.here);
+          self.blocks.push(Block::Else { jump: end_jump });
+        },
+        _ => return Err("'else' without 'if ... begin'".to_string()),
+      },
+      "end" => match self.blocks.pop() {
+        Some(Block::If { jump }) | Some(Block::Else { jump }) => self.patch_jump(jump, self.here),
+        _ => return Err("'end' without 'if ... begin'".to_string()),
+      },
+      "loop" => self.blocks.push(Block::Loop { start: self.here, breaks: Vec::new() }),
+      "while" => {
+        self.condition(true)?;
+        let jump = self.here;
+        self.inst(0x1000)?;
+        match self.blocks.iter_mut().rev().find_map(|block| match block {
+          Block::Loop { breaks, .. } => Some(breaks),
+          _ => None,
+        }) {
+          Some(breaks) => breaks.push(jump),
+          None => return Err("'while' outside of a loop".to_string()),
+        }
+      },
+      "again" => match self.blocks.pop() {
+        Some(Block::Loop { start, breaks }) => {
+          self.inst(0x1000 | (start & 0xFFF) as u16)?;
+          for jump in breaks {
+            self.patch_jump(jump, self.here);
+          }
+        },
+        _ => return Err("'again' without 'loop'".to_string()),
+      },
+      "then" | "begin" => return Err(format!("'{}' without 'if'", text)),
+      _ => {
+        if let Some(x) = self.register_named(text) {
+          self.register_statement(x)?;
+        } else if self.macros.contains_key(text) {
+          self.expand_macro(text)?;
+        } else if self.string_modes.contains_key(text) {
+          self.expand_string_mode(text)?;
+        } else if let Some(value) = self.known_value(&token)? {
+          if !self.labels.contains_key(text) {
+            // A bare number or constant is a byte of data
+            let value = value.floor() as i64;
+            if !(-128..=255).contains(&value) {
+              return Err(format!("{} doesn't fit in a byte", value));
+            }
+            self.byte(value as u8)?;
+          } else {
+            // A bare name calls a subroutine
+            self.inst(0x2000 | (value as i64 as usize & 0xFFF) as u16)?;
+          }
+        } else if text.starts_with([':', '"', '}']) {
+          return Err(format!("Unexpected '{}'", text));
+        } else {
+          // Most likely a subroutine defined further down
+          self.references.push(Reference { addr: self.here, fixup: Fixup::Addr12, name: token.text.clone(), line: token.line });
+          self.inst(0x2000)?;
+        }
+      },
+    }
+    Ok(())
+  }
+
+  fn index_statement(&mut self) -> Result<(), String> {
+    let op = self.next()?;
+    match op.text.as_str() {
+      ":=" => match self.peek() {
+        Some("long") => {
+          self.pos += 1;
+          let addr = self.address(Fixup::Addr16, self.here + 2)?;
+          self.inst(0xF000)?;
+          self.inst(addr as u16)
+        },
+        Some("hex") => {
+          self.pos += 1;
+          let x = self.register()?;
+          self.inst(0xF029 | (x as u16) << 8)
+        },
+        Some("bighex") => {
+          self.pos += 1;
+          let x = self.register()?;
+          self.inst(0xF030 | (x as u16) << 8)
+        },
+        _ => {
+          let addr = self.address(Fixup::Addr12, self.here)?;
+          if addr > 0xFFF {
+            return Err(format!("0x{:X} is out of range for i, use 'i := long'", addr));
+          }
+          self.inst(0xA000 | addr as u16)
+        },
+      },
+      "+=" => {
+        let x = self.register()?;
+        self.inst(0xF01E | (x as u16) << 8)
+      },
+      _ => Err(format!("Unknown operator 'i {}'", op.text)),
+    }
+  }
+
+  fn register_statement(&mut self, x: u8) -> Result<(), String> {
+    let op = self.next()?;
+    let vx = (x as u16) << 8;
+    // Operators that only take a register
+    let register_op = match op.text.as_str() {
+      "|=" => Some(0x8001),
+      "&=" => Some(0x8002),
+      "^=" => Some(0x8003),
+      "=-" => Some(0x8007),
+      ">>=" => Some(0x8006),
+      "<<=" => Some(0x800E),
+      _ => None,
+    };
+    if let Some(register_op) = register_op {
+      let y = self.register()?;
+      return self.inst(register_op | vx | (y as u16) << 4);
+    }
+
+    let rhs = self.peek().unwrap_or("").to_string();
+    let y = self.register_named(&rhs);
+    match (op.text.as_str(), y) {
+      (":=", _) if rhs == "key" => {
+        self.pos += 1;
+        self.inst(0xF00A | vx)
+      },
+      (":=", _) if rhs == "delay" => {
+        self.pos += 1;
+        self.inst(0xF007 | vx)
+      },
+      (":=", _) if rhs == "random" => {
+        self.pos += 1;
+        let mask = self.byte_value()?;
+        self.inst(0xC000 | vx | mask as u16)
+      },
+      (":=", Some(y)) => {
+        self.pos += 1;
+        self.inst(0x8000 | vx | (y as u16) << 4)
+      },
+      (":=", None) => {
+        let value = self.byte_value()?;
+        self.inst(0x6000 | vx | value as u16)
+      },
+      ("+=", Some(y)) => {
+        self.pos += 1;
+        self.inst(0x8004 | vx | (y as u16) << 4)
+      },
+      ("+=", None) => {
+        let value = self.byte_value()?;
+        self.inst(0x7000 | vx | value as u16)
+      },
+      ("-=", Some(y)) => {
+        self.pos += 1;
+        self.inst(0x8005 | vx | (y as u16) << 4)
+      },
+      ("-=", None) => {
+        let value = self.byte_value()?;
+        self.inst(0x7000 | vx | value.wrapping_neg() as u16)
+      },
+      _ => Err(format!("Unknown operator '{}'", op.text)),
+    }
+  }
+
+  fn if_statement(&mut self) -> Result<(), String> {
+    let start = self.pos;
+    // Find out which kind of if this is before assembling the condition
+    let kind = self.tokens[start..].iter().find(|token| token.text == "then" || token.text == "begin").map(|token| token.text.clone());
+    match kind.as_deref() {
+      Some("then") => {
+        // Skip the next statement unless the condition holds
+        self.condition(false)?;
+        self.expect("then")
+      },
+      Some(_) => {
+        // Skip the jump past the block if the condition holds
+        self.condition(true)?;
+        self.expect("begin")?;
+        self.blocks.push(Block::If { jump: self.here });
+        self.inst(0x1000)
+      },
+      None => Err("'if' without 'then' or 'begin'".to_string()),
+    }
+  }
+
+  fn operand(&mut self) -> Result<Operand, String> {
+    let text = self.peek().unwrap_or("").to_string();
+    match self.register_named(&text) {
+      Some(register) => {
+        self.pos += 1;
+        Ok(Operand::Register(register))
+      },
+      None => self.byte_value().map(Operand::Value),
+    }
+  }
+
+  /// Put VF to 1 if `a >= b` and 0 otherwise, by subtracting, where at least one is a register
+  fn compare(&mut self, a: &Operand, b: &Operand) -> Result<(), String> {
+    match (a, b) {
+      (Operand::Register(a), Operand::Register(b)) => {
+        self.inst(0x8F00 | (*a as u16) << 4)?;
+        self.inst(0x8F05 | (*b as u16) << 4)
+      },
+      (Operand::Register(a), Operand::Value(b)) => {
+        self.inst(0x6F00 | *b as u16)?;
+        self.inst(0x8F07 | (*a as u16) << 4)
+      },
+      (Operand::Value(a), Operand::Register(b)) => {
+        self.inst(0x6F00 | *a as u16)?;
+        self.inst(0x8F05 | (*b as u16) << 4)
+      },
+      (Operand::Value(_), Operand::Value(_)) => Err("A comparison needs a register".to_string()),
+    }
+  }
+
+  /// Assemble a condition as an instruction that skips the next one when the condition is
+  /// `skip_when`. Comparisons other than equality go through VF.
+  fn condition(&mut self, skip_when: bool) -> Result<(), String> {
+    let x = self.register()?;
+    let vx = (x as u16) << 8;
+    let op = self.next()?;
+    match op.text.as_str() {
+      "key" | "-key" => {
+        let pressed = op.text == "key";
+        self.inst(if skip_when == pressed { 0xE09E | vx } else { 0xE0A1 | vx })
+      },
+      "==" | "!=" => {
+        let equal = (op.text == "==") == skip_when;
+        match self.operand()? {
+          Operand::Register(y) => self.inst(if equal { 0x5000 } else { 0x9000 } | vx | (y as u16) << 4),
+          Operand::Value(value) => self.inst(if equal { 0x3000 } else { 0x4000 } | vx | value as u16),
+        }
+      },
+      "<" | ">" | "<=" | ">=" => {
+        let rhs = self.operand()?;
+        let lhs = Operand::Register(x);
+        // x < y and x >= y look at x - y, x > y and x <= y at y - x
+        let (a, b) = if matches!(op.text.as_str(), "<" | ">=") { (&lhs, &rhs) } else { (&rhs, &lhs) };
+        self.compare(a, b)?;
+        // VF is 1 exactly when the condition holds for >= and <=, and when it doesn't for < and >
+        let holds = if matches!(op.text.as_str(), ">=" | "<=") { 1 } else { 0 };
+        self.inst(if skip_when { 0x3F00 } else { 0x4F00 } | holds)
+      },
+      _ => Err(format!("Unknown comparison '{}'", op.text)),
+    }
+  }
+
+  fn expand_macro(&mut self, name: &str) -> Result<(), String> {
+    let (args, body, calls) = {
+      let mac = self.macros.get_mut(name).unwrap();
+      let calls = mac.calls;
+      mac.calls += 1;
+      (mac.args.clone(), mac.body.clone(), calls)
+    };
+    let mut values = HashMap::new();
+    for arg in args {
+      values.insert(arg, self.next()?.text);
+    }
+    values.insert("CALLS".to_string(), calls.to_string());
+    let line = self.tokens[self.pos - 1].line;
+    let expanded = body
+      .into_iter()
+      .map(|token| Token { text: values.get(&token.text).cloned().unwrap_or(token.text), line })
+      .collect();
+    self.expand(expanded)
+  }
+
+  /// Expand a string mode for each character of a string, with CHAR as the character code,
+  /// INDEX as its position in the string and VALUE as its position in the mode's alphabet
+  fn expand_string_mode(&mut self, name: &str) -> Result<(), String> {
+    let string = self.next()?;
+    let line = string.line;
+    let mut expanded = Vec::new();
+    for (index, c) in string_contents(&string)?.chars().enumerate() {
+      let (value, body) = self.string_modes[name]
+        .get(&c)
+        .ok_or_else(|| format!("String mode '{}' has no '{}'", name, c))?;
+      for token in body {
+        let text = match token.text.as_str() {
+          "CHAR" => (c as u32).to_string(),
+          "INDEX" => index.to_string(),
+          "VALUE" => value.to_string(),
+          _ => token.text.clone(),
+        };
+        expanded.push(Token { text, line });
+      }
+    }
+    self.expand(expanded)
+  }
+
+  fn finish(mut self) -> Result<Vec<u8>, String> {
+    if let Some(block) = self.blocks.last() {
+      return Err(match block {
+        Block::If { .. } | Block::Else { .. } => "'if ... begin' is missing its 'end'",
+        Block::Loop { .. } => "'loop' is missing its 'again'",
+      }.to_string());
+    }
+    for reference in std::mem::take(&mut self.references) {
+      let value = self.labels.get(&reference.name).copied()
+        .or_else(|| self.constants.get(&reference.name).map(|value| *value as i64 as usize & 0xFFFF))
+        .ok_or_else(|| format!("Line {}: Undefined name '{}'", reference.line, reference.name))?;
+      self.fill(reference.addr, reference.fixup, value).map_err(|err| format!("Line {}: {}", reference.line, err))?;
+    }
+    if self.main_jump {
+      let main = *self.labels.get("main").ok_or("There's no ': main' for the program to start at")?;
+      self.patch_jump(PROGRAM_START, main);
+    }
+    Ok(self.memory[PROGRAM_START..self.end].to_vec())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Assemble a program that should come out as whole instructions
+  fn words(source: &str) -> Vec<u16> {
+    let rom = assemble(source).unwrap();
+    assert_eq!(rom.len() % 2, 0, "{:02X?} isn't whole instructions", rom);
+    rom.chunks(2).map(|word| u16::from_be_bytes([word[0], word[1]])).collect()
+  }
+
+  #[test]
+  fn main_first_needs_no_jump() {
+    assert_eq!(words(": main clear"), [0x00E0]);
+  }
+
+  #[test]
+  fn main_not_first_is_jumped_to() {
+    assert_eq!(words(": sub ;\n: main sub"), [0x1204, 0x00EE, 0x2202]);
+  }
+
+  #[test]
+  fn forward_references() {
+    assert_eq!(words(": main later jump later\n: later ;"), [0x2204, 0x1204, 0x00EE]);
+  }
+
+  #[test]
+  fn if_then_skips_unless_the_condition_holds() {
+    let source = "
+      : main
+        if v1 == 3 then v2 := 1
+        if v1 != v3 then v2 := 2
+        if v1 key then clear
+        if v1 -key then clear
+    ";
+    assert_eq!(words(source), [0x4103, 0x6201, 0x5130, 0x6202, 0xE1A1, 0x00E0, 0xE19E, 0x00E0]);
+  }
+
+  #[test]
+  fn if_begin_else_end() {
+    let source = ": main if v0 == 1 begin v1 := 1 else v1 := 2 end clear";
+    assert_eq!(words(source), [0x3001, 0x1208, 0x6101, 0x120A, 0x6102, 0x00E0]);
+    assert_eq!(words(": main if v0 != 1 begin clear end"), [0x4001, 0x1206, 0x00E0]);
+  }
+
+  #[test]
+  fn loop_while_again() {
+    let source = ": main loop v0 += 1 while v0 != 5 clear again";
+    assert_eq!(words(source), [0x7001, 0x4005, 0x120A, 0x00E0, 0x1200]);
+  }
+
+  #[test]
+  fn comparisons_go_through_vf() {
+    // VF ends up 1 when the subtraction doesn't borrow, i.e. when the first operand is >= the second
+    assert_eq!(words(": main if v1 < v2 then clear"), [0x8F10, 0x8F25, 0x4F00, 0x00E0]);
+    assert_eq!(words(": main if v1 > v2 then clear"), [0x8F20, 0x8F15, 0x4F00, 0x00E0]);
+    assert_eq!(words(": main if v1 <= v2 then clear"), [0x8F20, 0x8F15, 0x4F01, 0x00E0]);
+    assert_eq!(words(": main if v1 >= 7 then clear"), [0x6F07, 0x8F17, 0x4F01, 0x00E0]);
+    assert_eq!(words(": main if v1 > 7 then clear"), [0x6F07, 0x8F15, 0x4F00, 0x00E0]);
+    assert_eq!(words(": main if v1 < v2 begin clear end"), [0x8F10, 0x8F25, 0x3F00, 0x120A, 0x00E0]);
+  }
+
+  #[test]
+  fn unpack() {
+    let rom = assemble(": main :unpack 0xA data :unpack long data\n: data 1").unwrap();
+    assert_eq!(rom, [0x60, 0xA2, 0x61, 0x08, 0x60, 0x02, 0x61, 0x08, 0x01]);
+  }
+
+  #[test]
+  fn long_index() {
+    let rom = assemble(": main i := long far\n:org 0x1234 : far 7").unwrap();
+    assert_eq!(rom[..4], [0xF0, 0x00, 0x12, 0x34]);
+    assert_eq!(rom.len(), 0x1235 - PROGRAM_START);
+    assert_eq!(rom[0x1234 - PROGRAM_START], 7);
+    // Plain `i :=` only has 12 bits for the address
+    assert!(assemble(": main i := far\n:org 0x1234 : far 7").unwrap_err().contains("out of range"));
+  }
+
+  #[test]
+  fn macros_calc_and_string_modes() {
+    let source = "
+      :macro twice X { X X }
+      :calc three { 1 + 2 }
+      :stringmode index \"AB\" { VALUE }
+      : main
+        twice three
+        index \"BA\"
+    ";
+    assert_eq!(assemble(source).unwrap(), [3, 3, 1, 0]);
+  }
+
+  #[test]
+  fn errors_name_the_line() {
+    assert_eq!(assemble(": main\n  i := missing").unwrap_err(), "Line 2: Undefined name 'missing'");
+    assert_eq!(assemble(": main\n  v0 := 300").unwrap_err(), "Line 2: 300 doesn't fit in a byte");
+    assert!(assemble("clear").unwrap_err().contains("main"));
+    assert!(assemble(": main loop clear").unwrap_err().contains("again"));
+  }
+}
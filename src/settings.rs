@@ -0,0 +1,211 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use eframe::egui::Key;
+use serde::{Deserialize, Serialize};
+
+use crate::chip8::Variant;
+use crate::frontend::egui_backend::default_key_layout;
+use crate::frontend::flicker::FlickerMode;
+use crate::gilrs_backend::GamepadMapping;
+use crate::frontend::palette::Palette;
+use crate::square_wave::Tone;
+
+/// How many ROMs are listed under File → Recent
+pub const MAX_RECENT_ROMS: usize = 10;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+/// Everything about the emulator that's kept between runs. Names are stored rather than the
+/// values themselves so the file stays easy to edit by hand.
+pub struct Settings {
+  /// Where the Load ROM dialog opens
+  pub rom_directory: Option<PathBuf>,
+  /// ROMs that have been loaded, newest first
+  pub recent_roms: Vec<PathBuf>,
+  pub variant: String,
+  pub speed: f32,
+  /// Buzzer volume, from 0 to 1
+  pub volume: f32,
+  /// The buzzer's frequency in Hz
+  pub buzzer_frequency: f32,
+  /// Fraction of each period the buzzer's square wave spends high
+  pub buzzer_duty: f32,
+  /// How long the buzzer takes to fade in and out, in milliseconds
+  pub buzzer_attack_ms: f32,
+  pub buzzer_release_ms: f32,
+  /// A palette name or `#RRGGBB` colors, as taken by `Palette::parse`
+  pub palette: String,
+  pub flicker: String,
+  pub blend_frames: usize,
+  /// Whether the on-screen keypad is shown beside the display
+  pub show_keypad: bool,
+  /// The egui key name for each keypad key, in COSMAC layout order (1 2 3 C 4 5 6 D ...)
+  pub keys: Vec<String>,
+  /// Which keypad key each gamepad control presses, as taken by `GamepadMapping::parse`
+  pub gamepad: String,
+  /// Overrides for particular ROMs, by ROM hash in hex
+  pub roms: BTreeMap<String, RomSettings>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+/// Settings that only apply to one ROM, overriding the global ones where set
+pub struct RomSettings {
+  pub variant: Option<String>,
+  pub ipf: Option<u32>,
+  pub palette: Option<String>,
+  pub gamepad: Option<String>,
+}
+
+impl Default for Settings {
+  fn default() -> Settings {
+    let tone = Tone::default();
+    Settings {
+      rom_directory: None,
+      recent_roms: vec![],
+      variant: Variant::XOCHIP.name().to_string(),
+      speed: 1.0,
+      volume: 1.0,
+      buzzer_frequency: tone.freq,
+      buzzer_duty: tone.duty,
+      buzzer_attack_ms: tone.attack.as_secs_f32() * 1000.0,
+      buzzer_release_ms: tone.release.as_secs_f32() * 1000.0,
+      palette: palette_name(&Palette::default()),
+      flicker: FlickerMode::Off.name().to_string(),
+      blend_frames: 2,
+      show_keypad: false,
+      keys: vec![],
+      gamepad: GamepadMapping::default().to_string(),
+      roms: BTreeMap::new(),
+    }
+  }
+}
+
+/// How a palette is written in the settings, by name if it's a preset
+pub fn palette_name(palette: &Palette) -> String {
+  palette.preset_name().map(str::to_string).unwrap_or_else(|| palette.to_string())
+}
+
+fn rom_key(rom_hash: u64) -> String {
+  format!("{:016x}", rom_hash)
+}
+
+impl Settings {
+  /// Where the settings are saved, in the platform's config directory
+  pub fn path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("silk8").join("settings.toml"))
+  }
+
+  /// Load the saved settings, falling back to the defaults if there are none or they can't be read
+  pub fn load() -> Settings {
+    let path = match Settings::path() {
+      Some(path) => path,
+      None => return Settings::default(),
+    };
+    let text = match fs::read_to_string(&path) {
+      Ok(text) => text,
+      Err(_) => return Settings::default(),
+    };
+    toml::from_str(&text).unwrap_or_else(|err| {
+      println!("Failed to read {}: {}", path.display(), err);
+      Settings::default()
+    })
+  }
+
+  pub fn save(&self) {
+    let path = match Settings::path() {
+      Some(path) => path,
+      None => return,
+    };
+    let result = toml::to_string_pretty(self)
+      .map_err(|err| err.to_string())
+      .and_then(|text| {
+        fs::create_dir_all(path.parent().unwrap())
+          .and_then(|_| fs::write(&path, text))
+          .map_err(|err| err.to_string())
+      });
+    if let Err(err) = result {
+      println!("Failed to save settings: {}", err);
+    }
+  }
+
+  /// Move a ROM to the top of the recent list, dropping the oldest once it's full
+  pub fn add_recent_rom(&mut self, path: &Path) {
+    self.recent_roms.retain(|recent| recent != path);
+    self.recent_roms.insert(0, path.to_path_buf());
+    self.recent_roms.truncate(MAX_RECENT_ROMS);
+  }
+
+  /// The buzzer as set up here, played and recorded alike
+  pub fn tone(&self) -> Tone {
+    Tone::new(self.buzzer_frequency, self.buzzer_duty, self.volume, self.buzzer_attack_ms, self.buzzer_release_ms)
+  }
+
+  /// Put the buzzer's sound back to the default, leaving its volume
+  pub fn reset_buzzer(&mut self) {
+    let defaults = Settings::default();
+    self.buzzer_frequency = defaults.buzzer_frequency;
+    self.buzzer_duty = defaults.buzzer_duty;
+    self.buzzer_attack_ms = defaults.buzzer_attack_ms;
+    self.buzzer_release_ms = defaults.buzzer_release_ms;
+  }
+
+  pub fn rom(&self, rom_hash: u64) -> RomSettings {
+    self.roms.get(&rom_key(rom_hash)).cloned().unwrap_or_default()
+  }
+
+  /// Change the overrides for a ROM, forgetting it entirely once nothing is overridden
+  pub fn set_rom(&mut self, rom_hash: u64, rom: RomSettings) {
+    if rom == RomSettings::default() {
+      self.roms.remove(&rom_key(rom_hash));
+    } else {
+      self.roms.insert(rom_key(rom_hash), rom);
+    }
+  }
+
+  /// The variant to run a ROM as, or any ROM if there's no hash
+  pub fn variant(&self, rom_hash: Option<u64>) -> Variant {
+    rom_hash
+      .and_then(|rom_hash| self.rom(rom_hash).variant)
+      .and_then(|name| Variant::from_name(&name))
+      .or_else(|| Variant::from_name(&self.variant))
+      .unwrap_or(Variant::XOCHIP)
+  }
+
+  pub fn palette(&self, rom_hash: Option<u64>) -> Palette {
+    rom_hash
+      .and_then(|rom_hash| self.rom(rom_hash).palette)
+      .and_then(|text| Palette::parse(&text))
+      .or_else(|| Palette::parse(&self.palette))
+      .unwrap_or_default()
+  }
+
+  pub fn gamepad_mapping(&self, rom_hash: Option<u64>) -> GamepadMapping {
+    rom_hash
+      .and_then(|rom_hash| self.rom(rom_hash).gamepad)
+      .and_then(|text| GamepadMapping::parse(&text))
+      .or_else(|| GamepadMapping::parse(&self.gamepad))
+      .unwrap_or_default()
+  }
+
+  /// The keyboard key for each keypad key, using the default for any that aren't set
+  pub fn key_layout(&self) -> [Key; 16] {
+    let mut layout = default_key_layout();
+    for (key, name) in layout.iter_mut().zip(&self.keys) {
+      if let Some(named) = Key::from_name(name) {
+        *key = named;
+      }
+    }
+    layout
+  }
+
+  pub fn set_key_layout(&mut self, layout: [Key; 16]) {
+    self.keys = layout.iter().map(|key| key.name().to_string()).collect();
+  }
+
+  pub fn flicker_mode(&self) -> FlickerMode {
+    FlickerMode::from_name(&self.flicker).unwrap_or(FlickerMode::Off)
+  }
+}
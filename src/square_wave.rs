@@ -1,26 +1,112 @@
-use std::f32::consts::PI;
+use std::sync::{
+  atomic::{AtomicBool, Ordering},
+  Arc
+};
 use std::time::Duration;
 
 use rodio::source::Source;
 
-/// An infinite source that produces a sine.
+use crate::chip8::{AudioPattern, DEFAULT_PITCH};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+/// What a square wave sounds like
+pub struct Tone {
+  pub freq: f32,
+  /// Fraction of each period spent high
+  pub duty: f32,
+  pub volume: f32,
+  /// How long it takes to fade in and out once switched on or off
+  pub attack: Duration,
+  pub release: Duration,
+}
+
+impl Tone {
+  /// A tone from the plain numbers kept in settings, with `volume` from 0 to 1 of the default
+  /// buzzer's loudness and the fades in milliseconds. Values out of range are pulled back in.
+  pub fn new(freq: f32, duty: f32, volume: f32, attack_ms: f32, release_ms: f32) -> Tone {
+    // A hand-edited settings file could even hold NaN
+    let clamp = |value: f32, min: f32, max: f32| if value.is_nan() { min } else { value.clamp(min, max) };
+    let millis = |ms: f32| Duration::from_secs_f32(clamp(ms, 0.0, 1000.0) / 1000.0);
+    Tone {
+      freq: clamp(freq, 20.0, 4000.0),
+      duty: clamp(duty, 0.01, 0.99),
+      volume: Tone::default().volume * clamp(volume, 0.0, 1.0),
+      attack: millis(attack_ms),
+      release: millis(release_ms),
+    }
+  }
+}
+
+impl Default for Tone {
+  /// The CHIP-8 buzzer, with fades short enough to sound instant but long enough to avoid clicks
+  fn default() -> Tone {
+    Tone {
+      freq: 440.0,
+      duty: 0.5,
+      volume: 0.10,
+      attack: Duration::from_millis(2),
+      release: Duration::from_millis(5),
+    }
+  }
+}
+
+/// Smooths the jump of a naive square wave at phase 0, for a wave advancing `step` per sample
+fn poly_blep(phase: f32, step: f32) -> f32 {
+  if phase < step {
+    let t = phase / step;
+    t + t - t * t - 1.0
+  } else if phase > 1.0 - step {
+    let t = (phase - 1.0) / step;
+    t * t + t + t + 1.0
+  } else {
+    0.0
+  }
+}
+
+/// An infinite band-limited square wave, using PolyBLEP to keep the edges from aliasing.
 ///
-/// Always has a rate of 48kHz and one channel.
+/// Has one channel. It's switched on and off through its gate, which fades it in and out
+/// over the attack and release times rather than cutting it off.
 #[derive(Clone, Debug)]
 pub struct SquareWave {
-  freq: f32,
-  num_sample: usize,
+  tone: Tone,
+  sample_rate: u32,
+  /// Position within the current period, from 0 to 1
+  phase: f32,
+  /// Current envelope level, from 0 to 1
+  level: f32,
+  gate: Arc<AtomicBool>,
 }
 
 impl SquareWave {
-  /// The frequency of the square wave.
-  #[inline]
-  pub fn new(freq: f32) -> SquareWave {
+  /// A square wave that starts with its gate closed
+  pub fn new(tone: Tone, sample_rate: u32) -> SquareWave {
     SquareWave {
-      freq,
-      num_sample: 0,
+      tone: Tone { duty: tone.duty.clamp(0.01, 0.99), ..tone },
+      sample_rate,
+      phase: 0.0,
+      level: 0.0,
+      gate: Arc::new(AtomicBool::new(false)),
     }
   }
+
+  /// Change how the wave sounds from the next sample on, carrying on from the same point in its
+  /// period and envelope so there's no click
+  pub fn set_tone(&mut self, tone: Tone) {
+    self.tone = Tone { duty: tone.duty.clamp(0.01, 0.99), ..tone };
+  }
+
+  /// A handle for switching the wave on and off, even after it's been handed off to a sink
+  pub fn gate(&self) -> Arc<AtomicBool> {
+    self.gate.clone()
+  }
+
+}
+
+/// How far an envelope moves each sample to go from silent to full in `time`
+fn envelope_step(time: Duration, sample_rate: u32) -> f32 {
+  let samples = time.as_secs_f32() * sample_rate as f32;
+  if samples < 1.0 { 1.0 } else { 1.0 / samples }
 }
 
 impl Iterator for SquareWave {
@@ -28,10 +114,20 @@ impl Iterator for SquareWave {
 
   #[inline]
   fn next(&mut self) -> Option<f32> {
-    self.num_sample = self.num_sample.wrapping_add(1);
+    self.level = if self.gate.load(Ordering::Relaxed) {
+      (self.level + envelope_step(self.tone.attack, self.sample_rate)).min(1.0)
+    } else {
+      (self.level - envelope_step(self.tone.release, self.sample_rate)).max(0.0)
+    };
 
-    let value = 2.0 * PI * self.freq * self.num_sample as f32 / 48000.0;
-    Some(if value == 0.0 { 0.0 } else { value.sin().signum() })
+    let step = self.tone.freq / self.sample_rate as f32;
+    let duty = self.tone.duty;
+    let naive = if self.phase < duty { 1.0 } else { -1.0 };
+    // Smooth both the rising edge at phase 0 and the falling edge at the duty cycle
+    let value = naive + poly_blep(self.phase, step) - poly_blep((self.phase + 1.0 - duty) % 1.0, step);
+    self.phase = (self.phase + step) % 1.0;
+
+    Some(value * self.level * self.tone.volume)
   }
 }
 
@@ -48,11 +144,66 @@ impl Source for SquareWave {
 
   #[inline]
   fn sample_rate(&self) -> u32 {
-    48000
+    self.sample_rate
   }
 
   #[inline]
   fn total_duration(&self) -> Option<Duration> {
     None
   }
-}
\ No newline at end of file
+}
+
+/// An XO-CHIP audio pattern, looping its bits as full high and low samples at the pattern's own
+/// rate. Uses the volume and envelope of a buzzer `Tone` so the two sound alike in level.
+#[derive(Clone, Debug)]
+pub struct PatternWave {
+  tone: Tone,
+  sample_rate: u32,
+  pattern: AudioPattern,
+  /// Position within the pattern, in bits from 0 to 128
+  position: f32,
+  level: f32,
+  playing: bool,
+}
+
+impl PatternWave {
+  /// A pattern wave that starts silent, with an empty pattern
+  pub fn new(tone: Tone, sample_rate: u32) -> PatternWave {
+    PatternWave {
+      tone,
+      sample_rate,
+      pattern: AudioPattern { bits: [0; 16], pitch: DEFAULT_PITCH },
+      position: 0.0,
+      level: 0.0,
+      playing: false,
+    }
+  }
+
+  /// Fade in or out, like the gate of a `SquareWave`
+  pub fn set_playing(&mut self, playing: bool) {
+    self.playing = playing;
+  }
+
+  /// Switch to another pattern or pitch, carrying on from the same place in the loop
+  pub fn set_pattern(&mut self, pattern: AudioPattern) {
+    self.pattern = pattern;
+  }
+}
+
+impl Iterator for PatternWave {
+  type Item = f32;
+
+  #[inline]
+  fn next(&mut self) -> Option<f32> {
+    self.level = if self.playing {
+      (self.level + envelope_step(self.tone.attack, self.sample_rate)).min(1.0)
+    } else {
+      (self.level - envelope_step(self.tone.release, self.sample_rate)).max(0.0)
+    };
+
+    let value = if self.pattern.bit(self.position as usize) { 1.0 } else { -1.0 };
+    self.position = (self.position + self.pattern.rate() / self.sample_rate as f32) % 128.0;
+
+    Some(value * self.level * self.tone.volume)
+  }
+}
@@ -0,0 +1,93 @@
+use std::sync::{
+  atomic::{AtomicBool, Ordering},
+  Arc
+};
+
+use crate::chip8::AudioPattern;
+use crate::square_wave::{PatternWave, SquareWave, Tone};
+
+const SAMPLE_RATE: u32 = 48000;
+/// Samples in each 60 Hz frame, which divides evenly so frames always line up with samples
+const SAMPLES_PER_FRAME: usize = SAMPLE_RATE as usize / 60;
+
+/// Renders the buzzer, or an XO-CHIP program's audio pattern, to samples as emulation runs, so it
+/// can be saved as a WAV file.
+/// Time is measured in emulated frames, so the recording matches what the program did
+/// rather than how fast it happened to run.
+pub struct WavRecorder {
+  wave: SquareWave,
+  gate: Arc<AtomicBool>,
+  pattern_wave: PatternWave,
+  playing: bool,
+  pattern: Option<AudioPattern>,
+  /// Index of the first sample of the current frame
+  frame_start: usize,
+  samples: Vec<i16>,
+}
+
+impl WavRecorder {
+  pub fn new(tone: Tone) -> WavRecorder {
+    let wave = SquareWave::new(tone, SAMPLE_RATE);
+    Self {
+      gate: wave.gate(),
+      wave,
+      pattern_wave: PatternWave::new(tone, SAMPLE_RATE),
+      playing: false,
+      pattern: None,
+      frame_start: 0,
+      samples: vec![],
+    }
+  }
+
+  /// Turn the sound on or off `progress` of the way through the current frame, from 0 to 1,
+  /// playing `pattern` if the program has loaded one and the buzzer otherwise
+  pub fn set_playing(&mut self, playing: bool, pattern: Option<AudioPattern>, progress: f32) {
+    if playing != self.playing || pattern != self.pattern {
+      let offset = (progress.clamp(0.0, 1.0) * SAMPLES_PER_FRAME as f32).round() as usize;
+      self.fill_to(self.frame_start + offset);
+      self.playing = playing;
+      self.pattern = pattern;
+      self.gate.store(playing && pattern.is_none(), Ordering::Relaxed);
+      self.pattern_wave.set_playing(playing && pattern.is_some());
+      if let Some(pattern) = pattern {
+        self.pattern_wave.set_pattern(pattern);
+      }
+    }
+  }
+
+  /// Finish off the current frame and move on to the next
+  pub fn end_frame(&mut self) {
+    self.frame_start += SAMPLES_PER_FRAME;
+    self.fill_to(self.frame_start);
+  }
+
+  fn fill_to(&mut self, end: usize) {
+    while self.samples.len() < end {
+      // Only one of the two is ever on, but the other may still be fading out
+      let sample = self.wave.next().unwrap() + self.pattern_wave.next().unwrap();
+      self.samples.push((sample * i16::MAX as f32) as i16);
+    }
+  }
+
+  /// The recording as a 16-bit mono PCM WAV file
+  pub fn to_wav(&self) -> Vec<u8> {
+    let data_len = self.samples.len() as u32 * 2;
+    let mut bytes = Vec::with_capacity(44 + data_len as usize);
+    bytes.extend_from_slice(b"RIFF");
+    bytes.extend_from_slice(&(36 + data_len).to_le_bytes());
+    bytes.extend_from_slice(b"WAVEfmt ");
+    bytes.extend_from_slice(&16_u32.to_le_bytes());
+    bytes.extend_from_slice(&1_u16.to_le_bytes()); // PCM
+    bytes.extend_from_slice(&1_u16.to_le_bytes()); // Mono
+    bytes.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    bytes.extend_from_slice(&(SAMPLE_RATE * 2).to_le_bytes());
+    bytes.extend_from_slice(&2_u16.to_le_bytes());
+    bytes.extend_from_slice(&16_u16.to_le_bytes());
+    bytes.extend_from_slice(b"data");
+    bytes.extend_from_slice(&data_len.to_le_bytes());
+    for sample in &self.samples {
+      bytes.extend_from_slice(&sample.to_le_bytes());
+    }
+    bytes
+  }
+}
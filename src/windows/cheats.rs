@@ -0,0 +1,178 @@
+use std::fs;
+use std::path::PathBuf;
+
+use eframe::egui;
+
+use crate::frontend::cheats::{Cheat, CheatList, CheatSearch, CheatTarget, SearchCondition};
+use crate::frontend::Emulator;
+use super::show_tool_window;
+
+/// Most search results to list at once, the rest need narrowing down first
+const MAX_LISTED_RESULTS: usize = 200;
+
+/// Where the cheats for a ROM are saved
+fn cheats_path(rom_hash: u64) -> PathBuf {
+  PathBuf::from(format!("./cheats/{:016x}.txt", rom_hash))
+}
+
+/// Load the saved cheats for the current ROM into the emulator
+pub fn load_cheats(emulator: &mut Emulator) {
+  if let Some(rom_hash) = emulator.rom_hash() {
+    let cheats = fs::read_to_string(cheats_path(rom_hash)).unwrap_or_default();
+    emulator.set_cheats(CheatList::from_text(&cheats));
+  }
+}
+
+fn save_cheats(emulator: &Emulator) {
+  if let Some(rom_hash) = emulator.rom_hash() {
+    let path = cheats_path(rom_hash);
+    let result = fs::create_dir_all(path.parent().unwrap())
+      .and_then(|_| fs::write(&path, emulator.cheats().to_text()));
+    if let Err(err) = result {
+      println!("Failed to save cheats: {}", err);
+    }
+  }
+}
+
+/// Searches memory and registers for values to freeze
+pub struct CheatsWindow {
+  pub open: bool,
+  search: Option<CheatSearch>,
+  condition: &'static str,
+  value: String,
+}
+
+impl CheatsWindow {
+  pub fn new() -> CheatsWindow {
+    Self {
+      open: false,
+      search: None,
+      condition: SearchCondition::NAMES[0],
+      value: "0".to_string(),
+    }
+  }
+
+  pub fn show(&mut self, ctx: &egui::Context, emulator: &mut Emulator) {
+    let mut open = self.open;
+    show_tool_window(ctx, "Cheats", [420.0, 480.0], &mut open, |ui| {
+      ui.heading("Search");
+      self.show_search(ui, emulator);
+      ui.separator();
+      ui.heading("Cheats");
+      self.show_cheats(ui, emulator);
+    });
+    self.open = open;
+  }
+
+  fn show_search(&mut self, ui: &mut egui::Ui, emulator: &mut Emulator) {
+    ui.horizontal(|ui| {
+      if ui.button("New Search").clicked() {
+        self.search = Some(CheatSearch::new(emulator.chip8()));
+      }
+
+      egui::ComboBox::from_id_source("cheat_condition")
+        .selected_text(self.condition)
+        .show_ui(ui, |ui| {
+          for name in SearchCondition::NAMES {
+            ui.selectable_value(&mut self.condition, name, name);
+          }
+        });
+
+      let value = self.value.trim();
+      let value = match value.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => value.parse::<u8>().ok(),
+      };
+      let condition = SearchCondition::parse(self.condition, value.unwrap_or(0));
+      let needs_value = matches!(condition, Some(SearchCondition::Equal(_) | SearchCondition::GreaterThan(_) | SearchCondition::LessThan(_)));
+      if needs_value {
+        ui.add(egui::TextEdit::singleline(&mut self.value).desired_width(48.0));
+      }
+
+      let can_filter = self.search.is_some() && condition.is_some() && (!needs_value || value.is_some());
+      if ui.add_enabled(can_filter, egui::Button::new("Filter")).clicked() {
+        self.search.as_mut().unwrap().filter(emulator.chip8(), condition.unwrap());
+      }
+    });
+
+    let search = match &self.search {
+      Some(search) => search,
+      None => {
+        ui.label("Start a new search, then filter it as the value you're after changes in game.");
+        return;
+      }
+    };
+
+    ui.label(format!("{} candidates", search.len()));
+    if search.len() > MAX_LISTED_RESULTS {
+      return;
+    }
+
+    let memory = emulator.chip8().get_memory();
+    let registers = emulator.chip8().get_registers();
+    let mut freeze = None;
+    egui::ScrollArea::vertical().id_source("cheat_results").max_height(160.0).show(ui, |ui| {
+      egui::Grid::new("cheat_results_grid").num_columns(4).striped(true).show(ui, |ui| {
+        for (target, previous) in search.results() {
+          let current = match target {
+            CheatTarget::Memory(addr) => memory[addr as usize],
+            CheatTarget::Register(x) => registers[x as usize],
+          };
+          ui.monospace(target.to_string());
+          ui.monospace(format!("was 0x{:02X}", previous));
+          ui.monospace(format!("now 0x{:02X}", current));
+          if ui.button("Freeze").clicked() {
+            freeze = Some((target, current));
+          }
+          ui.end_row();
+        }
+      });
+    });
+
+    if let Some((target, value)) = freeze {
+      let mut cheats = emulator.cheats().clone();
+      cheats.cheats.push(Cheat {
+        name: format!("{}", target),
+        target,
+        value,
+        enabled: true,
+      });
+      emulator.set_cheats(cheats);
+      save_cheats(emulator);
+    }
+  }
+
+  fn show_cheats(&mut self, ui: &mut egui::Ui, emulator: &mut Emulator) {
+    if emulator.rom_hash().is_none() {
+      ui.label("Load a ROM to use cheats.");
+      return;
+    }
+
+    if emulator.is_movie_active() && emulator.cheats().any_enabled() {
+      ui.colored_label(ui.visuals().warn_fg_color, "Cheats are off while a movie records or plays, so it replays the same.");
+    }
+
+    let mut cheats = emulator.cheats().clone();
+    let mut removed = None;
+    egui::Grid::new("cheat_list").num_columns(5).show(ui, |ui| {
+      for (index, cheat) in cheats.cheats.iter_mut().enumerate() {
+        ui.checkbox(&mut cheat.enabled, "");
+        ui.add(egui::TextEdit::singleline(&mut cheat.name).desired_width(140.0));
+        ui.monospace(cheat.target.to_string());
+        ui.add(egui::DragValue::new(&mut cheat.value).hexadecimal(2, false, true).prefix("0x"));
+        if ui.button("Remove").clicked() {
+          removed = Some(index);
+        }
+        ui.end_row();
+      }
+    });
+    if let Some(index) = removed {
+      cheats.cheats.remove(index);
+    }
+
+    if &cheats != emulator.cheats() {
+      emulator.set_cheats(cheats);
+      save_cheats(emulator);
+    }
+  }
+}
@@ -0,0 +1,87 @@
+use std::fs;
+
+use eframe::egui;
+use rfd::FileDialog;
+
+use crate::coverage::{annotated_listing, lcov, summarize};
+use crate::frontend::Emulator;
+use super::show_tool_window;
+
+fn save_report(filter: (&str, &str), name: &str, report: String) {
+  let file = FileDialog::new()
+    .add_filter(filter.0, &[filter.1])
+    .set_file_name(name)
+    .save_file();
+  if let Some(path) = file {
+    if let Err(err) = fs::write(&path, report) {
+      println!("Failed to export coverage: {}", err);
+    }
+  }
+}
+
+/// Shows how much of the ROM has been executed or read as data
+pub struct CoverageWindow {
+  pub open: bool,
+}
+
+impl CoverageWindow {
+  pub fn new() -> CoverageWindow {
+    Self {
+      open: false,
+    }
+  }
+
+  pub fn show(&mut self, ctx: &egui::Context, emulator: &mut Emulator) {
+    let mut open = self.open;
+    show_tool_window(ctx, "Coverage", [360.0, 200.0], &mut open, |ui| {
+      let (rom_len, rom_hash) = match (emulator.rom(), emulator.rom_hash()) {
+        (Some(rom), Some(rom_hash)) => (rom.len(), rom_hash),
+        _ => {
+          ui.label("Load a ROM to track its coverage.");
+          return;
+        }
+      };
+
+      let memory = emulator.chip8().get_memory();
+      let coverage = emulator.chip8().coverage();
+      let variant = emulator.variant();
+      let summary = summarize(&memory, rom_len, coverage, variant);
+      let percent = |bytes: usize| bytes as f64 * 100.0 / summary.rom_bytes.max(1) as f64;
+      egui::Grid::new("coverage_summary").num_columns(3).show(ui, |ui| {
+        ui.label("Executed");
+        ui.label(format!("{} bytes", summary.executed));
+        ui.label(format!("{:.1}%", percent(summary.executed)));
+        ui.end_row();
+        ui.label("Read as data");
+        ui.label(format!("{} bytes", summary.data));
+        ui.label(format!("{:.1}%", percent(summary.data)));
+        ui.end_row();
+        ui.label("Unused");
+        ui.label(format!("{} bytes", summary.unused));
+        ui.label(format!("{:.1}%", percent(summary.unused)));
+        ui.end_row();
+        ui.label("Skip outcomes");
+        ui.label(format!("{} of {}", summary.branch_outcomes_covered, summary.branch_outcomes));
+        ui.end_row();
+      });
+
+      ui.separator();
+      let mut reset = false;
+      ui.horizontal(|ui| {
+        if ui.button("Export Listing...").clicked() {
+          let listing = annotated_listing(&memory, rom_len, coverage, variant);
+          save_report(("Listing", "txt"), &format!("{:016x}_coverage.txt", rom_hash), listing);
+        }
+        if ui.button("Export LCOV...").clicked() {
+          let report = lcov(&memory, rom_len, coverage, variant, &format!("{:016x}.ch8", rom_hash));
+          save_report(("LCOV", "info"), &format!("{:016x}.info", rom_hash), report);
+        }
+        reset = ui.button("Reset").clicked();
+      });
+      if reset {
+        emulator.chip8_mut().reset_coverage();
+      }
+    });
+    self.open = open;
+  }
+}
@@ -0,0 +1,198 @@
+use std::fs;
+
+use eframe::egui;
+use egui::Color32;
+use rfd::FileDialog;
+
+use crate::disassembler::{decode, Symbols};
+use crate::frontend::Emulator;
+use super::show_tool_window;
+
+const MEMORY_SIZE: usize = 65536;
+const PC_COLOR: Color32 = Color32::from_rgb(70, 110, 200);
+const BREAKPOINT_COLOR: Color32 = Color32::from_rgb(200, 50, 50);
+const DATA_COLOR: Color32 = Color32::GRAY;
+
+/// A row of the listing, either one instruction or up to two bytes of data
+#[derive(Copy, Clone)]
+struct Line {
+  addr: u16,
+  len: u16,
+  code: bool,
+}
+
+/// Disassembly of memory that follows the program counter, with breakpoints
+pub struct DisassemblyWindow {
+  pub open: bool,
+  symbols: Symbols,
+  follow_pc: bool,
+  use_code_map: bool,
+  last_pc: Option<u16>,
+}
+
+impl DisassemblyWindow {
+  pub fn new() -> DisassemblyWindow {
+    Self {
+      open: false,
+      symbols: Symbols::default(),
+      follow_pc: true,
+      use_code_map: true,
+      last_pc: None,
+    }
+  }
+
+  pub fn show(&mut self, ctx: &egui::Context, emulator: &mut Emulator) {
+    let mut open = self.open;
+    show_tool_window(ctx, "Disassembly", [480.0, 560.0], &mut open, |ui| {
+      self.show_toolbar(ui, emulator);
+      ui.separator();
+      self.show_listing(ui, emulator);
+    });
+    self.open = open;
+  }
+
+  fn show_toolbar(&mut self, ui: &mut egui::Ui, emulator: &mut Emulator) {
+    ui.horizontal(|ui| {
+      if emulator.is_paused() {
+        if ui.button("Continue").clicked() {
+          emulator.set_paused(false);
+        }
+      } else if ui.button("Pause").clicked() {
+        emulator.set_paused(true);
+      }
+      if ui.button("Go to PC").clicked() {
+        self.last_pc = None;
+      }
+      ui.checkbox(&mut self.follow_pc, "Follow PC");
+      ui.checkbox(&mut self.use_code_map, "Show unexecuted bytes as data")
+        .on_hover_text("Only decode addresses the program has run, so data mixed into the ROM isn't shown as instructions");
+
+      if ui.button("Load Symbols...").clicked() {
+        self.load_symbols_from_dialog();
+      }
+    });
+
+    ui.horizontal(|ui| {
+      match emulator.stopped_at() {
+        Some(addr) => ui.colored_label(BREAKPOINT_COLOR, format!("Stopped at breakpoint 0x{:03X}", addr)),
+        None => ui.label(""),
+      };
+      ui.label("Click an address to toggle a breakpoint, right click it to run to it");
+    });
+  }
+
+  fn load_symbols_from_dialog(&mut self) {
+    let file = FileDialog::new()
+      .add_filter("Symbols", &["sym", "txt"])
+      .set_directory("./roms")
+      .pick_file();
+    if let Some(path) = file {
+      match fs::read_to_string(&path) {
+        Ok(text) => self.symbols = Symbols::from_text(&text),
+        Err(err) => println!("Failed to load symbols: {}", err),
+      }
+    }
+  }
+
+  fn show_listing(&mut self, ui: &mut egui::Ui, emulator: &mut Emulator) {
+    let memory = emulator.chip8().get_memory();
+    let pc = emulator.chip8().get_pc();
+    let lines = self.build_lines(&memory, &emulator.chip8().coverage().executed, pc);
+
+    let row_height = ui.text_style_height(&egui::TextStyle::Monospace) + ui.spacing().item_spacing.y;
+    let mut scroll_area = egui::ScrollArea::vertical().auto_shrink([false, false]);
+    let pc_moved = self.last_pc != Some(pc);
+    if pc_moved && (self.follow_pc || self.last_pc.is_none()) {
+      // Keep the program counter around the middle of the view
+      let row = lines.partition_point(|line| line.addr <= pc).saturating_sub(1);
+      let offset = row as f32 * row_height - ui.available_height() / 2.0;
+      scroll_area = scroll_area.vertical_scroll_offset(offset.max(0.0));
+    }
+    self.last_pc = Some(pc);
+
+    let variant = emulator.variant();
+    let mut toggled = None;
+    let mut run_to = None;
+    scroll_area.show_rows(ui, row_height, lines.len(), |ui, row_range| {
+      for line in &lines[row_range] {
+        ui.horizontal(|ui| {
+          let (gutter, response) = ui.allocate_exact_size(egui::vec2(row_height, row_height), egui::Sense::click());
+          if emulator.breakpoints().contains(&line.addr) {
+            ui.painter().circle_filled(gutter.center(), row_height / 3.0, BREAKPOINT_COLOR);
+          }
+          if response.clicked() {
+            toggled = Some(line.addr);
+          }
+
+          let mut addr = egui::RichText::new(format!("{:04X}", line.addr)).monospace();
+          if line.addr == pc {
+            addr = addr.background_color(PC_COLOR);
+          }
+          let response = ui.add(egui::Label::new(addr).sense(egui::Sense::click()));
+          if response.clicked() {
+            toggled = Some(line.addr);
+          }
+          response.context_menu(|ui| {
+            if ui.button("Toggle Breakpoint").clicked() {
+              toggled = Some(line.addr);
+              ui.close_menu();
+            }
+            if ui.button("Run to Cursor").clicked() {
+              run_to = Some(line.addr);
+              ui.close_menu();
+            }
+          });
+
+          let bytes = (0..line.len)
+            .map(|offset| format!("{:02X}", memory[(line.addr as usize + offset as usize) % MEMORY_SIZE]))
+            .collect::<String>();
+          ui.monospace(format!("{:<8}", bytes));
+
+          let label = self.symbols.get(line.addr).map(|label| format!("{}:", label)).unwrap_or_default();
+          ui.monospace(format!("{:<12}", label));
+
+          if line.code {
+            ui.monospace(decode(&memory, line.addr, variant).to_string_with(&self.symbols));
+          } else {
+            let data = (0..line.len)
+              .map(|offset| format!("0x{:02X}", memory[(line.addr as usize + offset as usize) % MEMORY_SIZE]))
+              .collect::<Vec<_>>();
+            ui.label(egui::RichText::new(format!("DB {}", data.join(", "))).monospace().color(DATA_COLOR));
+          }
+        });
+      }
+    });
+
+    if let Some(addr) = toggled {
+      emulator.toggle_breakpoint(addr);
+    }
+    if let Some(addr) = run_to {
+      emulator.run_to(addr);
+    }
+  }
+
+  /// Split memory into listing rows. Addresses that have run are always decoded as code, and
+  /// when using the code map everything else is data, otherwise it's decoded as code too
+  /// except where that would swallow the first byte of an executed instruction.
+  fn build_lines(&self, memory: &[u8], executed: &[bool], pc: u16) -> Vec<Line> {
+    let is_code = |addr: usize| executed[addr] || addr == pc as usize;
+    let mut lines = Vec::with_capacity(MEMORY_SIZE / 2);
+    let mut addr = 0;
+    while addr < MEMORY_SIZE {
+      let next_is_code = addr + 1 < MEMORY_SIZE && is_code(addr + 1);
+      let (len, code) = if is_code(addr) {
+        (2, true)
+      } else if next_is_code || addr + 1 == MEMORY_SIZE {
+        (1, false)
+      } else {
+        (2, !self.use_code_map)
+      };
+      // The XO-CHIP long load is followed by its address, which belongs on the same row
+      let len = if code && memory[addr] == 0xF0 && memory[(addr + 1) % MEMORY_SIZE] == 0x00 { 4 } else { len };
+
+      lines.push(Line { addr: addr as u16, len: len as u16, code });
+      addr += len;
+    }
+    lines
+  }
+}
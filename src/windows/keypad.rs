@@ -0,0 +1,61 @@
+use eframe::egui;
+
+use crate::chip8::STANDARD_LAYOUT;
+use crate::frontend::{Emulator, InputSource};
+
+/// Big enough to hit with a finger
+const BUTTON_SIZE: f32 = 56.0;
+
+/// The hex keypad as a side panel, which can be clicked or touched to press keys. It shows the
+/// keys held from every input source, and outlines the keys the program is reading.
+pub struct KeypadPanel {
+  pub open: bool,
+  /// The keys held down with the pointer, in COSMAC layout order
+  held: [bool; 16],
+}
+
+impl KeypadPanel {
+  pub fn new() -> KeypadPanel {
+    Self {
+      open: false,
+      held: [false; 16],
+    }
+  }
+
+  /// Show the panel. This has to come before the central panel so it can take its space.
+  pub fn show(&mut self, ctx: &egui::Context, emulator: &Emulator) {
+    if !self.open {
+      self.held = [false; 16];
+      return;
+    }
+    let pressed = emulator.chip8().get_keypad();
+    let polled = emulator.chip8().polled_keys();
+    egui::SidePanel::right("keypad").resizable(false).show(ctx, |ui| {
+      ui.add_space(8.0);
+      egui::Grid::new("keypad_buttons").spacing([6.0, 6.0]).show(ui, |ui| {
+        for (index, value) in STANDARD_LAYOUT.into_iter().enumerate() {
+          let text = egui::RichText::new(format!("{:X}", value)).size(28.0).monospace();
+          let mut button = egui::Button::new(text)
+            .min_size(egui::vec2(BUTTON_SIZE, BUTTON_SIZE))
+            .selected(pressed[index] != 0);
+          if polled & (1 << value) != 0 {
+            button = button.stroke(egui::Stroke::new(2.0, ui.visuals().warn_fg_color));
+          }
+          // Held for as long as the pointer stays down on it, rather than pressed once on click
+          self.held[index] = ui.add(button).is_pointer_button_down_on();
+          if index % 4 == 3 {
+            ui.end_row();
+          }
+        }
+      });
+      ui.add_space(4.0);
+      ui.label(egui::RichText::new("Outlined keys are being read by the program").small().color(ui.visuals().warn_fg_color));
+    });
+  }
+}
+
+impl InputSource for KeypadPanel {
+  fn keypad_state(&mut self) -> [bool; 16] {
+    self.held
+  }
+}
@@ -0,0 +1,188 @@
+use eframe::egui;
+use egui::Color32;
+
+use crate::frontend::Emulator;
+use super::show_tool_window;
+
+const MEMORY_SIZE: usize = 65536;
+/// Bytes per row choices, 1 and 2 line up with 8 and 16 pixel wide sprites in the bitmap column
+const ROW_WIDTHS: [usize; 4] = [1, 2, 8, 16];
+const PC_COLOR: Color32 = Color32::from_rgb(70, 110, 200);
+const I_COLOR: Color32 = Color32::from_rgb(60, 150, 80);
+const WRITTEN_COLOR: Color32 = Color32::from_rgb(180, 80, 60);
+
+#[derive(Copy, Clone, PartialEq)]
+enum SideColumn {
+  Ascii,
+  Sprite,
+}
+
+/// Hex view of memory that can also edit it
+pub struct MemoryWindow {
+  pub open: bool,
+  row_width: usize,
+  side_column: SideColumn,
+  jump_address: String,
+  scroll_to: Option<u16>,
+  follow_pc: bool,
+  editing: Option<(u16, String)>,
+}
+
+impl MemoryWindow {
+  pub fn new() -> MemoryWindow {
+    Self {
+      open: false,
+      row_width: 16,
+      side_column: SideColumn::Ascii,
+      jump_address: "200".to_string(),
+      scroll_to: None,
+      follow_pc: false,
+      editing: None,
+    }
+  }
+
+  pub fn show(&mut self, ctx: &egui::Context, emulator: &mut Emulator) {
+    let mut open = self.open;
+    show_tool_window(ctx, "Memory", [760.0, 520.0], &mut open, |ui| {
+      self.show_toolbar(ui, emulator);
+      ui.separator();
+      self.show_memory(ui, emulator);
+    });
+    self.open = open;
+  }
+
+  fn show_toolbar(&mut self, ui: &mut egui::Ui, emulator: &Emulator) {
+    ui.horizontal(|ui| {
+      ui.label("Address");
+      let response = ui.add(egui::TextEdit::singleline(&mut self.jump_address).desired_width(48.0));
+      let entered = response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+      if ui.button("Go").clicked() || entered {
+        let address = self.jump_address.trim();
+        if let Ok(address) = u16::from_str_radix(address.strip_prefix("0x").unwrap_or(address), 16) {
+          self.scroll_to = Some(address);
+        }
+      }
+      if ui.button("PC").clicked() {
+        self.scroll_to = Some(emulator.chip8().get_pc());
+      }
+      if ui.button("I").clicked() {
+        self.scroll_to = Some(emulator.chip8().get_index());
+      }
+      ui.checkbox(&mut self.follow_pc, "Follow PC");
+
+      ui.separator();
+      egui::ComboBox::from_id_source("memory_row_width")
+        .selected_text(format!("{} per row", self.row_width))
+        .show_ui(ui, |ui| {
+          for width in ROW_WIDTHS {
+            ui.selectable_value(&mut self.row_width, width, format!("{} per row", width));
+          }
+        });
+      ui.radio_value(&mut self.side_column, SideColumn::Ascii, "ASCII");
+      ui.radio_value(&mut self.side_column, SideColumn::Sprite, "Sprite");
+    });
+
+    ui.horizontal(|ui| {
+      ui.label(egui::RichText::new("PC").background_color(PC_COLOR));
+      ui.label(egui::RichText::new("I").background_color(I_COLOR));
+      ui.label(egui::RichText::new("Written last frame").background_color(WRITTEN_COLOR));
+      ui.label("Click a byte to edit it");
+    });
+  }
+
+  fn show_memory(&mut self, ui: &mut egui::Ui, emulator: &mut Emulator) {
+    let memory = emulator.chip8().get_memory();
+    let pc = emulator.chip8().get_pc() as usize;
+    let i = emulator.chip8().get_index() as usize;
+    let mut written = vec![false; MEMORY_SIZE];
+    for addr in emulator.chip8().memory_writes() {
+      written[*addr as usize] = true;
+    }
+
+    if self.follow_pc {
+      self.scroll_to = Some(pc as u16);
+    }
+
+    let row_height = ui.text_style_height(&egui::TextStyle::Monospace) + ui.spacing().item_spacing.y;
+    let rows = MEMORY_SIZE / self.row_width;
+    let mut scroll_area = egui::ScrollArea::vertical().auto_shrink([false, false]);
+    if let Some(address) = self.scroll_to.take() {
+      scroll_area = scroll_area.vertical_scroll_offset((address as usize / self.row_width) as f32 * row_height);
+    }
+
+    let mut edited = None;
+    scroll_area.show_rows(ui, row_height, rows, |ui, row_range| {
+      for row in row_range {
+        let start = row * self.row_width;
+        let bytes = &memory[start..start + self.row_width];
+        ui.horizontal(|ui| {
+          ui.monospace(format!("{:04X}", start));
+
+          for (offset, byte) in bytes.iter().enumerate() {
+            let addr = start + offset;
+            if let Some((editing_addr, text)) = &mut self.editing {
+              if *editing_addr as usize == addr {
+                let response = ui.add(egui::TextEdit::singleline(text).font(egui::TextStyle::Monospace).desired_width(16.0));
+                if response.lost_focus() {
+                  if ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+                    edited = u8::from_str_radix(text.trim(), 16).ok().map(|value| (addr as u16, value));
+                  }
+                  self.editing = None;
+                } else if !response.has_focus() {
+                  response.request_focus();
+                }
+                continue;
+              }
+            }
+
+            let mut text = egui::RichText::new(format!("{:02X}", byte)).monospace();
+            if addr == pc || addr == pc + 1 {
+              text = text.background_color(PC_COLOR);
+            } else if addr == i {
+              text = text.background_color(I_COLOR);
+            } else if written[addr] {
+              text = text.background_color(WRITTEN_COLOR);
+            }
+            if ui.add(egui::Label::new(text).sense(egui::Sense::click())).clicked() {
+              self.editing = Some((addr as u16, format!("{:02X}", byte)));
+            }
+          }
+
+          ui.separator();
+          match self.side_column {
+            SideColumn::Ascii => {
+              let ascii = bytes
+                .iter()
+                .map(|byte| if byte.is_ascii_graphic() || *byte == b' ' { *byte as char } else { '.' })
+                .collect::<String>();
+              ui.monospace(ascii);
+            },
+            SideColumn::Sprite => draw_sprite_row(ui, bytes, row_height),
+          }
+        });
+      }
+    });
+
+    if let Some((addr, value)) = edited {
+      emulator.chip8_mut().set_memory(addr, &[value]);
+    }
+  }
+}
+
+/// Draw bytes side by side as a row of sprite pixels, most significant bit first
+fn draw_sprite_row(ui: &mut egui::Ui, bytes: &[u8], row_height: f32) {
+  let pixel = (row_height / 2.0).floor().max(1.0);
+  let size = egui::vec2(bytes.len() as f32 * 8.0 * pixel, row_height);
+  let (rect, _) = ui.allocate_exact_size(size, egui::Sense::hover());
+  let painter = ui.painter_at(rect);
+  painter.rect_filled(rect, 0.0, Color32::BLACK);
+  for (index, byte) in bytes.iter().enumerate() {
+    for bit in 0..8 {
+      if byte & (0x80 >> bit) != 0 {
+        let x = rect.left() + (index * 8 + bit) as f32 * pixel;
+        let pixel_rect = egui::Rect::from_min_size(egui::pos2(x, rect.top()), egui::vec2(pixel, row_height));
+        painter.rect_filled(pixel_rect, 0.0, Color32::WHITE);
+      }
+    }
+  }
+}
@@ -0,0 +1,34 @@
+pub mod cheats;
+pub mod coverage;
+pub mod disassembly;
+pub mod keypad;
+pub mod memory;
+pub mod netplay;
+pub mod palette;
+pub mod profiler;
+pub mod settings;
+
+use eframe::egui;
+
+/// Show a tool window in its own viewport, clearing `open` once the user closes it
+pub fn show_tool_window(ctx: &egui::Context, title: &str, size: [f32; 2], open: &mut bool, add_contents: impl FnOnce(&mut egui::Ui)) {
+  ctx.show_viewport_immediate(
+    egui::ViewportId::from_hash_of(title),
+    egui::ViewportBuilder::default()
+      .with_title(title)
+      .with_inner_size(size),
+    |ctx, class| {
+      assert!(
+        class == egui::ViewportClass::Immediate,
+        "This egui backend doesn't support multiple viewports"
+      );
+
+      egui::CentralPanel::default().show(ctx, add_contents);
+
+      if ctx.input(|i| i.viewport().close_requested()) {
+        // Tell parent viewport that we should not show next frame:
+        *open = false;
+      }
+    },
+  );
+}
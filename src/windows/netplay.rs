@@ -0,0 +1,96 @@
+use std::net::{SocketAddr, ToSocketAddrs};
+
+use eframe::egui;
+
+use crate::netplay::{NetplaySession, NetplayStatus};
+use super::show_tool_window;
+
+/// Lets the user connect to another instance for two-player netplay
+pub struct NetplayWindow {
+  pub open: bool,
+  local_port: String,
+  peer_address: String,
+  is_player_1: bool,
+  delay: u32,
+  error: Option<String>,
+}
+
+impl NetplayWindow {
+  pub fn new() -> NetplayWindow {
+    Self {
+      open: false,
+      local_port: "7008".to_string(),
+      peer_address: "127.0.0.1:7009".to_string(),
+      is_player_1: true,
+      delay: 3,
+      error: None,
+    }
+  }
+
+  pub fn show(&mut self, ctx: &egui::Context, netplay: &mut Option<NetplaySession>) {
+    let mut open = self.open;
+    show_tool_window(ctx, "Netplay", [320.0, 200.0], &mut open, |ui| {
+      match netplay {
+        Some(session) => {
+          let status = match session.status() {
+            NetplayStatus::Connecting => "Waiting for the other player...".to_string(),
+            NetplayStatus::Running => format!("Playing, frame {}", session.frame()),
+            NetplayStatus::Desynced(frame) => format!("Desynced at frame {}", frame),
+            NetplayStatus::RomMismatch => "The other player has a different ROM loaded".to_string(),
+          };
+          ui.label(status);
+          if ui.button("Disconnect").clicked() {
+            *netplay = None;
+          }
+        },
+        None => {
+          egui::Grid::new("netplay_settings").num_columns(2).show(ui, |ui| {
+            ui.label("Local port");
+            ui.text_edit_singleline(&mut self.local_port);
+            ui.end_row();
+
+            ui.label("Other player");
+            ui.text_edit_singleline(&mut self.peer_address);
+            ui.end_row();
+
+            ui.label("Player");
+            ui.horizontal(|ui| {
+              ui.radio_value(&mut self.is_player_1, true, "1 (left keys)");
+              ui.radio_value(&mut self.is_player_1, false, "2 (right keys)");
+            });
+            ui.end_row();
+
+            ui.label("Input delay");
+            ui.add(egui::Slider::new(&mut self.delay, 0..=10).suffix(" frames"));
+            ui.end_row();
+          });
+
+          if ui.button("Connect").clicked() {
+            match self.connect() {
+              Ok(session) => {
+                *netplay = Some(session);
+                self.error = None;
+              },
+              Err(err) => self.error = Some(err),
+            }
+          }
+          if let Some(error) = &self.error {
+            ui.colored_label(egui::Color32::RED, error);
+          }
+        }
+      }
+    });
+    self.open = open;
+  }
+
+  fn connect(&self) -> Result<NetplaySession, String> {
+    let port = self.local_port.trim().parse::<u16>().map_err(|_| "Local port must be a number")?;
+    let peer = self.peer_address
+      .to_socket_addrs()
+      .ok()
+      .and_then(|mut addrs| addrs.next())
+      .ok_or("Other player must be an address like 192.168.1.2:7009")?;
+    let local = SocketAddr::new("0.0.0.0".parse().unwrap(), port);
+    NetplaySession::new(local, peer, self.is_player_1, self.delay).map_err(|e| e.to_string())
+  }
+}
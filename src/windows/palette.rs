@@ -0,0 +1,49 @@
+use eframe::egui;
+
+use crate::frontend::palette::Palette;
+use crate::frontend::Emulator;
+use super::show_tool_window;
+
+/// What each palette color is drawn for
+const COLOR_NAMES: [&str; 4] = ["Background", "Foreground", "Plane 2", "Both Planes"];
+
+/// Picks each of the display colors by hand
+pub struct PaletteWindow {
+  pub open: bool,
+}
+
+impl PaletteWindow {
+  pub fn new() -> PaletteWindow {
+    Self {
+      open: false,
+    }
+  }
+
+  pub fn show(&mut self, ctx: &egui::Context, emulator: &mut Emulator) {
+    let mut open = self.open;
+    show_tool_window(ctx, "Custom Palette", [280.0, 180.0], &mut open, |ui| {
+      let mut palette = emulator.palette();
+      egui::Grid::new("palette_colors").num_columns(2).show(ui, |ui| {
+        for (name, color) in COLOR_NAMES.iter().zip(palette.colors.iter_mut()) {
+          ui.label(*name);
+          ui.color_edit_button_srgb(color);
+          ui.end_row();
+        }
+      });
+
+      ui.separator();
+      egui::ComboBox::from_label("Start from")
+        .selected_text(palette.preset_name().unwrap_or("Custom"))
+        .show_ui(ui, |ui| {
+          for (name, preset) in Palette::PRESETS {
+            ui.selectable_value(&mut palette, preset, name);
+          }
+        });
+
+      if palette != emulator.palette() {
+        emulator.set_palette(palette);
+      }
+    });
+    self.open = open;
+  }
+}
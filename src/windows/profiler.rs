@@ -0,0 +1,233 @@
+use std::fs;
+
+use eframe::egui;
+use egui::Color32;
+use rfd::FileDialog;
+
+use crate::chip8::{Profile, Variant};
+use crate::disassembler::decode;
+use crate::frontend::Emulator;
+use super::show_tool_window;
+
+/// The heatmap shows one 4 KiB page of memory at a time, as a 64x64 grid of bytes
+const PAGE_SIZE: usize = 4096;
+const HEATMAP_COLUMNS: usize = 64;
+const CELL_SIZE: f32 = 6.0;
+/// Most addresses to list in the hot spot table
+const MAX_LISTED_ADDRESSES: usize = 100;
+
+/// Name an opcode class the way instructions are usually written, e.g. 0x8004 as 8XY4
+pub fn opcode_class_name(class: u16) -> String {
+  let kind = class >> 12;
+  match kind {
+    0x0 if matches!(class, 0x00C0 | 0x00D0) => format!("{:03X}N", class >> 4),
+    0x0 => format!("{:04X}", class),
+    0x1 | 0x2 | 0xA | 0xB => format!("{:X}NNN", kind),
+    0x3 | 0x4 | 0x6 | 0x7 | 0xC => format!("{:X}XNN", kind),
+    0x5 | 0x8 | 0x9 => format!("{:X}XY{:X}", kind, class & 0xF),
+    0xD => "DXYN".to_string(),
+    // F000 and F002 only exist with X as 0
+    _ if matches!(class, 0xF000 | 0xF002) => format!("{:04X}", class),
+    _ => format!("{:X}X{:02X}", kind, class & 0xFF),
+  }
+}
+
+/// Quote a CSV field, since instructions separate their operands with commas
+fn csv_field(text: &str) -> String {
+  format!("\"{}\"", text.replace('"', "\"\""))
+}
+
+/// Per-address counts as CSV, hottest first
+fn address_csv(profile: &Profile, memory: &[u8], variant: Variant) -> String {
+  let mut csv = "address,count,percent,instruction\n".to_string();
+  for (addr, count) in hot_addresses(profile) {
+    let percent = count as f64 * 100.0 / profile.total as f64;
+    csv += &format!("0x{:04X},{},{:.4},{}\n", addr, count, percent, csv_field(&decode(memory, addr, variant).to_string()));
+  }
+  csv
+}
+
+/// Per-opcode-class counts as CSV, most run first
+fn class_csv(profile: &Profile) -> String {
+  let mut csv = "opcode,count,percent\n".to_string();
+  for (class, count) in hot_classes(profile) {
+    let percent = count as f64 * 100.0 / profile.total as f64;
+    csv += &format!("{},{},{:.4}\n", opcode_class_name(class), count, percent);
+  }
+  csv
+}
+
+fn hot_addresses(profile: &Profile) -> Vec<(u16, u64)> {
+  let mut addresses = profile.address_counts
+    .iter()
+    .enumerate()
+    .filter(|(_, count)| **count > 0)
+    .map(|(addr, count)| (addr as u16, *count))
+    .collect::<Vec<_>>();
+  addresses.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+  addresses
+}
+
+fn hot_classes(profile: &Profile) -> Vec<(u16, u64)> {
+  let mut classes = profile.class_counts.iter().map(|(class, count)| (*class, *count)).collect::<Vec<_>>();
+  classes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+  classes
+}
+
+fn save_csv(name: &str, csv: String) {
+  let file = FileDialog::new()
+    .add_filter("CSV", &["csv"])
+    .set_file_name(name)
+    .save_file();
+  if let Some(path) = file {
+    if let Err(err) = fs::write(&path, csv) {
+      println!("Failed to export profile: {}", err);
+    }
+  }
+}
+
+/// Shades from dark blue for rarely run addresses to yellow for the hottest, on a log scale
+/// so a handful of tight loops don't wash out everything else
+fn heat_color(count: u64, max: u64) -> Color32 {
+  if count == 0 {
+    return Color32::from_gray(24);
+  }
+  let heat = ((count as f32).ln_1p() / (max as f32).ln_1p()).clamp(0.0, 1.0);
+  let lerp = |from: f32, to: f32| (from + (to - from) * heat) as u8;
+  Color32::from_rgb(lerp(30.0, 255.0), lerp(40.0, 220.0), lerp(160.0, 40.0))
+}
+
+/// Shows where the program spends its instructions
+pub struct ProfilerWindow {
+  pub open: bool,
+  page: usize,
+}
+
+impl ProfilerWindow {
+  pub fn new() -> ProfilerWindow {
+    Self {
+      open: false,
+      page: 0,
+    }
+  }
+
+  pub fn show(&mut self, ctx: &egui::Context, emulator: &mut Emulator) {
+    let mut open = self.open;
+    show_tool_window(ctx, "Profiler", [900.0, 560.0], &mut open, |ui| {
+      self.show_toolbar(ui, emulator);
+      ui.separator();
+
+      let profile = match emulator.chip8().profile() {
+        Some(profile) if profile.total > 0 => profile,
+        _ => {
+          ui.label("Start profiling, then run the part of the program you want to measure.");
+          return;
+        }
+      };
+      ui.horizontal_top(|ui| {
+        ui.vertical(|ui| self.show_heatmap(ui, profile));
+        ui.separator();
+        show_hot_addresses(ui, profile, emulator);
+        ui.separator();
+        show_hot_classes(ui, profile);
+      });
+    });
+    self.open = open;
+  }
+
+  fn show_toolbar(&mut self, ui: &mut egui::Ui, emulator: &mut Emulator) {
+    ui.horizontal(|ui| {
+      let mut profiling = emulator.chip8().is_profiling();
+      if ui.checkbox(&mut profiling, "Profiling").changed() {
+        emulator.chip8_mut().set_profiling(profiling);
+      }
+      if ui.button("Reset").clicked() {
+        emulator.chip8_mut().reset_profile();
+      }
+
+      let profile = emulator.chip8().profile().filter(|profile| profile.total > 0);
+      if let Some(profile) = profile {
+        ui.label(format!("{} instructions", profile.total));
+      }
+      if ui.add_enabled(profile.is_some(), egui::Button::new("Export Addresses CSV...")).clicked() {
+        save_csv("profile_addresses.csv", address_csv(profile.unwrap(), &emulator.chip8().get_memory(), emulator.variant()));
+      }
+      if ui.add_enabled(profile.is_some(), egui::Button::new("Export Opcodes CSV...")).clicked() {
+        save_csv("profile_opcodes.csv", class_csv(profile.unwrap()));
+      }
+    });
+  }
+
+  fn show_heatmap(&mut self, ui: &mut egui::Ui, profile: &Profile) {
+    let page_count = profile.address_counts.len() / PAGE_SIZE;
+    egui::ComboBox::from_id_source("profiler_page")
+      .selected_text(page_name(self.page))
+      .show_ui(ui, |ui| {
+        for page in 0..page_count {
+          // Pages that never ran anything aren't worth offering
+          if page == self.page || profile.address_counts[page * PAGE_SIZE..(page + 1) * PAGE_SIZE].iter().any(|count| *count > 0) {
+            ui.selectable_value(&mut self.page, page, page_name(page));
+          }
+        }
+      });
+
+    let counts = &profile.address_counts[self.page * PAGE_SIZE..(self.page + 1) * PAGE_SIZE];
+    let max = *profile.address_counts.iter().max().unwrap();
+    let rows = PAGE_SIZE / HEATMAP_COLUMNS;
+    let size = egui::vec2(HEATMAP_COLUMNS as f32 * CELL_SIZE, rows as f32 * CELL_SIZE);
+    let (rect, response) = ui.allocate_exact_size(size, egui::Sense::hover());
+    let painter = ui.painter_at(rect);
+    for (offset, count) in counts.iter().enumerate() {
+      let x = rect.left() + (offset % HEATMAP_COLUMNS) as f32 * CELL_SIZE;
+      let y = rect.top() + (offset / HEATMAP_COLUMNS) as f32 * CELL_SIZE;
+      let cell = egui::Rect::from_min_size(egui::pos2(x, y), egui::vec2(CELL_SIZE, CELL_SIZE));
+      painter.rect_filled(cell, 0.0, heat_color(*count, max));
+    }
+
+    if let Some(pos) = response.hover_pos() {
+      let column = ((pos.x - rect.left()) / CELL_SIZE) as usize;
+      let row = ((pos.y - rect.top()) / CELL_SIZE) as usize;
+      let offset = (row * HEATMAP_COLUMNS + column).min(PAGE_SIZE - 1);
+      let addr = self.page * PAGE_SIZE + offset;
+      response.on_hover_text_at_pointer(format!("0x{:04X}: {}", addr, counts[offset]));
+    }
+  }
+}
+
+fn page_name(page: usize) -> String {
+  format!("0x{:04X}-0x{:04X}", page * PAGE_SIZE, (page + 1) * PAGE_SIZE - 1)
+}
+
+fn show_hot_addresses(ui: &mut egui::Ui, profile: &Profile, emulator: &Emulator) {
+  let memory = emulator.chip8().get_memory();
+  ui.vertical(|ui| {
+    ui.heading("Hot spots");
+    egui::ScrollArea::vertical().id_source("profiler_addresses").show(ui, |ui| {
+      egui::Grid::new("profiler_addresses_grid").num_columns(4).striped(true).show(ui, |ui| {
+        for (addr, count) in hot_addresses(profile).into_iter().take(MAX_LISTED_ADDRESSES) {
+          ui.monospace(format!("0x{:04X}", addr));
+          ui.monospace(count.to_string());
+          ui.monospace(format!("{:.1}%", count as f64 * 100.0 / profile.total as f64));
+          ui.monospace(decode(&memory, addr, emulator.variant()).to_string());
+          ui.end_row();
+        }
+      });
+    });
+  });
+}
+
+fn show_hot_classes(ui: &mut egui::Ui, profile: &Profile) {
+  ui.vertical(|ui| {
+    ui.heading("Opcodes");
+    egui::ScrollArea::vertical().id_source("profiler_classes").show(ui, |ui| {
+      egui::Grid::new("profiler_classes_grid").num_columns(3).striped(true).show(ui, |ui| {
+        for (class, count) in hot_classes(profile) {
+          ui.monospace(opcode_class_name(class));
+          ui.monospace(count.to_string());
+          ui.monospace(format!("{:.1}%", count as f64 * 100.0 / profile.total as f64));
+          ui.end_row();
+        }
+      });
+    });
+  });
+}
@@ -0,0 +1,303 @@
+use eframe::egui;
+use egui::Key;
+use rfd::FileDialog;
+
+use crate::chip8::{Variant, STANDARD_LAYOUT};
+use crate::frontend::egui_backend::default_key_layout;
+use crate::frontend::flicker::{FlickerMode, BLEND_FRAME_PRESETS};
+use crate::gilrs_backend::{GamepadControl, GamepadMapping};
+use crate::frontend::palette::Palette;
+use crate::frontend::{default_ipf, SPEED_PRESETS};
+use crate::settings::Settings;
+use super::show_tool_window;
+
+/// Pick a palette by preset name, keeping any custom palette as an option
+fn palette_combo(ui: &mut egui::Ui, id: &str, palette: &mut String) {
+  let selected = Palette::parse(palette).and_then(|palette| palette.preset_name()).unwrap_or("Custom");
+  egui::ComboBox::from_id_source(id).selected_text(selected).show_ui(ui, |ui| {
+    for (name, _) in Palette::PRESETS {
+      if ui.selectable_label(selected == name, name).clicked() {
+        *palette = name.to_string();
+      }
+    }
+  });
+}
+
+/// Edits the settings that are kept between runs
+pub struct SettingsWindow {
+  pub open: bool,
+  /// The keypad key waiting for a keyboard key to be pressed, by its index in COSMAC layout order
+  binding: Option<usize>,
+}
+
+impl SettingsWindow {
+  pub fn new() -> SettingsWindow {
+    Self {
+      open: false,
+      binding: None,
+    }
+  }
+
+  /// Show the window, returning whether any settings were changed
+  pub fn show(&mut self, ctx: &egui::Context, settings: &mut Settings, rom_hash: Option<u64>) -> bool {
+    let before = settings.clone();
+    let mut open = self.open;
+    show_tool_window(ctx, "Settings", [420.0, 520.0], &mut open, |ui| {
+      egui::ScrollArea::vertical().show(ui, |ui| {
+        ui.heading("General");
+        show_general(ui, settings);
+        ui.separator();
+        ui.heading("Buzzer");
+        show_buzzer(ui, settings);
+        ui.separator();
+        ui.heading("Keys");
+        self.show_keys(ui, settings);
+        ui.separator();
+        ui.heading("Gamepad");
+        show_gamepad(ui, settings, rom_hash);
+        if let Some(rom_hash) = rom_hash {
+          ui.separator();
+          ui.heading("This ROM");
+          show_rom(ui, settings, rom_hash);
+        }
+      });
+    });
+    self.open = open;
+    if !open {
+      self.binding = None;
+    }
+    *settings != before
+  }
+
+  fn show_keys(&mut self, ui: &mut egui::Ui, settings: &mut Settings) {
+    let mut layout = settings.key_layout();
+    if let Some(index) = self.binding {
+      let pressed = ui.input(|i| i.events.iter().find_map(|event| match event {
+        egui::Event::Key { key, pressed: true, .. } => Some(*key),
+        _ => None,
+      }));
+      match pressed {
+        Some(Key::Escape) => self.binding = None,
+        Some(key) => {
+          layout[index] = key;
+          self.binding = None;
+        },
+        None => {},
+      }
+    }
+
+    egui::Grid::new("settings_keys").num_columns(4).show(ui, |ui| {
+      for (index, value) in STANDARD_LAYOUT.into_iter().enumerate() {
+        let text = match self.binding {
+          Some(binding) if binding == index => format!("{:X}: ...", value),
+          _ => format!("{:X}: {}", value, layout[index].name()),
+        };
+        if ui.add(egui::Button::new(text).min_size(egui::vec2(90.0, 0.0))).clicked() {
+          self.binding = Some(index);
+        }
+        if index % 4 == 3 {
+          ui.end_row();
+        }
+      }
+    });
+    ui.horizontal(|ui| {
+      if ui.button("Reset Keys").clicked() {
+        layout = default_key_layout();
+        self.binding = None;
+      }
+      if self.binding.is_some() {
+        ui.label("Press a key, or Escape to cancel");
+      }
+    });
+
+    if layout != settings.key_layout() {
+      settings.set_key_layout(layout);
+    }
+  }
+}
+
+fn show_general(ui: &mut egui::Ui, settings: &mut Settings) {
+  egui::Grid::new("settings_general").num_columns(2).show(ui, |ui| {
+    ui.label("ROM folder");
+    ui.horizontal(|ui| {
+      match &settings.rom_directory {
+        Some(directory) => ui.label(directory.display().to_string()),
+        None => ui.label("./roms"),
+      };
+      if ui.button("Browse...").clicked() {
+        if let Some(directory) = FileDialog::new().pick_folder() {
+          settings.rom_directory = Some(directory);
+        }
+      }
+    });
+    ui.end_row();
+
+    ui.label("Variant");
+    let selected = Variant::from_name(&settings.variant).unwrap_or(Variant::XOCHIP);
+    egui::ComboBox::from_id_source("settings_variant").selected_text(selected.name()).show_ui(ui, |ui| {
+      for variant in Variant::ALL {
+        if ui.selectable_label(variant == selected, variant.name()).clicked() {
+          settings.variant = variant.name().to_string();
+        }
+      }
+    });
+    ui.end_row();
+
+    ui.label("Speed");
+    egui::ComboBox::from_id_source("settings_speed").selected_text(format!("{}x", settings.speed)).show_ui(ui, |ui| {
+      for speed in SPEED_PRESETS {
+        ui.selectable_value(&mut settings.speed, speed, format!("{}x", speed));
+      }
+    });
+    ui.end_row();
+
+    ui.label("Volume");
+    ui.add(egui::Slider::new(&mut settings.volume, 0.0..=1.0).custom_formatter(|volume, _| format!("{:.0}%", volume * 100.0)));
+    ui.end_row();
+
+    ui.label("Palette");
+    palette_combo(ui, "settings_palette", &mut settings.palette);
+    ui.end_row();
+
+    ui.label("Flicker reduction");
+    let selected = settings.flicker_mode();
+    egui::ComboBox::from_id_source("settings_flicker").selected_text(selected.label()).show_ui(ui, |ui| {
+      for mode in FlickerMode::ALL {
+        if ui.selectable_label(mode == selected, mode.label()).clicked() {
+          settings.flicker = mode.name().to_string();
+        }
+      }
+    });
+    ui.end_row();
+
+    ui.label("Frames to blend");
+    egui::ComboBox::from_id_source("settings_blend_frames").selected_text(settings.blend_frames.to_string()).show_ui(ui, |ui| {
+      for frames in BLEND_FRAME_PRESETS {
+        ui.selectable_value(&mut settings.blend_frames, frames, frames.to_string());
+      }
+    });
+    ui.end_row();
+  });
+}
+
+fn show_buzzer(ui: &mut egui::Ui, settings: &mut Settings) {
+  egui::Grid::new("settings_buzzer").num_columns(2).show(ui, |ui| {
+    ui.label("Frequency");
+    ui.add(egui::Slider::new(&mut settings.buzzer_frequency, 50.0..=2000.0).logarithmic(true).suffix(" Hz"));
+    ui.end_row();
+
+    ui.label("Duty cycle");
+    ui.add(egui::Slider::new(&mut settings.buzzer_duty, 0.05..=0.95).custom_formatter(|duty, _| format!("{:.0}%", duty * 100.0)));
+    ui.end_row();
+
+    ui.label("Attack");
+    ui.add(egui::Slider::new(&mut settings.buzzer_attack_ms, 0.0..=50.0).suffix(" ms"));
+    ui.end_row();
+
+    ui.label("Release");
+    ui.add(egui::Slider::new(&mut settings.buzzer_release_ms, 0.0..=50.0).suffix(" ms"));
+    ui.end_row();
+  });
+  if ui.button("Reset Buzzer").clicked() {
+    settings.reset_buzzer();
+  }
+}
+
+/// Map gamepad controls to keypad keys, either for every ROM or just the loaded one
+fn show_gamepad(ui: &mut egui::Ui, settings: &mut Settings, rom_hash: Option<u64>) {
+  let mut rom = rom_hash.map(|rom_hash| settings.rom(rom_hash));
+  if let Some(rom) = &mut rom {
+    let mut overridden = rom.gamepad.is_some();
+    if ui.checkbox(&mut overridden, "Separate mapping for this ROM").changed() {
+      rom.gamepad = overridden.then(|| settings.gamepad.clone());
+    }
+  }
+  let text = match rom.as_mut().and_then(|rom| rom.gamepad.as_mut()) {
+    Some(text) => text,
+    None => &mut settings.gamepad,
+  };
+
+  let before = GamepadMapping::parse(text).unwrap_or_default();
+  let mut mapping = before;
+  egui::Grid::new("settings_gamepad").num_columns(4).show(ui, |ui| {
+    for (index, control) in GamepadControl::ALL.into_iter().enumerate() {
+      let mut key = mapping.key(control);
+      ui.label(control.label());
+      egui::ComboBox::from_id_source(("settings_gamepad", index))
+        .width(48.0)
+        .selected_text(key.map_or("-".to_string(), |key| format!("{:X}", key)))
+        .show_ui(ui, |ui| {
+          ui.selectable_value(&mut key, None, "-");
+          for value in 0..16 {
+            ui.selectable_value(&mut key, Some(value), format!("{:X}", value));
+          }
+        });
+      mapping.set_key(control, key);
+      if index % 2 == 1 {
+        ui.end_row();
+      }
+    }
+  });
+  if ui.button("Reset Gamepad").clicked() {
+    mapping = GamepadMapping::default();
+  }
+  if mapping != before {
+    *text = mapping.to_string();
+  }
+
+  if let (Some(rom_hash), Some(rom)) = (rom_hash, rom) {
+    if rom != settings.rom(rom_hash) {
+      settings.set_rom(rom_hash, rom);
+    }
+  }
+}
+
+fn show_rom(ui: &mut egui::Ui, settings: &mut Settings, rom_hash: u64) {
+  let mut rom = settings.rom(rom_hash);
+  ui.label(format!("Overrides for ROM {:016x}", rom_hash));
+  egui::Grid::new("settings_rom").num_columns(2).show(ui, |ui| {
+    ui.label("Variant");
+    let selected = rom.variant.as_deref().and_then(Variant::from_name);
+    egui::ComboBox::from_id_source("settings_rom_variant")
+      .selected_text(selected.map_or("Default", |variant| variant.name()))
+      .show_ui(ui, |ui| {
+        if ui.selectable_label(selected.is_none(), "Default").clicked() {
+          rom.variant = None;
+        }
+        for variant in Variant::ALL {
+          if ui.selectable_label(selected == Some(variant), variant.name()).clicked() {
+            rom.variant = Some(variant.name().to_string());
+          }
+        }
+      });
+    ui.end_row();
+
+    ui.label("Instructions per frame");
+    ui.horizontal(|ui| {
+      let mut overridden = rom.ipf.is_some();
+      if ui.checkbox(&mut overridden, "Override").changed() {
+        rom.ipf = overridden.then(|| default_ipf(settings.variant(Some(rom_hash))));
+      }
+      if let Some(ipf) = &mut rom.ipf {
+        ui.add(egui::DragValue::new(ipf).clamp_range(1..=10000));
+      }
+    });
+    ui.end_row();
+
+    ui.label("Palette");
+    ui.horizontal(|ui| {
+      let mut overridden = rom.palette.is_some();
+      if ui.checkbox(&mut overridden, "Override").changed() {
+        rom.palette = overridden.then(|| settings.palette.clone());
+      }
+      if let Some(palette) = &mut rom.palette {
+        palette_combo(ui, "settings_rom_palette", palette);
+      }
+    });
+    ui.end_row();
+  });
+
+  if rom != settings.rom(rom_hash) {
+    settings.set_rom(rom_hash, rom);
+  }
+}